@@ -39,9 +39,9 @@ fn run_oog_test(bytecode: &[u8]){
     vm.gas_remaining = OOG_GAS_LIMIT;
     vm.copy_into_memory(CODE_MEM, bytecode).unwrap();
     let mut hv = TestHypervisor::default();
-    let r = vm.execute(&mut hv).unwrap_err();
+    let r = vm.execute(&mut hv);
     match r{
-        VMError::OutOfGas => return,
+        Ok(Termination::OutOfGas) => return,
         _ => panic!("Unexpected error instead of Out Of Gas")
     };
 }
@@ -69,6 +69,51 @@ fn mov_modrm_benchmark(c: &mut Criterion) {
     c.bench_function_over_inputs("mov modrm x2000", | i, bytecode | i.iter(|| run_exec_test(bytecode)), vec![bytes]);
 }
 
+fn hot_loop_benchmark(c: &mut Criterion) {
+    //the loop body is decoded only once since it is in read only memory
+    let bytes = asm("
+    mov ecx, 10000
+    mov esi, 0x80000000
+    _a:
+        mov eax, [esi]
+        add eax, ecx
+        xor ebx, eax
+        mov [esi + 4], ebx
+        lea edx, [eax + ecx * 2 + 1]
+        shr edx, 3
+        dec ecx
+        jnz _a
+    hlt
+    ");
+    c.bench_function_over_inputs("hot loop x10000", | i, bytecode | i.iter(|| run_exec_test(bytecode)), vec![bytes]);
+}
+
+fn branchy_loop_benchmark(c: &mut Criterion) {
+    //the conditional branches within the loop are never taken, while the calls are followed by the pipeline
+    let bytes = asm("
+    mov ecx, 10000
+    mov esi, 0x80000000
+    mov esp, 0x80001000
+    _a:
+        mov eax, [esi]
+        cmp eax, 5
+        je _done
+        call _add
+        test ebx, 0x80000000
+        jnz _done
+        cmp ecx, 20000
+        ja _done
+        dec ecx
+        jnz _a
+    _done:
+    hlt
+    _add:
+        add ebx, ecx
+        ret
+    ");
+    c.bench_function_over_inputs("branchy loop x10000", | i, bytecode | i.iter(|| run_exec_test(bytecode)), vec![bytes]);
+}
+
 fn infinite_loop_oog_benchmark(c: &mut Criterion) {
     let bytes = asm("
     _a:
@@ -98,7 +143,7 @@ fn test_add_calculation_uint8(c: &mut Criterion) {
     c.bench_function_over_inputs("calculate_overflow_add", |i, bytecode| i.iter(|| run_exec_test(bytecode)), vec![bytes]);
 }
 
-criterion_group!(benches, nop_hlt_benchmark, mov_modrm_benchmark, infinite_loop_oog_benchmark, indirect_infinite_loop_oog_benchmark, test_add_calculation_uint8);
+criterion_group!(benches, nop_hlt_benchmark, mov_modrm_benchmark, hot_loop_benchmark, branchy_loop_benchmark, infinite_loop_oog_benchmark, indirect_infinite_loop_oog_benchmark, test_add_calculation_uint8);
 criterion_main!(benches);
 
 
@@ -124,7 +169,7 @@ pub fn create_vm_with_asm(input: &str) -> VM{
 pub fn execute_vm_asm(input: &str) -> VM{
     let mut vm = create_vm_with_asm(input);
     let mut hv = TestHypervisor::default();
-    assert!(vm.execute(&mut hv).unwrap());
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
     vm
 }
 
@@ -0,0 +1,133 @@
+use crate::vm::*;
+use crate::pipeline::*;
+use crate::structs::*;
+use std::cell::Cell;
+
+/// The kind of memory access which triggers a watchpoint
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum WatchAccess{
+    /// Triggered only when the memory is read
+    Read,
+    /// Triggered only when the memory is written
+    Write,
+    /// Triggered when the memory is either read or written
+    ReadWrite
+}
+
+/// A watched range of memory
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Watchpoint{
+    /// The first address of the watched range
+    pub address: u32,
+    /// The number of bytes in the watched range
+    pub size: u32,
+    pub access: WatchAccess
+}
+
+/// The breakpoints and watchpoints of a VM.
+/// A breakpoint stops execution with Termination::Breakpoint before the opcode at that address is executed, leaving EIP pointing to it.
+/// A watchpoint stops execution with Termination::Watchpoint after the opcode which accessed the memory has completely executed,
+/// leaving EIP pointing to the next opcode. In both cases execution can be resumed by calling `execute` again
+#[derive(Default, Debug)]
+pub struct Debugger{
+    pub breakpoints: Vec<u32>,
+    pub watchpoints: Vec<Watchpoint>,
+    /// Set when execution stopped at a breakpoint, so that resuming executes the opcode rather than stopping at the same breakpoint again
+    resume_eip: Option<u32>,
+    /// The first watched access made by the currently executing opcode
+    hit: Cell<Option<MemoryAccess>>
+}
+
+impl Debugger{
+    /// Adds a breakpoint at the specified address
+    pub fn add_breakpoint(&mut self, address: u32){
+        if !self.breakpoints.contains(&address){
+            self.breakpoints.push(address);
+        }
+    }
+    /// Removes the breakpoint at the specified address, if it exists
+    pub fn remove_breakpoint(&mut self, address: u32){
+        self.breakpoints.retain(|b| *b != address);
+    }
+    /// Adds a watchpoint covering `size` bytes starting at the specified address
+    pub fn add_watchpoint(&mut self, address: u32, size: u32, access: WatchAccess){
+        self.watchpoints.push(Watchpoint{
+            address,
+            size,
+            access
+        });
+    }
+    /// Removes all watchpoints starting at the specified address
+    pub fn remove_watchpoint(&mut self, address: u32){
+        self.watchpoints.retain(|w| w.address != address);
+    }
+    /// Determines if the pipeline should stop at the specified address.
+    /// This is not the case for the first opcode of the pipeline when resuming from a breakpoint at that address
+    pub(crate) fn stops_at(&self, eip: u32, first: bool) -> bool{
+        if first && self.resume_eip == Some(eip){
+            return false;
+        }
+        self.breakpoints.contains(&eip)
+    }
+    /// Called after the pipeline is filled, since resuming only skips a breakpoint once
+    pub(crate) fn resumed(&mut self){
+        self.resume_eip = None;
+    }
+    /// Records a memory access if it overlaps a watchpoint of the matching kind
+    pub(crate) fn watch(&self, address: u32, size: u32, write: bool){
+        if self.hit.get().is_some(){
+            return;
+        }
+        for w in &self.watchpoints{
+            let matches = match w.access{
+                WatchAccess::Read => !write,
+                WatchAccess::Write => write,
+                WatchAccess::ReadWrite => true
+            };
+            //compare using u64 so that ranges ending at the top of memory do not overflow
+            let (start, end) = (address as u64, address as u64 + size as u64);
+            let (w_start, w_end) = (w.address as u64, w.address as u64 + w.size as u64);
+            if matches && start < w_end && w_start < end{
+                self.hit.set(Some(MemoryAccess{
+                    address,
+                    kind: if write { AccessKind::Write } else { AccessKind::Read }
+                }));
+                return;
+            }
+        }
+    }
+    /// Retrieves and clears the recorded watchpoint access
+    pub(crate) fn take_hit(&self) -> Option<MemoryAccess>{
+        self.hit.take()
+    }
+}
+
+/// Retrieves the number of bytes of memory used by a value of the specified size
+pub(crate) fn size_in_bytes(size: ValueSize) -> u32{
+    match size{
+        ValueSize::None => 0,
+        ValueSize::Byte => 1,
+        ValueSize::Word => 2,
+        ValueSize::Dword => 4,
+        ValueSize::Qword => 8,
+        ValueSize::Dqword => 16
+    }
+}
+
+/// Retrieves the number of bytes of memory used by the specified value
+pub(crate) fn value_size_in_bytes(value: &SizedValue) -> u32{
+    match value{
+        SizedValue::None => 0,
+        SizedValue::Byte(_) => 1,
+        SizedValue::Word(_) => 2,
+        SizedValue::Dword(_) => 4,
+        SizedValue::Qword(_) => 8,
+        SizedValue::Dqword(_) => 16
+    }
+}
+
+/// The logic function placed in the pipeline in place of an opcode with a breakpoint on it
+pub fn op_breakpoint(vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    vm.debugger.resume_eip = Some(vm.eip);
+    Err(VMError::Breakpoint(vm.eip))
+}
@@ -127,6 +127,49 @@ impl ModRM{
             is_memory: true
         };
     }
+    /// This is the 16 bit addressing equivalent of `decode`, used when an address size override prefix is present.
+    /// There is no SIB byte in this form, and the displacement is truncated to 16 bits.
+    /// Reference: http://ref.x86asm.net/coder32.html#modrm_byte_16
+    fn decode16(&self, disp: u32, size: ValueSize) -> OpArgument{
+        use Reg16::*;
+        if self.mode == 3 {
+            return OpArgument{
+                location: ArgLocation::RegisterValue(self.rm, size),
+                is_memory: false
+            };
+        }
+        //special case for [disp16]
+        if self.mode == 0 && self.rm == 6 {
+            return OpArgument{
+                location: ArgLocation::ModRMAddress16{
+                    offset: Some(disp as u16),
+                    reg1: Option::None,
+                    reg2: Option::None,
+                    size
+                },
+                is_memory: true
+            };
+        }
+        let (reg1, reg2) = match self.rm{
+            0 => (BX, Some(SI as u8)),
+            1 => (BX, Some(DI as u8)),
+            2 => (BP, Some(SI as u8)),
+            3 => (BP, Some(DI as u8)),
+            4 => (SI, Option::None),
+            5 => (DI, Option::None),
+            6 => (BP, Option::None),
+            _ => (BX, Option::None)
+        };
+        OpArgument{
+            location: ArgLocation::ModRMAddress16{
+                offset: if self.mode == 0 { Option::None } else { Some(disp as u16) },
+                reg1: Some(reg1 as u8),
+                reg2,
+                size
+            },
+            is_memory: true
+        }
+    }
 }
 
 /// The SIB (scale-index-base) byte structure used within x86.
@@ -168,7 +211,7 @@ pub struct ParsedModRM{
 impl ParsedModRM{
     /// Decodes the current byte stream (starting at the ModRM byte) into a fully formed ParsedModRM struct
     pub fn from_bytes(bytestream: &[u8]) -> Result<ParsedModRM, VMError>{
-        if bytestream.len() < 5{
+        if bytestream.len() < 2{
             return Err(VMError::DecodingOverrun);
         }
         let mut parsed = ParsedModRM::default();
@@ -178,7 +221,7 @@ impl ParsedModRM{
         bytes = &bytes[1..]; //advance to next byte
         size += 1;
         if parsed.modrm.mode != 3 && parsed.modrm.rm == 4 {
-            parsed.sib = Some(SIB::parse(bytes[0]));
+            parsed.sib = Some(SIB::parse(u8_from_bytes(bytes)?));
             bytes = &bytes[1..];
             size += 1;
         }
@@ -197,6 +240,26 @@ impl ParsedModRM{
         parsed.size = size;
         Ok(parsed)
     }
+    /// Decodes the current byte stream (starting at the ModRM byte) using 16 bit addressing rules.
+    /// This is used when an address size override prefix is present
+    pub fn from_bytes16(bytestream: &[u8]) -> Result<ParsedModRM, VMError>{
+        if bytestream.len() < 2{
+            return Err(VMError::DecodingOverrun);
+        }
+        let mut parsed = ParsedModRM::default();
+        let bytes = &bytestream[2..]; //skip opcode and ModRM
+        parsed.modrm = self::ModRM::parse(bytestream[1]);
+        parsed.size = 1;
+        //16 bit displacements
+        if (parsed.modrm.mode == 0 && parsed.modrm.rm == 6) || parsed.modrm.mode == 2 {
+            parsed.disp = Some(u16_from_bytes(bytes)? as u32);
+            parsed.size += 2;
+        } else if parsed.modrm.mode == 1 {
+            parsed.disp = Some(((u8_from_bytes(bytes)? as i8) as i32) as u32);
+            parsed.size += 1;
+        }
+        Ok(parsed)
+    }
 }
 
 /// Decodes the set of arguments for an opcode assuming that no ModRM value is needed (this is used primarily for simpler unit testing)
@@ -204,9 +267,9 @@ pub fn decode_args(opcode: &Opcode, bytestream: &[u8], args: &mut [OpArgument; M
     decode_args_with_modrm(opcode, bytestream, args, size_override, false, None)
 }
 /// Decodes the set of arguments for a given opcode within a given byte stream. This returns the total size of the arguments in opcode bytes
-pub fn decode_args_with_modrm(opcode: &Opcode, bytestream: &[u8], args: &mut [OpArgument; MAX_ARGS], size_override: bool, _address_override: bool, parsed_modrm: Option<ParsedModRM>) -> Result<usize, VMError>{
+pub fn decode_args_with_modrm(opcode: &Opcode, bytestream: &[u8], args: &mut [OpArgument; MAX_ARGS], size_override: bool, address_override: bool, parsed_modrm: Option<ParsedModRM>) -> Result<usize, VMError>{
     use ArgSource::*;
-    if bytestream.len() < 6{
+    if bytestream.is_empty(){
         return Err(VMError::DecodingOverrun);
     }
     let opcode_byte = bytestream[0];
@@ -227,7 +290,11 @@ pub fn decode_args_with_modrm(opcode: &Opcode, bytestream: &[u8], args: &mut [Op
                 0
             },
             ModRM => {
-                args[n] = modrm.modrm.decode(&modrm.sib.unwrap_or_default(), modrm.disp.unwrap_or(0), arg_size);
+                args[n] = if address_override{
+                    modrm.modrm.decode16(modrm.disp.unwrap_or(0), arg_size)
+                }else{
+                    modrm.modrm.decode(&modrm.sib.unwrap_or_default(), modrm.disp.unwrap_or(0), arg_size)
+                };
                 0 //size calculation was done before here, so don't need to advance any
             },
             ModRMReg => {
@@ -243,7 +310,7 @@ pub fn decode_args_with_modrm(opcode: &Opcode, bytestream: &[u8], args: &mut [Op
             ImmediateValue | JumpRel => {
                 let (loc, sz) = match arg_size{
                     ValueSize::None => (ArgLocation::Immediate(SizedValue::None), 0),
-                    ValueSize::Byte => (ArgLocation::Immediate(SizedValue::Byte(bytes[0])), 1),
+                    ValueSize::Byte => (ArgLocation::Immediate(SizedValue::Byte(u8_from_bytes(bytes)?)), 1),
                     ValueSize::Word => {
                         (ArgLocation::Immediate(SizedValue::Word(u16_from_bytes(bytes)?)), 2)
                     },
@@ -253,6 +320,7 @@ pub fn decode_args_with_modrm(opcode: &Opcode, bytestream: &[u8], args: &mut [Op
                     ValueSize::Qword => { //throw error here in the future?
                         (ArgLocation::Immediate(SizedValue::None), 0)
                     },
+                    ValueSize::Dqword => (ArgLocation::Immediate(SizedValue::None), 0),
                 };
                 args[n].location = loc;
                 args[n].is_memory = false;
@@ -324,6 +392,17 @@ mod tests {
         };
         (args[0], res) 
     }
+    //helper function to simplify testing
+    fn decode_arg_modrm16(source: ArgSource, size: OpcodeValueSize, bytecode: &[u8]) -> (OpArgument, usize){
+        let mut args:[OpArgument; MAX_ARGS] = Default::default();
+        let mut opcode:Opcode = Default::default();
+        opcode.arg_source[0] = source;
+        opcode.arg_size[0] = size;
+
+        let modrm = ParsedModRM::from_bytes16(bytecode).unwrap();
+        let res = decode_args_with_modrm(&opcode, bytecode, &mut args, false, true, Some(modrm)).unwrap();
+        (args[0], res)
+    }
     #[test]
     fn decode_immediate_address(){
         use OpcodeValueSize::*;
@@ -554,4 +633,76 @@ mod tests {
             assert_eq!(arg.location, ArgLocation::RegisterValue(Reg32::EAX as u8, ValueSize::Dword));
         }
     }
-}
\ No newline at end of file
+    #[test]
+    fn decode_modrm16(){
+        use OpcodeValueSize::*;
+        use ValueSize::*;
+        {
+            let mut bytes = vec![
+                0xFA, //opcode
+                0x00 //modrm [bx + si]
+            ];
+            bytes.resize(bytes.len() + 16, 0);
+            let (arg, size) = decode_arg_modrm16(ArgSource::ModRM, Fixed(Word), &bytes);
+            assert_eq!(size, 2);
+            assert_eq!(arg.location, ArgLocation::ModRMAddress16{
+                offset: Option::None,
+                reg1: Some(Reg16::BX as u8),
+                reg2: Some(Reg16::SI as u8),
+                size: ValueSize::Word
+            });
+        }
+        {
+            let mut bytes = vec![
+                0xFA, //opcode
+                0x06, //modrm [disp16]
+                0x34,
+                0x12
+            ];
+            bytes.resize(bytes.len() + 16, 0);
+            let (arg, size) = decode_arg_modrm16(ArgSource::ModRM, Fixed(Word), &bytes);
+            assert_eq!(size, 4);
+            assert_eq!(arg.location, ArgLocation::ModRMAddress16{
+                offset: Some(0x1234),
+                reg1: Option::None,
+                reg2: Option::None,
+                size: ValueSize::Word
+            });
+        }
+        {
+            let mut bytes = vec![
+                0xFA, //opcode
+                0x46, //modrm [bp + disp8]
+                0xFE //disp8 (-2)
+            ];
+            bytes.resize(bytes.len() + 16, 0);
+            let (arg, size) = decode_arg_modrm16(ArgSource::ModRM, Fixed(Dword), &bytes);
+            assert_eq!(size, 3);
+            assert_eq!(arg.location, ArgLocation::ModRMAddress16{
+                offset: Some(0xFFFE),
+                reg1: Some(Reg16::BP as u8),
+                reg2: Option::None,
+                size: ValueSize::Dword
+            });
+        }
+        {
+            let mut bytes = vec![
+                0xFA, //opcode
+                0xBB, //modrm [bp + di + disp16] with /r=di
+                0x00,
+                0x80
+            ];
+            bytes.resize(bytes.len() + 16, 0);
+            let (arg, size) = decode_arg_modrm16(ArgSource::ModRM, Fixed(Word), &bytes);
+            assert_eq!(size, 4);
+            assert_eq!(arg.location, ArgLocation::ModRMAddress16{
+                offset: Some(0x8000),
+                reg1: Some(Reg16::BP as u8),
+                reg2: Some(Reg16::DI as u8),
+                size: ValueSize::Word
+            });
+            let (arg, _) = decode_arg_modrm16(ArgSource::ModRMReg, Fixed(Word), &bytes);
+            assert_eq!(arg.location, ArgLocation::RegisterValue(Reg16::DI as u8, ValueSize::Word));
+        }
+    }
+}
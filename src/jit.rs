@@ -0,0 +1,355 @@
+use crate::vm::*;
+use crate::pipeline::*;
+use crate::opcodes::*;
+use crate::structs::*;
+use crate::threaded::*;
+use crate::flags::X86Flags;
+use std::sync::Arc;
+
+/// The size of each executable memory mapping which compiled blocks are written into
+const CHUNK_SIZE: usize = 0x10000;
+/// The adjust flag, which `and`, `or` and `xor` leave unchanged in the VM, but which is undefined for the host
+const ADJUST_FLAG: u8 = 0x10;
+/// The minimum number of consecutive opcodes which are compiled. Entering native code has a fixed cost, which single opcodes are not worth
+const MIN_COMPILED_OPS: usize = 2;
+
+/// The entry point of a compiled block. It is passed the VM registers and the arithmetic flags as packed by `pack_flags`,
+/// and returns the amount to add to EIP in the top 32 bits and the resulting flags in the bottom 32 bits
+type NativeFn = unsafe extern "sysv64" fn(regs: *mut u32, flags: u32) -> u64;
+
+lazy_static! {
+    /// Native code loads and stores the flags using LAHF and SAHF, which a few early x86-64 processors do not support
+    static ref LAHF_SAHF: bool = std::arch::x86_64::__cpuid(0x8000_0000).eax >= 0x8000_0001
+        && std::arch::x86_64::__cpuid(0x8000_0001).ecx & 1 != 0;
+}
+
+/// Packs the arithmetic flags with the flags loaded by SAHF in bits 8 through 15, and the overflow flag in bit 0
+fn pack_flags(flags: &X86Flags) -> u32{
+    (flags.carry as u32) << 8 | 1 << 9 | (flags.parity as u32) << 10 | (flags.adjust as u32) << 12
+        | (flags.zero as u32) << 14 | (flags.sign as u32) << 15 | flags.overflow as u32
+}
+
+/// The reverse of `pack_flags`, leaving the flags which native code does not modify unchanged
+fn unpack_flags(flags: &mut X86Flags, packed: u32){
+    flags.carry = packed & 1 << 8 != 0;
+    flags.parity = packed & 1 << 10 != 0;
+    flags.adjust = packed & 1 << 12 != 0;
+    flags.zero = packed & 1 << 14 != 0;
+    flags.sign = packed & 1 << 15 != 0;
+    flags.overflow = packed & 1 != 0;
+}
+
+/// A mapping of memory which compiled blocks are written into.
+/// It is only writeable while a block is being written, and otherwise is only executable
+struct Chunk{
+    ptr: *mut u8
+}
+
+//the memory is only ever written through a CodeArena, which requires mutable access
+unsafe impl Send for Chunk{}
+unsafe impl Sync for Chunk{}
+
+impl Chunk{
+    fn new() -> Option<Chunk>{
+        let ptr = unsafe{
+            libc::mmap(std::ptr::null_mut(), CHUNK_SIZE, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+        if ptr == libc::MAP_FAILED{
+            return None;
+        }
+        Some(Chunk{
+            ptr: ptr as *mut u8
+        })
+    }
+    fn protect(&self, protection: libc::c_int) -> bool{
+        unsafe{ libc::mprotect(self.ptr as *mut libc::c_void, CHUNK_SIZE, protection) == 0 }
+    }
+}
+
+impl Drop for Chunk{
+    fn drop(&mut self){
+        unsafe{
+            libc::munmap(self.ptr as *mut libc::c_void, CHUNK_SIZE);
+        }
+    }
+}
+
+/// Allocates executable memory for compiled blocks. Each chunk is freed once every block within it has been discarded
+#[derive(Default)]
+pub(crate) struct CodeArena{
+    chunk: Option<Arc<Chunk>>,
+    used: usize
+}
+
+impl CodeArena{
+    /// Copies the code into executable memory, returning the chunk it was written into and its address
+    fn write(&mut self, code: &[u8]) -> Option<(Arc<Chunk>, *const u8)>{
+        if code.len() > CHUNK_SIZE{
+            return None;
+        }
+        if self.chunk.is_none() || self.used + code.len() > CHUNK_SIZE{
+            self.chunk = Some(Arc::new(Chunk::new()?));
+            self.used = 0;
+        }
+        let chunk = self.chunk.clone()?;
+        if !chunk.protect(libc::PROT_READ | libc::PROT_WRITE){
+            return None;
+        }
+        let address = unsafe{
+            let address = chunk.ptr.add(self.used);
+            std::ptr::copy_nonoverlapping(code.as_ptr(), address, code.len());
+            address
+        };
+        if !chunk.protect(libc::PROT_READ | libc::PROT_EXEC){
+            return None;
+        }
+        self.used += code.len();
+        Some((chunk, address))
+    }
+}
+
+/// A run of opcodes compiled into native code.
+/// Only opcodes which can not fail are compiled, so once it begins a compiled block always executes completely
+pub(crate) struct NativeBlock{
+    _chunk: Arc<Chunk>,
+    entry: NativeFn,
+    /// The number of opcodes which were compiled
+    pub(crate) ops: usize
+}
+
+impl NativeBlock{
+    /// Executes the compiled opcodes and leaves EIP pointing to the opcode after them.
+    /// The caller is responsible for charging the gas of the compiled opcodes
+    pub(crate) fn run(&self, vm: &mut VM){
+        let result = unsafe{ (self.entry)(vm.regs.as_mut_ptr(), pack_flags(&vm.flags)) };
+        unpack_flags(&mut vm.flags, result as u32);
+        vm.eip = vm.eip.wrapping_add((result >> 32) as u32);
+    }
+}
+
+/// Compiles the longest run of supported opcodes at the start of `pipeline`.
+/// None is returned if fewer than MIN_COMPILED_OPS opcodes are supported, in which case nothing is written to the arena
+pub(crate) fn compile(pipeline: &[Pipeline], arena: &mut CodeArena) -> Option<NativeBlock>{
+    if !*LAHF_SAHF{
+        return None;
+    }
+    let mut e = Emitter::default();
+    //adding 0x7F to the overflow flag in AL overflows only if it is set, and SAHF then loads the rest of the flags from AH
+    //mov eax, esi; add al, 0x7F; sahf
+    e.bytes(&[0x89, 0xF0, 0x04, 0x7F, 0x9E]);
+    //EIP is tracked relative to the first opcode, and the native code returns the amount to add to it
+    let mut offset: u32 = 0;
+    let mut ops = 0;
+    let mut branched = false;
+    for p in pipeline{
+        let start = e.code.len();
+        match e.opcode(p, offset){
+            Some(Flow::Next) => {
+                offset = offset.wrapping_add(p.eip_size as u32);
+            },
+            Some(Flow::Jump(target)) => {
+                offset = target;
+            },
+            Some(Flow::Branch(cc, target)) => {
+                //mov edx, fall_through; j!cc skip; mov edx, target; skip:
+                e.byte(0xBA);
+                e.dword(offset.wrapping_add(p.eip_size as u32));
+                e.bytes(&[0x70 | ((cc & 0x0F) ^ 1), 5, 0xBA]);
+                e.dword(target);
+                ops += 1;
+                branched = true;
+                break;
+            },
+            None => {
+                e.code.truncate(start);
+                break;
+            }
+        }
+        ops += 1;
+    }
+    if ops < MIN_COMPILED_OPS{
+        return None;
+    }
+    if !branched{
+        //mov edx, offset
+        e.byte(0xBA);
+        e.dword(offset);
+    }
+    //lahf; seto al; movzx eax, ax; shl rdx, 32; or rax, rdx; ret
+    e.bytes(&[0x9F, 0x0F, 0x90, 0xC0, 0x0F, 0xB7, 0xC0, 0x48, 0xC1, 0xE2, 0x20, 0x48, 0x09, 0xD0, 0xC3]);
+    let (chunk, address) = arena.write(&e.code)?;
+    Some(NativeBlock{
+        _chunk: chunk,
+        entry: unsafe{ std::mem::transmute::<*const u8, NativeFn>(address) },
+        ops
+    })
+}
+
+/// How execution continues after a compiled opcode
+enum Flow{
+    Next,
+    /// An unconditional jump to the specified offset
+    Jump(u32),
+    /// A conditional branch with the specified condition code to the specified offset
+    Branch(u8, u32)
+}
+
+/// Generates the native code of a block. The guest registers are accessed in memory through RDI,
+/// and RAX, RCX, and RDX are used as scratch registers
+#[derive(Default)]
+struct Emitter{
+    code: Vec<u8>
+}
+
+impl Emitter{
+    fn byte(&mut self, b: u8){
+        self.code.push(b);
+    }
+    fn bytes(&mut self, b: &[u8]){
+        self.code.extend_from_slice(b);
+    }
+    fn dword(&mut self, d: u32){
+        self.code.extend_from_slice(&d.to_le_bytes());
+    }
+    /// Emits an opcode with a ModRM byte which addresses the guest register `r` as `[rdi + r * 4]`
+    fn guest_reg(&mut self, opcode: u8, reg: u8, r: usize){
+        self.bytes(&[opcode, 0x47 | (reg << 3), (r * 4) as u8]);
+    }
+    /// Emits a single opcode, returning None if it is not supported, in which case anything emitted must be discarded
+    /// `offset` is the position of the opcode relative to the first compiled opcode, which jump targets are also relative to
+    fn opcode(&mut self, p: &Pipeline, offset: u32) -> Option<Flow>{
+        if p.size_override{
+            return None;
+        }
+        let (arg0, arg1) = (p.args[0].location, p.args[1].location);
+        //the /digit of the 0x81 opcode for each arithmetic opcode, and whether it is a logic opcode
+        let arithmetic = match p.kind{
+            OpcodeKind::Add => Some((0, false)),
+            OpcodeKind::Or => Some((1, true)),
+            OpcodeKind::And => Some((4, true)),
+            OpcodeKind::Sub => Some((5, false)),
+            OpcodeKind::Xor => Some((6, true)),
+            OpcodeKind::Cmp => Some((7, false)),
+            _ => None
+        };
+        if let Some((digit, logic)) = arithmetic{
+            let d = match Operand::new(arg0)?{
+                Operand::Reg(d) => d,
+                _ => return None
+            };
+            let s = Operand::source(arg1)?;
+            if logic{
+                //lahf; mov dl, ah
+                self.bytes(&[0x9F, 0x88, 0xE2]);
+            }
+            match s{
+                Operand::Reg(s) => {
+                    //mov ecx, s; op d, ecx
+                    self.guest_reg(0x8B, 1, s);
+                    self.guest_reg((digit << 3) | 1, 1, d);
+                },
+                Operand::Imm(v) => {
+                    self.guest_reg(0x81, digit, d);
+                    self.dword(v);
+                },
+                Operand::Mem(_) => return None
+            }
+            if logic{
+                //keep the adjust flag from before the opcode. The overflow flag is cleared by both the opcode and the `or`
+                //lahf; and dl, ADJUST_FLAG; and ah, !ADJUST_FLAG; or ah, dl; sahf
+                self.bytes(&[0x9F, 0x80, 0xE2, ADJUST_FLAG, 0x80, 0xE4, !ADJUST_FLAG, 0x08, 0xD4, 0x9E]);
+            }
+        }else if p.kind == OpcodeKind::Mov{
+            let d = match Operand::new(arg0)?{
+                Operand::Reg(d) => d,
+                _ => return None
+            };
+            match Operand::new(arg1)?{
+                Operand::Reg(s) => {
+                    self.guest_reg(0x8B, 1, s);
+                    self.guest_reg(0x89, 1, d);
+                },
+                Operand::Imm(v) => {
+                    self.guest_reg(0xC7, 0, d);
+                    self.dword(v);
+                },
+                Operand::Mem(_) => return None
+            }
+        }else if p.kind == OpcodeKind::Increment || p.kind == OpcodeKind::Decrement{
+            let d = match Operand::new(arg0)?{
+                Operand::Reg(d) => d,
+                _ => return None
+            };
+            let digit = if p.kind == OpcodeKind::Increment { 0 } else { 1 };
+            self.guest_reg(0xFF, digit, d);
+        }else if p.kind == OpcodeKind::Lea{
+            let d = match Operand::new(arg0)?{
+                Operand::Reg(d) => d,
+                _ => return None
+            };
+            let a = EffectiveAddress::new(arg1)?;
+            let scale = match (a.index, a.scale){
+                (Option::None, _) => 0,
+                (Some(_), 1) => 0,
+                (Some(_), 2) => 1,
+                (Some(_), 4) => 2,
+                (Some(_), 8) => 3,
+                _ => return None
+            };
+            match a.base{
+                Some(b) => self.guest_reg(0x8B, 0, b),
+                Option::None => {
+                    //mov eax, 0, which unlike xor does not modify flags
+                    self.byte(0xB8);
+                    self.dword(0);
+                }
+            }
+            match a.index{
+                Some(i) => {
+                    //mov ecx, i; lea eax, [rax + rcx * scale + offset]
+                    self.guest_reg(0x8B, 1, i);
+                    self.bytes(&[0x8D, 0x84, (scale << 6) | 0x08]);
+                },
+                Option::None => {
+                    //lea eax, [rax + offset]
+                    self.bytes(&[0x8D, 0x80]);
+                }
+            }
+            self.dword(a.offset);
+            self.guest_reg(0x89, 0, d);
+        }else if p.kind == OpcodeKind::JmpRel || p.kind == OpcodeKind::Jcc{
+            let rel = match arg0{
+                ArgLocation::Immediate(v) => v.u32_sx().ok()?,
+                _ => return None
+            };
+            let target = offset.wrapping_add(p.eip_size as u32).wrapping_add(rel);
+            if p.kind == OpcodeKind::JmpRel{
+                return Some(Flow::Jump(target));
+            }
+            return Some(Flow::Branch(p.opcode, target));
+        }else{
+            return None;
+        }
+        Some(Flow::Next)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_rejected_run_not_written(){
+        let mut pipeline = vec![Pipeline::default(); 2];
+        pipeline[0].kind = OpcodeKind::Increment;
+        pipeline[0].args[0].location = ArgLocation::RegisterValue(Reg32::EAX as u8, ValueSize::Dword);
+        let mut arena = CodeArena::default();
+        //a single supported opcode is not worth compiling, so nothing is written for it
+        assert!(compile(&pipeline, &mut arena).is_none());
+        assert!(arena.chunk.is_none());
+        assert_eq!(arena.used, 0);
+        pipeline[1] = pipeline[0];
+        assert_eq!(compile(&pipeline, &mut arena).unwrap().ops, 2);
+        assert!(arena.used > 0);
+    }
+}
@@ -24,6 +24,25 @@ mod ops;
 pub mod flags;
 /// Helper functions used for bit manipulation
 mod bitmanip;
+/// Deterministic software floating point arithmetic used by the x87 opcodes
+#[cfg(feature = "x87")]
+mod softfloat;
+/// The x87 FPU state and opcode logic functions
+#[cfg(feature = "x87")]
+pub mod x87;
+/// The opcode logic functions for the integer SSE2 subset
+#[cfg(feature = "sse2")]
+mod sse;
+/// Translation of read only code into threaded code
+pub mod threaded;
+/// Compilation of translated blocks into native x86-64 code
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+compile_error!("the jit feature is only supported on x86-64 Linux");
+/// Breakpoint and memory watchpoint support
+#[cfg(feature = "debugger")]
+pub mod debugger;
 
 
 
@@ -18,14 +18,16 @@ pub struct BufferMemory{
 
 impl fmt::Display for BufferMemory {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let formatted_vec = String::from_utf8(self.memory.clone()).unwrap();
+        let formatted_vec = String::from_utf8_lossy(&self.memory);
         write!(f, "{}", formatted_vec)
     }
 }
 /// The system for tracking all memory within the VM
 #[derive(Default, Debug)]
 pub struct MemorySystem{
-    map: HashMap<u32, BufferMemory>
+    map: HashMap<u32, BufferMemory>,
+    /// Incremented whenever memory is added or read only memory may have been modified
+    generation: u64
 }
 
 impl MemorySystem{
@@ -44,19 +46,26 @@ impl MemorySystem{
             memory: Vec::default(),
         };
         b.memory.resize(size as usize, 0);
-        self.map.insert(aligned, b);
-        self.map.get_mut(&aligned).unwrap().memory[0] = 10;
-        Ok(&mut self.map.get_mut(&aligned).unwrap().memory[0..])
+        self.generation += 1;
+        let memory = &mut self.map.entry(aligned).or_insert(b).memory;
+        if let Some(first) = memory.first_mut(){
+            *first = 10;
+        }
+        Ok(&mut memory[0..])
     }
 
     /// Note that this will not respect the "readonly" flag, nor readonly memory space
     /// This is designed for internal use and with the VM exposed methods checking for these errors
     pub fn get_mut_memory(&mut self, address: u32) -> Result<&mut [u8], VMError> {
+        if address < WRITEABLE_MEMORY{
+            //the VM can not write to read only memory, but the code within it may be changed externally
+            self.generation += 1;
+        }
         match self.map.get_mut(&(address & 0xFFFF0000)){
             Option::None => return Err(VMError::ReadUnloadedMemory(address)), //should never happen?
             Option::Some(m) =>  {
                 let local = (address & 0xFFFF) as usize;
-                if m.memory.len() - 1 < local{
+                if local >= m.memory.len(){
                     return Err(VMError::WroteBadMemory(address));
                 }
                 return Ok(&mut (&mut m.memory)[local..])
@@ -69,7 +78,7 @@ impl MemorySystem{
             Option::None => return Err(VMError::ReadUnloadedMemory(address)),
             Option::Some(m) =>  {
                 let local = (address & 0xFFFF) as usize;
-                if m.memory.len() - 1 < local{
+                if local >= m.memory.len(){
                     return Err(VMError::ReadBadMemory(address));
                 }
                 return Ok(&(&m.memory)[local..])
@@ -80,7 +89,7 @@ impl MemorySystem{
     pub fn get_sized_memory(&self, address: u32, size: u32) -> Result<&[u8], VMError>{
         let m = self.get_memory(address)?;
         if m.len() < size as usize {
-            return Err(VMError::ReadBadMemory(address + size - 1));
+            return Err(VMError::ReadBadMemory(address.wrapping_add(size).wrapping_sub(1)));
         }
         Ok(&m[0..size as usize])
     }
@@ -90,7 +99,7 @@ impl MemorySystem{
     pub fn get_mut_sized_memory(&mut self, address: u32, size: u32) -> Result<&mut [u8], VMError>{
         let m = self.get_mut_memory(address)?;
         if m.len() < size as usize {
-            return Err(VMError::WroteBadMemory(address + size - 1));
+            return Err(VMError::WroteBadMemory(address.wrapping_add(size).wrapping_sub(1)));
         }
         Ok(&mut m[0..size as usize])
     }
@@ -121,6 +130,13 @@ impl MemorySystem{
         let v: [u8; 8] = *(&m[0..8].try_into().unwrap());
         Ok(u64::from_le_bytes(v))
     }
+    /// Retreives a single u128 from memory, including endianness correction if needed
+    pub fn get_u128(&self, address: u32) -> Result<u128, VMError>{
+        use std::convert::TryInto;
+        let m = self.get_sized_memory(address, 16)?;
+        let v: [u8; 16] = *(&m[0..16].try_into().unwrap());
+        Ok(u128::from_le_bytes(v))
+    }
     /// Sets a single u8 in memory
     pub fn set_u8(&mut self, address: u32, v: u8) -> Result<u8, VMError>{
         let m = self.get_mut_sized_memory(address, 1)?;
@@ -148,8 +164,20 @@ impl MemorySystem{
         (&mut m[0..8]).copy_from_slice(&d);
         Ok(v)
     }
+    /// Sets a single u128 in memory, including endianness correction if needed
+    pub fn set_u128(&mut self, address: u32, v: u128) -> Result<u128, VMError>{
+        let m = self.get_mut_sized_memory(address, 16)?;
+        let d = v.to_le_bytes();
+        (&mut m[0..16]).copy_from_slice(&d);
+        Ok(v)
+    }
     /// Determines if a block of memory exists
     pub fn section_exists(&self, address: u32) -> bool{
         self.map.contains_key(&(address & 0xFFFF0000))
     }
+    /// Retrieves a counter which changes whenever memory is added or read only memory is retrieved as mutable.
+    /// This is used to determine when previously decoded code in read only memory must be decoded again
+    pub fn generation(&self) -> u64{
+        self.generation
+    }
 }
\ No newline at end of file
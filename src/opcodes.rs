@@ -1,6 +1,7 @@
 use crate::structs::*;
 use crate::vm::*;
 use crate::pipeline::*;
+use std::sync::Arc;
 
 #[allow(dead_code)] //remove after design stuff is done
 
@@ -73,6 +74,9 @@ pub enum PipelineBehavior{
     /// This is for predictable jumps with a hard coded jump target.
     /// The pipeline building process will follow these jumps since they are static and can be predicted
     RelativeJump,
+    /// This is for the same behavior as RelativeJump but with the same gas penalty as Unpredictable
+    /// used for `jmp relW`, which was treated as unpredictable before it could be followed, so that its gas cost is unchanged
+    RelativeJumpWithGas,
     /// This is for any opcode which changes EIP or execution state that may affect opcodes later in the pipeline and can not easily be predicted
     /// this includes opcodes like `jne` and also opcodes like `jmp [eax]`, as well as system calls using `int`
     Unpredictable,
@@ -82,14 +86,59 @@ pub enum PipelineBehavior{
     UnpredictableNoGas,
 }
 
+/// Identifies opcodes which are handled specially after decoding, such as by opcode fusion and block translation.
+/// Kinds without a size suffix are the NativeWord forms, and other forms of the same instruction are `Other`
+#[derive(PartialEq, Debug)]
+#[derive(Copy, Clone)]
+pub enum OpcodeKind{
+    /// An opcode which is never handled specially
+    Other,
+    /// `mov` of any size between registers, memory, and immediates
+    Mov,
+    Add,
+    Or,
+    And,
+    Sub,
+    Xor,
+    Cmp,
+    Cmp8,
+    Test,
+    Test8,
+    Increment,
+    Decrement,
+    Lea,
+    /// `push` of any source, including segment registers
+    Push,
+    /// `pop` of any destination, including segment registers
+    Pop,
+    /// A conditional jump with a relative target
+    Jcc,
+    /// An unconditional jump with a relative target
+    JmpRel,
+    Rdtsc,
+    /// `int` and `int3`, which call the Hypervisor
+    Interrupt,
+    /// `ud2`, which calls the Hypervisor
+    Trap,
+    /// A string opcode with a `rep`, `repe`, or `repne` prefix
+    Rep,
+    /// A pair of opcodes fused into a single pipeline slot
+    Fused
+}
+
 /// Defines an opcode with all the information needed for decoding the opcode and its arguments
 #[derive(Copy, Clone)]
 pub struct Opcode{
     pub function: OpcodeFn,
+    pub kind: OpcodeKind,
     pub arg_size: [OpcodeValueSize; MAX_ARGS],
     pub arg_source: [ArgSource; MAX_ARGS],
     pub gas_cost: GasCost,
     pub pipeline_behavior: PipelineBehavior,
+    /// Set if the opcode accepts an address size override prefix. Any other opcode using the prefix is invalid
+    pub address_override: bool,
+    /// Set if the opcode accepts a LOCK prefix. This is only valid when the first argument is in memory
+    pub lockable: bool,
     pub defined: bool
 }
 
@@ -132,11 +181,14 @@ impl Default for Opcode{
     fn default() -> Opcode{
         Opcode{
             function: op_undefined,
+            kind: OpcodeKind::Other,
             arg_size: [OpcodeValueSize::Fixed(ValueSize::None); 3],
             arg_source: [ArgSource::None; 3],
             gas_cost: GasCost::None,
             //this defaults to conditional so that an unknown opcode is considered conditional
             pipeline_behavior: PipelineBehavior::Unpredictable,
+            address_override: false,
+            lockable: false,
             defined: false
         }
     }
@@ -145,8 +197,16 @@ impl Default for Opcode{
 /// The master opcode table.
 /// index: lower byte is primary opcode.
 /// upper bit is set if 0x0F prefix is used (ie, extended opcode)
-pub const OPCODE_TABLE_SIZE:usize = 0x1FF;
-const OP_TWOBYTE:usize = 1 << 8;
+/// Extended opcodes which require a mandatory 0x66 or 0xF3 prefix, and opcodes using the 0x0F 0x38 escape, are placed in their own regions after this
+pub const OPCODE_TABLE_SIZE:usize = 0x500;
+/// The region of the opcode table for extended opcodes (ie, with a 0x0F prefix)
+pub const OP_TWOBYTE:usize = 1 << 8;
+/// The region of the opcode table for extended opcodes with a mandatory 0x66 prefix
+pub const OP_PREFIX_66:usize = 0x200;
+/// The region of the opcode table for extended opcodes with a mandatory 0xF3 prefix
+pub const OP_PREFIX_F3:usize = 0x300;
+/// The region of the opcode table for three byte opcodes (ie, with a 0x0F 0x38 prefix)
+pub const OP_THREEBYTE:usize = 0x400;
 
 /// This is a helper structure and set of functions for defining opcodes
 /// Basically it provides sane defaults for how opcodes are typically defined and saves a lot of typing and potential errors on the programmer's part
@@ -155,13 +215,18 @@ pub struct OpcodeDefiner{
     opcode: u8,
     len: usize,
     two_byte: bool,
+    three_byte: bool,
     group: Option<u8>,
     gas_level: Option<GasCost>,
     args: Vec<(ArgSource, OpcodeValueSize)>,
     function: Option<OpcodeFn>,
+    kind: Option<OpcodeKind>,
     jump: Option<PipelineBehavior>,
     has_modrm: bool,
-    reg_suffix: bool
+    reg_suffix: bool,
+    address_override: bool,
+    lockable: bool,
+    mandatory_prefix: Option<u8>
 }
 
 impl OpcodeDefiner{
@@ -170,6 +235,19 @@ impl OpcodeDefiner{
         self.two_byte = true;
         self
     }
+    /// Specifies that the opcode is a three byte opcode and therefore is prefixed with 0x0F 0x38
+    pub fn is_three_byte_op(&mut self) -> &mut OpcodeDefiner {
+        self.two_byte = true;
+        self.three_byte = true;
+        self
+    }
+    /// Specifies that the opcode is an extended opcode which requires a mandatory 0x66 or 0xF3 prefix.
+    /// The prefix then selects this opcode rather than acting as an operand size override or rep prefix
+    pub fn with_mandatory_prefix(&mut self, prefix: u8) -> &mut OpcodeDefiner {
+        self.two_byte = true;
+        self.mandatory_prefix = Some(prefix);
+        self
+    }
     /// Specifies that the opcode is a group opcode.
     /// Example: to encode `0xFF /0` one would use define_opcode(0xFF).is_group(0)
     pub fn is_group(&mut self, group: u8) -> &mut OpcodeDefiner{
@@ -182,6 +260,11 @@ impl OpcodeDefiner{
         self.function = Some(function);
         self
     }
+    /// Specifies the kind of the opcode, for opcodes which are handled specially after decoding
+    pub fn is_kind(&mut self, kind: OpcodeKind) -> &mut OpcodeDefiner{
+        self.kind = Some(kind);
+        self
+    }
     /// Specifies that the next argument for the opcode is from a particular source and of a particular size
     pub fn with_arg(&mut self, source: ArgSource, size: OpcodeValueSize) -> &mut OpcodeDefiner{
         
@@ -201,6 +284,11 @@ impl OpcodeDefiner{
         self.jump = Some(PipelineBehavior::RelativeJump);
         self
     }
+    /// Specifies that the opcode is a relative jump which should be followed, but to charge the unpredictable gas surcharge
+    pub fn is_jump_with_gas(&mut self) -> &mut OpcodeDefiner{
+        self.jump = Some(PipelineBehavior::RelativeJumpWithGas);
+        self
+    }
     /// Specifies that the opcode is unpredictable and pipeline filling should stop upon encountering it 
     pub fn is_unpredictable(&mut self) -> &mut OpcodeDefiner{
         self.jump = Some(PipelineBehavior::Unpredictable);
@@ -211,6 +299,18 @@ impl OpcodeDefiner{
         self.jump = Some(PipelineBehavior::UnpredictableNoGas);
         self
     }
+    /// Specifies that the opcode can be used with an address size override prefix (0x67)
+    /// Memory below 0x10000 is inaccessible, so this is only useful for LEA
+    pub fn allows_address_override(&mut self) -> &mut OpcodeDefiner{
+        self.address_override = true;
+        self
+    }
+    /// Specifies that the opcode can be used with a LOCK prefix (0xF0)
+    /// This is only valid for read-modify-write opcodes, and only when the destination is in memory
+    pub fn is_lockable(&mut self) -> &mut OpcodeDefiner{
+        self.lockable = true;
+        self
+    }
     /// Specifies the gas tier of the opcode
     pub fn with_gas(&mut self, gas: GasCost) -> &mut OpcodeDefiner{
         self.gas_level = Some(gas);
@@ -303,9 +403,13 @@ impl OpcodeDefiner{
         };
         for n in 0..limit {
             let mut op = self.opcode as usize + n;
-            op = match self.two_byte{
-                true => op | OP_TWOBYTE,
-                false => op
+            op = match (self.two_byte, self.three_byte, self.mandatory_prefix){
+                (true, false, Some(0x66)) => op | OP_PREFIX_66,
+                (true, false, Some(0xF3)) => op | OP_PREFIX_F3,
+                (_, _, Some(_)) => panic!("Incorrect opcode configuration"),
+                (true, true, Option::None) => op | OP_THREEBYTE,
+                (true, false, Option::None) => op | OP_TWOBYTE,
+                (false, _, Option::None) => op
             };
             if op == 0x90 {
                 if table[op].defined {
@@ -336,8 +440,11 @@ impl OpcodeDefiner{
                 }
                 table[op].opcodes[inner].defined = true;
                 table[op].opcodes[inner].function = self.function.unwrap();
+                table[op].opcodes[inner].kind = self.kind.unwrap_or(OpcodeKind::Other);
                 table[op].opcodes[inner].gas_cost = self.gas_level.unwrap();
                 table[op].opcodes[inner].pipeline_behavior = self.jump.unwrap();
+                table[op].opcodes[inner].address_override = self.address_override;
+                table[op].opcodes[inner].lockable = self.lockable;
                 for n in 0..self.args.len(){
                     let (source, size) = self.args[n];
                     table[op].opcodes[inner].arg_source[n] = source;
@@ -354,12 +461,37 @@ pub fn define_opcode(opcode: u8) -> OpcodeDefiner{
     d.len = 1;
     d
 }
+/// Defines a range of opcodes with the same properties and creates a new OpcodeDefiner helper struct
 pub fn define_opcode_multi(opcode: u8, len: usize) -> OpcodeDefiner{
     let mut d = OpcodeDefiner::default();
     d.opcode = opcode;
     d.len = len;
     d
 }
+/// A shared reference to the opcode table which a VM uses for decoding.
+/// By default this is the master qx86 OPCODES table.
+/// Custom opcodes can be added by copying OPCODES with copy_opcode_table, using define_opcode on the copy, and then setting `vm.opcodes` to the new table
+#[derive(Clone)]
+pub struct OpcodeTable(Arc<[OpcodeProperties; OPCODE_TABLE_SIZE]>);
+
+impl OpcodeTable{
+    /// Creates an opcode table from a modified copy of OPCODES, such as one created with copy_opcode_table.
+    /// The table is shared rather than copied when the OpcodeTable is cloned, so it can be used by many VMs
+    pub fn new(table: Box<[OpcodeProperties; OPCODE_TABLE_SIZE]>) -> OpcodeTable{
+        OpcodeTable(Arc::from(table))
+    }
+    /// Retrieves the underlying opcode table
+    pub fn table(&self) -> &[OpcodeProperties; OPCODE_TABLE_SIZE]{
+        &self.0
+    }
+}
+
+impl Default for OpcodeTable{
+    fn default() -> OpcodeTable{
+        OpcodeTable(OPCODES.clone())
+    }
+}
+
 /*
 Opcode definition convention note:
 This uses the format used by Intel assembly syntax
@@ -378,113 +510,152 @@ Use a suffix of "W" in comments to indicate that an argument can be either word
 lazy_static! {
     /// The master qx86 subset opcode map definition.
     /// Note this uses lazy_static so that the definitions can be constructed more simply while not incurring a runtime execution cost
-    pub static ref OPCODES: [OpcodeProperties; OPCODE_TABLE_SIZE] = {
+    pub static ref OPCODES: Arc<[OpcodeProperties; OPCODE_TABLE_SIZE]> = {
         use crate::ops::*;
         use OpcodeValueSize::*;
         use ValueSize::*;
         use ArgSource::*;
         use GasCost::*;
-        let mut ops: [OpcodeProperties; OPCODE_TABLE_SIZE] = [OpcodeProperties::default(); OPCODE_TABLE_SIZE];
+        let mut ops = vec![OpcodeProperties::default(); OPCODE_TABLE_SIZE];
         //nop
         define_opcode(0x90).calls(nop).with_gas(GasCost::None).into_table(&mut ops);
 
-        //lock
-        //this is technically a prefix, but since it is always the first prefix in an instruction, it is ok to just treat this
-        //like a normal "nop" instruction 
-        define_opcode(0xF0).calls(nop).with_gas(GasCost::None).into_table(&mut ops);
-
         //hlt
         define_opcode(0xF4).calls(hlt).with_gas(GasCost::None).is_unpredictable_no_gas().into_table(&mut ops);
+        //0x0F A2 cpuid
+        define_opcode(0xA2).is_two_byte_op().calls(cpuid).with_gas(Low).into_table(&mut ops);
+        //0x0F 31 rdtsc
+        define_opcode(0x31).is_two_byte_op().calls(rdtsc).is_kind(OpcodeKind::Rdtsc).with_gas(Low).into_table(&mut ops);
 
         //mov opcodes
         //0xB0 mov r8, imm8
-        define_opcode(0xB0).calls(mov).with_gas(VeryLow)
+        define_opcode(0xB0).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_suffix_reg8()
             .with_imm8()
             .into_table(&mut ops);
         //0xB8 mov rW, immW
-        define_opcode(0xB8).calls(mov).with_gas(VeryLow)
+        define_opcode(0xB8).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_suffix_regw()
             .with_immw()
             .into_table(&mut ops);
         //0x88 /r mov rm8, r8
-        define_opcode(0x88).calls(mov).with_gas(VeryLow)
+        define_opcode(0x88).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_rm8()
             .with_rm_reg8()
             .into_table(&mut ops);
         //0x89 /r mov rmW, rW       
-        define_opcode(0x89).calls(mov).with_gas(VeryLow)
+        define_opcode(0x89).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_rmw()
             .with_rm_regw()
             .into_table(&mut ops);
         //0x8A /r mov r8, rm8
-        define_opcode(0x8A).calls(mov).with_gas(VeryLow)
+        define_opcode(0x8A).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_rm_reg8()
             .with_rm8()
             .into_table(&mut ops); 
         //0x8B /r mov rW, rmW
-        define_opcode(0x8B).calls(mov).with_gas(VeryLow)
+        define_opcode(0x8B).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_rm_regw()
             .with_rmw()
             .into_table(&mut ops);
         //0xA0 mov AL, offs8
-        define_opcode(0xA0).calls(mov).with_gas(VeryLow)
+        define_opcode(0xA0).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_arg(HardcodedRegister(Reg8::AL as u8), Fixed(Byte))
             .with_offs8()
             .into_table(&mut ops);
         //0xA1 mov EAX/AX, offsW
-        define_opcode(0xA1).calls(mov).with_gas(VeryLow)
+        define_opcode(0xA1).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_arg(HardcodedRegister(Reg32::EAX as u8), NativeWord) //Reg32::EAX resolves to the same as Reg16:AX
             .with_offsw()
             .into_table(&mut ops);
         //0xA2 mov offs8, AL
-        define_opcode(0xA2).calls(mov).with_gas(VeryLow)
+        define_opcode(0xA2).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_offs8()
             .with_arg(HardcodedRegister(Reg8::AL as u8), Fixed(Byte))
             .into_table(&mut ops);
         //0xA3 mov offsW, EAX/AX
-        define_opcode(0xA3).calls(mov).with_gas(VeryLow)
+        define_opcode(0xA3).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_offsw()
             .with_arg(HardcodedRegister(Reg32::EAX as u8), NativeWord) //Reg32::EAX resolves to the same as Reg16:AX
             .into_table(&mut ops);
         //0xC6 mov rm8, imm8
-        define_opcode(0xC6).calls(mov).with_gas(VeryLow)
+        define_opcode(0xC6).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_rm8()
             .with_imm8()
             .into_table(&mut ops);
         //0xC7 mov rmW, immW
-        define_opcode(0xC7).calls(mov).with_gas(VeryLow)
+        define_opcode(0xC7).calls(mov).is_kind(OpcodeKind::Mov).with_gas(VeryLow)
             .with_rmw()
             .with_immw()
             .into_table(&mut ops);
         //push opcodes
         //0x50 +r push rW
-        define_opcode(0x50).calls(push).with_gas(VeryLow)
+        define_opcode(0x50).calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
             .with_suffix_regw()
             .into_table(&mut ops);
         //0x68 push immW
-        define_opcode(0x68).calls(push).with_gas(VeryLow)
+        define_opcode(0x68).calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
             .with_immw()
             .into_table(&mut ops);
         //0x6A push imm8
-        define_opcode(0x6A).calls(push).with_gas(VeryLow)
+        define_opcode(0x6A).calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
             .with_imm8()
             .into_table(&mut ops);
         //0xFF /6 push rmW
-        define_opcode(0xFF).calls(push).with_gas(VeryLow)
+        define_opcode(0xFF).calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
             .is_group(6)
             .with_rmw()
             .into_table(&mut ops);
         //pop opcodes
         //0x58 +r pop rW
-        define_opcode(0x58).calls(pop).with_gas(VeryLow)
+        define_opcode(0x58).calls(pop).is_kind(OpcodeKind::Pop).with_gas(VeryLow)
             .with_suffix_regw()
             .into_table(&mut ops);
         //0x8F /0 pop rmW
-        define_opcode(0x8F).calls(pop).with_gas(VeryLow)
+        define_opcode(0x8F).calls(pop).is_kind(OpcodeKind::Pop).with_gas(VeryLow)
             .is_group(0)
             .with_rmw()
             .into_table(&mut ops);
+        //segment registers are never used, so push/pop of them only modifies the stack
+        //0x06 push ES
+        define_opcode(0x06).calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
+            .with_arg(Literal(SizedValue::Word(0)), NativeWord)
+            .into_table(&mut ops);
+        //0x0E push CS
+        define_opcode(0x0E).calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
+            .with_arg(Literal(SizedValue::Word(0)), NativeWord)
+            .into_table(&mut ops);
+        //0x16 push SS
+        define_opcode(0x16).calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
+            .with_arg(Literal(SizedValue::Word(0)), NativeWord)
+            .into_table(&mut ops);
+        //0x1E push DS
+        define_opcode(0x1E).calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
+            .with_arg(Literal(SizedValue::Word(0)), NativeWord)
+            .into_table(&mut ops);
+        //0x0F A0 push FS
+        define_opcode(0xA0).is_two_byte_op().calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
+            .with_arg(Literal(SizedValue::Word(0)), NativeWord)
+            .into_table(&mut ops);
+        //0x0F A8 push GS
+        define_opcode(0xA8).is_two_byte_op().calls(push).is_kind(OpcodeKind::Push).with_gas(VeryLow)
+            .with_arg(Literal(SizedValue::Word(0)), NativeWord)
+            .into_table(&mut ops);
+        //0x07 pop ES
+        define_opcode(0x07).calls(pop).is_kind(OpcodeKind::Pop).with_gas(VeryLow)
+            .into_table(&mut ops);
+        //0x17 pop SS
+        define_opcode(0x17).calls(pop).is_kind(OpcodeKind::Pop).with_gas(VeryLow)
+            .into_table(&mut ops);
+        //0x1F pop DS
+        define_opcode(0x1F).calls(pop).is_kind(OpcodeKind::Pop).with_gas(VeryLow)
+            .into_table(&mut ops);
+        //0x0F A1 pop FS
+        define_opcode(0xA1).is_two_byte_op().calls(pop).is_kind(OpcodeKind::Pop).with_gas(VeryLow)
+            .into_table(&mut ops);
+        //0x0F A9 pop GS
+        define_opcode(0xA9).is_two_byte_op().calls(pop).is_kind(OpcodeKind::Pop).with_gas(VeryLow)
+            .into_table(&mut ops);
         //call opcodes
         //0xE8 Call rel16
         //0xE8 Call rel32
@@ -511,7 +682,7 @@ lazy_static! {
             .into_table(&mut ops);
         //jmp opcodes
         //0xEB  JMP  rel8
-        define_opcode(0xEB).calls(jmp_rel).with_gas(Low)
+        define_opcode(0xEB).calls(jmp_rel).is_kind(OpcodeKind::JmpRel).with_gas(Low)
             .with_arg(ArgSource::JumpRel, Fixed(Byte))
             .is_jump()
             .into_table(&mut ops);
@@ -525,18 +696,33 @@ lazy_static! {
             .with_arg(ArgSource::JumpRel, Fixed(Byte))
             .is_unpredictable()
             .into_table(&mut ops);
+        //0xE0 LOOPNE rel8
+        define_opcode(0xE0).calls(loopne_rel).with_gas(Low)
+            .with_arg(ArgSource::JumpRel, Fixed(Byte))
+            .is_unpredictable()
+            .into_table(&mut ops);
+        //0xE1 LOOPE rel8
+        define_opcode(0xE1).calls(loope_rel).with_gas(Low)
+            .with_arg(ArgSource::JumpRel, Fixed(Byte))
+            .is_unpredictable()
+            .into_table(&mut ops);
+        //0xE2 LOOP rel8
+        define_opcode(0xE2).calls(loop_rel).with_gas(Low)
+            .with_arg(ArgSource::JumpRel, Fixed(Byte))
+            .is_unpredictable()
+            .into_table(&mut ops);
         //0xE9 JMP  relW
-        define_opcode(0xE9).calls(jmp_rel).with_gas(Low)
+        define_opcode(0xE9).calls(jmp_rel).is_kind(OpcodeKind::JmpRel).with_gas(Low)
             .with_arg(ArgSource::JumpRel, NativeWord)
-            .is_unpredictable()
+            .is_jump_with_gas()
             .into_table(&mut ops);
         //0x70-0x7F Jcc rel8
-        define_opcode_multi(0x70, 16).calls(jcc).with_gas(Low)
+        define_opcode_multi(0x70, 16).calls(jcc).is_kind(OpcodeKind::Jcc).with_gas(Low)
             .with_arg(ArgSource::JumpRel, Fixed(Byte))
             .is_unpredictable()
             .into_table(&mut ops);
         //0x80-0x8F Jcc relw
-        define_opcode_multi(0x80, 16).is_two_byte_op().calls(jcc).with_gas(Low)
+        define_opcode_multi(0x80, 16).is_two_byte_op().calls(jcc).is_kind(OpcodeKind::Jcc).with_gas(Low)
             .with_arg(ArgSource::JumpRel, NativeWord)
             .is_unpredictable()
             .into_table(&mut ops);
@@ -547,12 +733,14 @@ lazy_static! {
         define_opcode(0x18).calls(sbb_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x19 sbb r/m16, r16
         //0x19 sbb r/m32, r32
         define_opcode(0x19).calls(sbb_native_word).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x1A sbb r8, r/m8
         define_opcode(0x1A).calls(sbb_8bit).with_gas(Low)
@@ -580,30 +768,35 @@ lazy_static! {
         define_opcode(0x80).is_group(3).calls(sbb_8bit).with_gas(Low)
             .with_rm8()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x81 sbb r/m16, imm16
         //0x81 sbb r/m32, imm32
         define_opcode(0x81).is_group(3).calls(sbb_native_word).with_gas(Low)
             .with_rmw()
             .with_immw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x83 sbb r/m16, imm8
         //0x83 sbb r/m32, imm8
         define_opcode(0x83).is_group(3).calls(sbb_native_word).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
             //adc opcodes
         //0x10 adc r/m8, r8
         define_opcode(0x10).calls(adc_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x11 adc r/m16, r16
         //0x11 adc r/m32, r32
         define_opcode(0x11).calls(adc_native_word).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x12 adc r8, r/m8
         define_opcode(0x12).calls(adc_8bit).with_gas(Low)
@@ -631,30 +824,35 @@ lazy_static! {
         define_opcode(0x80).is_group(2).calls(adc_8bit).with_gas(Low)
             .with_rm8()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x81 adc r/m16, imm16
         //0x81 adc r/m32, imm32
         define_opcode(0x81).is_group(2).calls(adc_native_word).with_gas(Low)
             .with_rmw()
             .with_immw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x83 adc r/m16, imm8
         //0x83 adc r/m32, imm8
         define_opcode(0x83).is_group(2).calls(adc_native_word).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //add opcodes
         //0x00 add r/m8, r8
         define_opcode(0x00).calls(add_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x01 add r/m16, r16
         //0x01 add r/m32, r32
-        define_opcode(0x01).calls(add_native_word).with_gas(Low)
+        define_opcode(0x01).calls(add_native_word).is_kind(OpcodeKind::Add).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x02 add r8, r/m8
         define_opcode(0x02).calls(add_8bit).with_gas(Low)
@@ -663,7 +861,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x03 add r16. r/m16
         //0x03 add r32, r/m32
-        define_opcode(0x03).calls(add_native_word).with_gas(Low)
+        define_opcode(0x03).calls(add_native_word).is_kind(OpcodeKind::Add).with_gas(Low)
            .with_rm_regw()
            .with_rmw()
            .into_table(&mut ops);
@@ -674,7 +872,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x05 add AX, imm16
         //0x05 add EAX, imm32
-        define_opcode(0x05).calls(add_native_word).with_gas(Low)
+        define_opcode(0x05).calls(add_native_word).is_kind(OpcodeKind::Add).with_gas(Low)
             .with_arg(HardcodedRegister(Reg32::EAX as u8), NativeWord) //Reg32::EAX resolves to the same as Reg16:AX
             .with_immw()
             .into_table(&mut ops);
@@ -682,30 +880,35 @@ lazy_static! {
         define_opcode(0x80).is_group(0).calls(add_8bit).with_gas(Low)
             .with_rm8()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x81 add r/m16, imm16
         //0x81 add r/m32, imm32
-        define_opcode(0x81).is_group(0).calls(add_native_word).with_gas(Low)
+        define_opcode(0x81).is_group(0).calls(add_native_word).is_kind(OpcodeKind::Add).with_gas(Low)
             .with_rmw()
             .with_immw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x83 add r/m16, imm8
         //0x83 add r/m32, imm8
-        define_opcode(0x83).is_group(0).calls(add_native_word).with_gas(Low)
+        define_opcode(0x83).is_group(0).calls(add_native_word).is_kind(OpcodeKind::Add).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //sub opcodes
         //0x28 sub r/m8, r8
         define_opcode(0x28).calls(sub_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x29 sub r/m16, r16
         //0x29 sub r/m32, r32
-        define_opcode(0x29).calls(sub_native_word).with_gas(Low)
+        define_opcode(0x29).calls(sub_native_word).is_kind(OpcodeKind::Sub).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x2A sub r8, r/m8
         define_opcode(0x2A).calls(sub_8bit).with_gas(Low)
@@ -714,7 +917,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x2B sub r16, r/m16
         //0x2B sub r32, r/m32
-        define_opcode(0x2B).calls(sub_native_word).with_gas(Low)
+        define_opcode(0x2B).calls(sub_native_word).is_kind(OpcodeKind::Sub).with_gas(Low)
             .with_rm_regw()
             .with_rmw()
             .into_table(&mut ops);
@@ -725,7 +928,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x2D sub AX, imm16
         //0x2D sub EAX, imm32
-        define_opcode(0x2D).calls(sub_native_word).with_gas(Low)
+        define_opcode(0x2D).calls(sub_native_word).is_kind(OpcodeKind::Sub).with_gas(Low)
             .with_arg(HardcodedRegister(Reg32::EAX as u8), NativeWord) //Reg32::EAX resolves to the same as Reg16:AX
             .with_immw()
             .into_table(&mut ops);
@@ -733,18 +936,21 @@ lazy_static! {
         define_opcode(0x80).is_group(5).calls(sub_8bit).with_gas(Low)
             .with_rm8()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x81 sub r/m16, imm16
         //0x81 sub r/m32, imm32
-        define_opcode(0x81).is_group(5).calls(sub_native_word).with_gas(Low)
+        define_opcode(0x81).is_group(5).calls(sub_native_word).is_kind(OpcodeKind::Sub).with_gas(Low)
             .with_rmw()
             .with_immw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x83 sub r/m16, imm8
         //0x83 sub r/m32, imm8
-        define_opcode(0x83).is_group(5).calls(sub_native_word).with_gas(Low)
+        define_opcode(0x83).is_group(5).calls(sub_native_word).is_kind(OpcodeKind::Sub).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0xC0 shl r/m8, imm8
         define_opcode(0xC0).is_group(4).calls(shl_8bit).with_gas(Low)
@@ -811,6 +1017,7 @@ lazy_static! {
         define_opcode(0x86).calls(xchg).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x87 xchg r/m16, r16
         //0x87 xchg r16, r/m16
@@ -819,6 +1026,7 @@ lazy_static! {
         define_opcode(0x87).calls(xchg).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x90 xchg ax, r16
         //0x90 xchg r16, ax
@@ -834,6 +1042,34 @@ lazy_static! {
             .with_rmw()
             .with_arg(HardcodedRegister(Reg8::CL as u8), Fixed(Byte))
             .into_table(&mut ops);
+        //0x0F A4 shld r/m16, r16, imm8
+        //0x0F A4 shld r/m32, r32, imm8
+        define_opcode(0xA4).is_two_byte_op().calls(shld_native_word).with_gas(Low)
+            .with_rmw()
+            .with_rm_regw()
+            .with_imm8()
+            .into_table(&mut ops);
+        //0x0F A5 shld r/m16, r16, CL
+        //0x0F A5 shld r/m32, r32, CL
+        define_opcode(0xA5).is_two_byte_op().calls(shld_native_word).with_gas(Low)
+            .with_rmw()
+            .with_rm_regw()
+            .with_arg(HardcodedRegister(Reg8::CL as u8), Fixed(Byte))
+            .into_table(&mut ops);
+        //0x0F AC shrd r/m16, r16, imm8
+        //0x0F AC shrd r/m32, r32, imm8
+        define_opcode(0xAC).is_two_byte_op().calls(shrd_native_word).with_gas(Low)
+            .with_rmw()
+            .with_rm_regw()
+            .with_imm8()
+            .into_table(&mut ops);
+        //0x0F AD shrd r/m16, r16, CL
+        //0x0F AD shrd r/m32, r32, CL
+        define_opcode(0xAD).is_two_byte_op().calls(shrd_native_word).with_gas(Low)
+            .with_rmw()
+            .with_rm_regw()
+            .with_arg(HardcodedRegister(Reg8::CL as u8), Fixed(Byte))
+            .into_table(&mut ops);
         //0xF6 mul r/m8
         define_opcode(0xF6).is_group(4).calls(mul_8bit).with_gas(Low)
             .with_rm8()
@@ -898,65 +1134,68 @@ lazy_static! {
         //0x0F C7 CMPXCHG8B  
         define_opcode(0xC7).is_two_byte_op().calls(cmpxchg8b).with_gas(Low)
             .with_arg(ArgSource::ModRM, OpcodeValueSize::Fixed(ValueSize::Dword))
+            .is_lockable()
             .into_table(&mut ops);
         //0x0F B0 CMPXCHG r/m8, r8
         define_opcode(0xB0).is_two_byte_op().calls(cmpxchg_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x0F B1 CMPXCHG r/m16, r16
         //0x0F B1 CMPXCHG r/m32, r32
         define_opcode(0xB1).is_two_byte_op().calls(cmpxchg_native_word).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x38 cmp r/m8, r8
-        define_opcode(0x38).calls(cmp_8bit).with_gas(Low)
+        define_opcode(0x38).calls(cmp_8bit).is_kind(OpcodeKind::Cmp8).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
             .into_table(&mut ops);
         //0x39 cmp, r/m16, r16
         //0x39 cmp, r/m32, r32
-        define_opcode(0x39).calls(cmp_native_word).with_gas(Low)
+        define_opcode(0x39).calls(cmp_native_word).is_kind(OpcodeKind::Cmp).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
             .into_table(&mut ops);
         //0x3A cmp r8, r/m8
-        define_opcode(0x3A).calls(cmp_8bit).with_gas(Low)
+        define_opcode(0x3A).calls(cmp_8bit).is_kind(OpcodeKind::Cmp8).with_gas(Low)
             .with_rm_reg8()
             .with_rm8()
             .into_table(&mut ops);
         //0x3B cmp r16, r/m16
         //0x3B cmp r32, r/m32
-        define_opcode(0x3B).calls(cmp_native_word).with_gas(Low)
+        define_opcode(0x3B).calls(cmp_native_word).is_kind(OpcodeKind::Cmp).with_gas(Low)
             .with_rm_regw()
             .with_rmw()
             .into_table(&mut ops);
         //0x3C cmp AL, imm8
-        define_opcode(0x3C).calls(cmp_8bit).with_gas(Low)
+        define_opcode(0x3C).calls(cmp_8bit).is_kind(OpcodeKind::Cmp8).with_gas(Low)
             .with_arg(HardcodedRegister(Reg8::AL as u8), Fixed(Byte))
             .with_imm8()
             .into_table(&mut ops);        
         //0x3D cmp AX, imm16
         //0x3D cmp EAX, imm32
-        define_opcode(0x3D).calls(cmp_native_word).with_gas(Low)
+        define_opcode(0x3D).calls(cmp_native_word).is_kind(OpcodeKind::Cmp).with_gas(Low)
             .with_arg(HardcodedRegister(Reg32::EAX as u8), NativeWord) //Reg32::EAX resolves to the same as Reg16:AX
             .with_immw()
             .into_table(&mut ops);
         //0x80 cmp r/m8, imm8
-        define_opcode(0x80).is_group(7).calls(cmp_8bit).with_gas(Low)
+        define_opcode(0x80).is_group(7).calls(cmp_8bit).is_kind(OpcodeKind::Cmp8).with_gas(Low)
             .with_rm8()
             .with_imm8()
             .into_table(&mut ops);
         //0x81 cmp r/m16, imm16
         //0x81 cmp r/m32, imm32
-        define_opcode(0x81).is_group(7).calls(cmp_native_word).with_gas(Low)
+        define_opcode(0x81).is_group(7).calls(cmp_native_word).is_kind(OpcodeKind::Cmp).with_gas(Low)
             .with_rmw()
             .with_immw()
             .into_table(&mut ops);
         //0x83 cmp r/m16, imm8
         //0x83 cmp r/m32, imm8
-        define_opcode(0x83).is_group(7).calls(cmp_native_word).with_gas(Low)
+        define_opcode(0x83).is_group(7).calls(cmp_native_word).is_kind(OpcodeKind::Cmp).with_gas(Low)
             .with_rmw()
             .with_imm8()
             .into_table(&mut ops);
@@ -973,12 +1212,14 @@ lazy_static! {
         define_opcode(0x20).calls(and_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x21 and r/m16, r16
         //0x21 and r/m32, r32
-        define_opcode(0x21).calls(and_native_word).with_gas(Low)
+        define_opcode(0x21).calls(and_native_word).is_kind(OpcodeKind::And).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x22 and r8, r/m8
         define_opcode(0x22).calls(and_8bit).with_gas(Low)
@@ -987,7 +1228,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x23 and r16, r/m16
         //0x23 and r32, r/m32
-        define_opcode(0x23).calls(and_native_word).with_gas(Low)
+        define_opcode(0x23).calls(and_native_word).is_kind(OpcodeKind::And).with_gas(Low)
             .with_rm_regw()
             .with_rmw()
             .into_table(&mut ops);
@@ -998,7 +1239,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x25 and AX, imm16
         //0x25 and EAX, imm32
-        define_opcode(0x25).calls(and_native_word).with_gas(Low)
+        define_opcode(0x25).calls(and_native_word).is_kind(OpcodeKind::And).with_gas(Low)
             .with_arg(HardcodedRegister(Reg32::EAX as u8), NativeWord) //Reg32::EAX resolves to the same as Reg16:AX
             .with_immw()
             .into_table(&mut ops);
@@ -1006,30 +1247,35 @@ lazy_static! {
         define_opcode(0x80).is_group(4).calls(and_8bit).with_gas(Low)
             .with_rm8()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x81 and r/m16, imm16
         //0x81 and r/m32, imm32
-        define_opcode(0x81).is_group(4).calls(and_native_word).with_gas(Low)
+        define_opcode(0x81).is_group(4).calls(and_native_word).is_kind(OpcodeKind::And).with_gas(Low)
             .with_rmw()
             .with_immw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x83 and r/m16, imm8
         //0x83 and r/m32, imm8
-        define_opcode(0x83).is_group(4).calls(and_native_word).with_gas(Low)
+        define_opcode(0x83).is_group(4).calls(and_native_word).is_kind(OpcodeKind::And).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         // Bitwise OR
         //0x08 or r/m8, r8
         define_opcode(0x08).calls(or_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x09 or r/m16, r16
         //0x09 or r/m32, r32
-        define_opcode(0x09).calls(or_native_word).with_gas(Low)
+        define_opcode(0x09).calls(or_native_word).is_kind(OpcodeKind::Or).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x0A or r8, r/m8
         define_opcode(0x0A).calls(or_8bit).with_gas(Low)
@@ -1038,7 +1284,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x0B or r16, r/m16
         //0x0B or r32, r/m32
-        define_opcode(0x0B).calls(or_native_word).with_gas(Low)
+        define_opcode(0x0B).calls(or_native_word).is_kind(OpcodeKind::Or).with_gas(Low)
             .with_rm_regw()
             .with_rmw()
             .into_table(&mut ops);
@@ -1049,7 +1295,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x0D or AX, imm16
         //0x0D or EAX, imm32
-        define_opcode(0x0D).calls(or_native_word).with_gas(Low)
+        define_opcode(0x0D).calls(or_native_word).is_kind(OpcodeKind::Or).with_gas(Low)
             .with_arg(HardcodedRegister(Reg32::EAX as u8), NativeWord) //Reg32::EAX resolves to the same as Reg16:AX
             .with_immw()
             .into_table(&mut ops);
@@ -1057,30 +1303,35 @@ lazy_static! {
         define_opcode(0x80).is_group(1).calls(or_8bit).with_gas(Low)
             .with_rm8()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x81 or r/m16, imm16
         //0x81 or r/m32, imm32
-        define_opcode(0x81).is_group(1).calls(or_native_word).with_gas(Low)
+        define_opcode(0x81).is_group(1).calls(or_native_word).is_kind(OpcodeKind::Or).with_gas(Low)
             .with_rmw()
             .with_immw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x83 or r/m16, imm8
         //0x83 or r/m32, imm8
-        define_opcode(0x83).is_group(1).calls(or_native_word).with_gas(Low)
+        define_opcode(0x83).is_group(1).calls(or_native_word).is_kind(OpcodeKind::Or).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         // Bitwise XOR
         //0x30 xor r/m8, r8
         define_opcode(0x30).calls(xor_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x31 xor r/m16, r16
         //0x31 xor r/m32, r32
-        define_opcode(0x31).calls(xor_native_word).with_gas(Low)
+        define_opcode(0x31).calls(xor_native_word).is_kind(OpcodeKind::Xor).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x32 xor r8, r/m8
         define_opcode(0x32).calls(xor_8bit).with_gas(Low)
@@ -1089,7 +1340,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x33 xor r16, r/m16
         //0x33 xor r32, r/m32
-        define_opcode(0x33).calls(xor_native_word).with_gas(Low)
+        define_opcode(0x33).calls(xor_native_word).is_kind(OpcodeKind::Xor).with_gas(Low)
             .with_rm_regw()
             .with_rmw()
             .into_table(&mut ops);
@@ -1100,7 +1351,7 @@ lazy_static! {
             .into_table(&mut ops);
         //0x35 xor AX, imm16
         //0x35 xor EAX, imm32
-        define_opcode(0x35).calls(xor_native_word).with_gas(Low)
+        define_opcode(0x35).calls(xor_native_word).is_kind(OpcodeKind::Xor).with_gas(Low)
             .with_arg(HardcodedRegister(Reg32::EAX as u8), NativeWord) //Reg32::EAX resolves to the same as Reg16:AX
             .with_immw()
             .into_table(&mut ops);
@@ -1108,68 +1359,79 @@ lazy_static! {
         define_opcode(0x80).is_group(6).calls(xor_8bit).with_gas(Low)
             .with_rm8()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x81 xor r/m16, imm16
         //0x81 xor r/m32, imm32
-        define_opcode(0x81).is_group(6).calls(xor_native_word).with_gas(Low)
+        define_opcode(0x81).is_group(6).calls(xor_native_word).is_kind(OpcodeKind::Xor).with_gas(Low)
             .with_rmw()
             .with_immw()
+            .is_lockable()
             .into_table(&mut ops);
         //0x83 xor r/m16, imm8
         //0x83 xor r/m32, imm8
-        define_opcode(0x83).is_group(6).calls(xor_native_word).with_gas(Low)
+        define_opcode(0x83).is_group(6).calls(xor_native_word).is_kind(OpcodeKind::Xor).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
        //Bitwise NOT
         //0xF6 not r/m8,
         define_opcode(0xF6).is_group(2).calls(not_8bit).with_gas(Low)
             .with_rm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0xF7 not r/m16
         //0xF7 not r/m32
         define_opcode(0xF7).is_group(2).calls(not_native_word).with_gas(Low)
             .with_rmw()
+            .is_lockable()
             .into_table(&mut ops);
         // Bitwise NEG
         // 0xF6 neg r/m8
         define_opcode(0xF6).is_group(3).calls(neg_8bit).with_gas(Low)
             .with_rm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0xF7 neg r/m16
         //0xF7 neg r/m32
         define_opcode(0xF7).is_group(3).calls(neg_native_word).with_gas(Low)
             .with_rmw()
+            .is_lockable()
             .into_table(&mut ops);
         // decrement
         // 0x48 dec r16
         // 0x48 dec r32
-        define_opcode(0x48).calls(decrement_native_word).with_gas(Low)
+        define_opcode(0x48).calls(decrement_native_word).is_kind(OpcodeKind::Decrement).with_gas(Low)
             .with_suffix_regw()
             .into_table(&mut ops);
         // 0xFE dec r/m8
         define_opcode(0xFE).is_group(1).calls(decrement_8bit).with_gas(Low)
             .with_rm8()
+            .is_lockable()
             .into_table(&mut ops);
         // 0xFF dec r/m16
         // 0xFF dec r/m32
-        define_opcode(0xFF).is_group(1).calls(decrement_native_word).with_gas(Low)
+        define_opcode(0xFF).is_group(1).calls(decrement_native_word).is_kind(OpcodeKind::Decrement).with_gas(Low)
             .with_rmw()
+            .is_lockable()
             .into_table(&mut ops);
         // increment
         // 0x40 inc r16
         // 0x40 inc r32
-        define_opcode(0x40).calls(increment_native_word).with_gas(Low)
+        define_opcode(0x40).calls(increment_native_word).is_kind(OpcodeKind::Increment).with_gas(Low)
             .with_suffix_regw()
             .into_table(&mut ops);
         // 0xFE inc r/m8
         define_opcode(0xFE).is_group(0).calls(increment_8bit).with_gas(Low)
             .with_rm8()
+            .is_lockable()
             .into_table(&mut ops);
         // 0xFF inc r/m16
         // 0xFF inc r/m32
-        define_opcode(0xFF).is_group(0).calls(increment_native_word).with_gas(Low)
+        define_opcode(0xFF).is_group(0).calls(increment_native_word).is_kind(OpcodeKind::Increment).with_gas(Low)
             .with_rmw()
+            .is_lockable()
             .into_table(&mut ops);
         // 0x9E SAHF 
         define_opcode(0x9E).calls(sahf).with_gas(Low)
@@ -1375,12 +1637,16 @@ lazy_static! {
             .with_imm8()
             .into_table(&mut ops);
         // 0xCD int imm8
-        define_opcode(0xCD).calls(interrupt).with_gas(Moderate)
+        define_opcode(0xCD).calls(interrupt).is_kind(OpcodeKind::Interrupt).with_gas(Moderate)
             .with_imm8()
             .is_unpredictable()
             .into_table(&mut ops);
+        // 0x0F 0B ud2
+        define_opcode(0x0B).is_two_byte_op().calls(trap).is_kind(OpcodeKind::Trap).with_gas(Moderate)
+            .is_unpredictable_no_gas()
+            .into_table(&mut ops);
         // 0xCC int3
-        define_opcode(0xCC).calls(interrupt).with_gas(Moderate)
+        define_opcode(0xCC).calls(interrupt).is_kind(OpcodeKind::Interrupt).with_gas(Moderate)
             .with_arg(ArgSource::Literal(SizedValue::Byte(3)), OpcodeValueSize::Fixed(ValueSize::Byte))
             .is_unpredictable()
             .into_table(&mut ops);
@@ -1395,39 +1661,40 @@ lazy_static! {
             .with_rmw()
             .into_table(&mut ops); 
         //0x84 TEST rm8, r8
-        define_opcode(0x84).calls(test_8bit).with_gas(Low)
+        define_opcode(0x84).calls(test_8bit).is_kind(OpcodeKind::Test8).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
             .into_table(&mut ops);
         //0x85 TEST rmW, rW
-        define_opcode(0x85).calls(test_native_word).with_gas(Low)
+        define_opcode(0x85).calls(test_native_word).is_kind(OpcodeKind::Test).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
             .into_table(&mut ops);
         //0xA8 TEST AL, imm8
-        define_opcode(0xA8).calls(test_8bit).with_gas(Low)
+        define_opcode(0xA8).calls(test_8bit).is_kind(OpcodeKind::Test8).with_gas(Low)
             .with_arg(ArgSource::HardcodedRegister(Reg8::AL as u8), OpcodeValueSize::Fixed(Byte))
             .with_imm8()
             .into_table(&mut ops);
         //0xA9 TEST EAX/AX, immW
-        define_opcode(0xA9).calls(test_native_word).with_gas(Low)
+        define_opcode(0xA9).calls(test_native_word).is_kind(OpcodeKind::Test).with_gas(Low)
             .with_arg(ArgSource::HardcodedRegister(Reg32::EAX as u8), OpcodeValueSize::NativeWord)
             .with_immw()
             .into_table(&mut ops);
         //0xF6 /0 TEST rm8, imm8
-        define_opcode(0xF6).is_group(0).calls(test_8bit).with_gas(Low)
+        define_opcode(0xF6).is_group(0).calls(test_8bit).is_kind(OpcodeKind::Test8).with_gas(Low)
             .with_rm8()
             .with_imm8()
             .into_table(&mut ops);
         //0xF7 /0 TEST rmW, immW
-        define_opcode(0xF7).is_group(0).calls(test_native_word).with_gas(Low)
+        define_opcode(0xF7).is_group(0).calls(test_native_word).is_kind(OpcodeKind::Test).with_gas(Low)
             .with_rmw()
             .with_immw()
             .into_table(&mut ops);
         //0x8D /r LEA  rW,m
-        define_opcode(0x8D).calls(lea).with_gas(Low)
+        define_opcode(0x8D).calls(lea).is_kind(OpcodeKind::Lea).with_gas(Low)
             .with_rm_regw()
             .with_rmw()
+            .allows_address_override()
             .into_table(&mut ops);
         //0x9C PUSHF
         define_opcode(0x9C).calls(pushf).with_gas(Low)
@@ -1452,36 +1719,42 @@ lazy_static! {
         define_opcode(0xAB).is_two_byte_op().calls(bit_test_set).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0xBA BTS r/m16, imm8
         //0xBA BTS r/m32, imm8
         define_opcode(0xBA).is_group(5).is_two_byte_op().calls(bit_test_set).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x0F B3 BTR r/m16, r16
         //0x0F B3 BTR r/m32, r32
         define_opcode(0xB3).is_two_byte_op().calls(bit_test_reset).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0xBA BTR r/m16, imm8
         //0xBA BTR r/m32, imm8
         define_opcode(0xBA).is_group(6).is_two_byte_op().calls(bit_test_reset).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x0F BB BTC r/m16, r16
         //0x0F BB BTC r/m32, r32
         define_opcode(0xBB).is_two_byte_op().calls(bit_test_complement).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0xBA BTC r/m16, imm8
         //0xBA BTC r/m32, imm8
         define_opcode(0xBA).is_group(7).is_two_byte_op().calls(bit_test_complement).with_gas(Low)
             .with_rmw()
             .with_imm8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x98 CWDE
         //0x98 CBW
@@ -1537,11 +1810,13 @@ lazy_static! {
         define_opcode(0xC0).is_two_byte_op().calls(xadd_8bit).with_gas(Low)
             .with_rm8()
             .with_rm_reg8()
+            .is_lockable()
             .into_table(&mut ops);
         //0x0F C1 XADD r/m32, r32
         define_opcode(0xC1).is_two_byte_op().calls(xadd_native_word).with_gas(Low)
             .with_rmw()
             .with_rm_regw()
+            .is_lockable()
             .into_table(&mut ops);
         //0xFD STD
         define_opcode(0xFD).calls(set_direction).with_gas(VeryLow)
@@ -1581,7 +1856,352 @@ lazy_static! {
             .with_imm16()
             .with_imm8()
             .into_table(&mut ops);
-        ops
+        #[cfg(feature = "x87")]
+        define_x87_opcodes(&mut ops);
+        #[cfg(feature = "sse2")]
+        define_sse2_opcodes(&mut ops);
+        #[cfg(feature = "bitops")]
+        define_bitops_opcodes(&mut ops);
+        Arc::from(into_boxed_table(ops))
     };
 }
 
+/// Converts a Vec of opcodes into a boxed opcode table.
+/// The opcode table is too large to safely place on the stack, so it is always constructed on the heap
+fn into_boxed_table(ops: Vec<OpcodeProperties>) -> Box<[OpcodeProperties; OPCODE_TABLE_SIZE]>{
+    use std::convert::TryInto;
+    match ops.into_boxed_slice().try_into(){
+        Ok(table) => table,
+        Err(_) => panic!("Incorrect opcode table size")
+    }
+}
+
+/// Creates a copy of the master OPCODES table which can be modified to add custom opcodes
+pub fn copy_opcode_table() -> Box<[OpcodeProperties; OPCODE_TABLE_SIZE]>{
+    into_boxed_table(OPCODES.to_vec())
+}
+
+/// Adds the x87 FPU opcodes to the specified opcode table.
+/// The register forms of these opcodes (ie, ST(i) operands) are decoded as a Mod R/M register argument,
+/// which the logic functions interpret as the index of the FPU stack register
+#[cfg(feature = "x87")]
+fn define_x87_opcodes(ops: &mut [OpcodeProperties]){
+    use crate::x87::*;
+    use OpcodeValueSize::*;
+    use ValueSize::*;
+    use ArgSource::*;
+    use GasCost::*;
+    //0x9B fwait
+    define_opcode(0x9B).calls(nop).with_gas(GasCost::None).into_table(ops);
+    //0xD8 /0 fadd m32 / fadd st(0), st(i)
+    define_opcode(0xD8).is_group(0).calls(fadd).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xD8 /1 fmul m32 / fmul st(0), st(i)
+    define_opcode(0xD8).is_group(1).calls(fmul).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xD8 /4 fsub m32 / fsub st(0), st(i)
+    define_opcode(0xD8).is_group(4).calls(fsub).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xD8 /5 fsubr m32 / fsubr st(0), st(i)
+    define_opcode(0xD8).is_group(5).calls(fsubr).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xD8 /6 fdiv m32 / fdiv st(0), st(i)
+    define_opcode(0xD8).is_group(6).calls(fdiv).with_gas(FloatHigh)
+        .with_rm32()
+        .into_table(ops);
+    //0xD8 /7 fdivr m32 / fdivr st(0), st(i)
+    define_opcode(0xD8).is_group(7).calls(fdivr).with_gas(FloatHigh)
+        .with_rm32()
+        .into_table(ops);
+    //0xD9 /0 fld m32 / fld st(i)
+    define_opcode(0xD9).is_group(0).calls(fld).with_gas(FloatLow)
+        .with_rm32()
+        .into_table(ops);
+    //0xD9 /1 fxch st(i)
+    define_opcode(0xD9).is_group(1).calls(fxch).with_gas(FloatLow)
+        .with_rm32()
+        .into_table(ops);
+    //0xD9 /2 fst m32
+    define_opcode(0xD9).is_group(2).calls(fst).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xD9 /3 fstp m32
+    define_opcode(0xD9).is_group(3).calls(fstp).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xD9 /4 fchs (0xD9 E0) / fabs (0xD9 E1)
+    define_opcode(0xD9).is_group(4).calls(fchs_fabs).with_gas(FloatLow)
+        .with_rm32()
+        .into_table(ops);
+    //0xD9 /5 fldcw m16 / fld1 (0xD9 E8) / fldz (0xD9 EE)
+    define_opcode(0xD9).is_group(5).calls(fldcw).with_gas(FloatLow)
+        .with_rm16()
+        .into_table(ops);
+    //0xD9 /7 fnstcw m16
+    define_opcode(0xD9).is_group(7).calls(fnstcw).with_gas(FloatLow)
+        .with_rm16()
+        .into_table(ops);
+    //0xDB /0 fild m32
+    define_opcode(0xDB).is_group(0).calls(fild).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDB /2 fist m32
+    define_opcode(0xDB).is_group(2).calls(fist).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDB /3 fistp m32
+    define_opcode(0xDB).is_group(3).calls(fistp).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDB /4 fnclex (0xDB E2) / fninit (0xDB E3)
+    define_opcode(0xDB).is_group(4).calls(fninit).with_gas(FloatLow)
+        .with_rm32()
+        .into_table(ops);
+    //0xDB /5 fucomi st(0), st(i)
+    define_opcode(0xDB).is_group(5).calls(fucomi).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDB /6 fcomi st(0), st(i)
+    define_opcode(0xDB).is_group(6).calls(fcomi).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDC /0 fadd m64 / fadd st(i), st(0)
+    define_opcode(0xDC).is_group(0).calls(fadd).with_gas(FloatModerate)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDC /1 fmul m64 / fmul st(i), st(0)
+    define_opcode(0xDC).is_group(1).calls(fmul).with_gas(FloatModerate)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDC /4 fsub m64 / fsubr st(i), st(0)
+    define_opcode(0xDC).is_group(4).calls(fsub).with_gas(FloatModerate)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDC /5 fsubr m64 / fsub st(i), st(0)
+    define_opcode(0xDC).is_group(5).calls(fsubr).with_gas(FloatModerate)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDC /6 fdiv m64 / fdivr st(i), st(0)
+    define_opcode(0xDC).is_group(6).calls(fdiv).with_gas(FloatHigh)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDC /7 fdivr m64 / fdiv st(i), st(0)
+    define_opcode(0xDC).is_group(7).calls(fdivr).with_gas(FloatHigh)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDD /0 fld m64
+    define_opcode(0xDD).is_group(0).calls(fld).with_gas(FloatLow)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDD /2 fst m64 / fst st(i)
+    define_opcode(0xDD).is_group(2).calls(fst).with_gas(FloatLow)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDD /3 fstp m64 / fstp st(i)
+    define_opcode(0xDD).is_group(3).calls(fstp).with_gas(FloatLow)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDE /0 faddp st(i), st(0)
+    define_opcode(0xDE).is_group(0).calls(fadd).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDE /1 fmulp st(i), st(0)
+    define_opcode(0xDE).is_group(1).calls(fmul).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDE /4 fsubrp st(i), st(0)
+    define_opcode(0xDE).is_group(4).calls(fsub).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDE /5 fsubp st(i), st(0)
+    define_opcode(0xDE).is_group(5).calls(fsubr).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDE /6 fdivrp st(i), st(0)
+    define_opcode(0xDE).is_group(6).calls(fdiv).with_gas(FloatHigh)
+        .with_rm32()
+        .into_table(ops);
+    //0xDE /7 fdivp st(i), st(0)
+    define_opcode(0xDE).is_group(7).calls(fdivr).with_gas(FloatHigh)
+        .with_rm32()
+        .into_table(ops);
+    //0xDF /0 fild m16
+    define_opcode(0xDF).is_group(0).calls(fild).with_gas(FloatModerate)
+        .with_rm16()
+        .into_table(ops);
+    //0xDF /2 fist m16
+    define_opcode(0xDF).is_group(2).calls(fist).with_gas(FloatModerate)
+        .with_rm16()
+        .into_table(ops);
+    //0xDF /3 fistp m16
+    define_opcode(0xDF).is_group(3).calls(fistp).with_gas(FloatModerate)
+        .with_rm16()
+        .into_table(ops);
+    //0xDF /5 fucomip st(0), st(i) / fild m64
+    define_opcode(0xDF).is_group(5).calls(fucomi).with_gas(FloatModerate)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xDF /6 fcomip st(0), st(i)
+    define_opcode(0xDF).is_group(6).calls(fcomi).with_gas(FloatModerate)
+        .with_rm32()
+        .into_table(ops);
+    //0xDF /7 fistp m64
+    define_opcode(0xDF).is_group(7).calls(fistp).with_gas(FloatModerate)
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+}
+
+/// Adds the integer SSE2 subset opcodes to the specified opcode table.
+/// Note the 0x66 prefixed forms of the SSE opcodes (ie, movupd and xorpd) need no definition,
+/// since they fall back to the unprefixed opcode which is bitwise identical
+#[cfg(feature = "sse2")]
+fn define_sse2_opcodes(ops: &mut [OpcodeProperties]){
+    use crate::ops::mov;
+    use crate::sse::*;
+    use OpcodeValueSize::*;
+    use ValueSize::*;
+    use ArgSource::*;
+    use GasCost::*;
+    //0x0F 10 movups xmm, xmm/m128
+    define_opcode(0x10).is_two_byte_op().calls(mov).is_kind(OpcodeKind::Mov).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x0F 11 movups xmm/m128, xmm
+    define_opcode(0x11).is_two_byte_op().calls(mov).is_kind(OpcodeKind::Mov).with_gas(Low)
+        .with_arg(ModRM, Fixed(Dqword))
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .into_table(ops);
+    //0x0F 28 movaps xmm, xmm/m128
+    define_opcode(0x28).is_two_byte_op().calls(mov).is_kind(OpcodeKind::Mov).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x0F 29 movaps xmm/m128, xmm
+    define_opcode(0x29).is_two_byte_op().calls(mov).is_kind(OpcodeKind::Mov).with_gas(Low)
+        .with_arg(ModRM, Fixed(Dqword))
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .into_table(ops);
+    //0x0F 54 andps xmm, xmm/m128
+    define_opcode(0x54).is_two_byte_op().calls(pand).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x0F 55 andnps xmm, xmm/m128
+    define_opcode(0x55).is_two_byte_op().calls(pandn).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x0F 56 orps xmm, xmm/m128
+    define_opcode(0x56).is_two_byte_op().calls(por).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x0F 57 xorps xmm, xmm/m128
+    define_opcode(0x57).is_two_byte_op().calls(pxor).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x66 0F 6E movd xmm, r/m32
+    define_opcode(0x6E).with_mandatory_prefix(0x66).calls(movd_to_xmm).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_rm32()
+        .into_table(ops);
+    //0x66 0F 6F movdqa xmm, xmm/m128
+    define_opcode(0x6F).with_mandatory_prefix(0x66).calls(mov).is_kind(OpcodeKind::Mov).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x66 0F 70 pshufd xmm, xmm/m128, imm8
+    define_opcode(0x70).with_mandatory_prefix(0x66).calls(pshufd).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .with_imm8()
+        .into_table(ops);
+    //0x66 0F 7E movd r/m32, xmm
+    define_opcode(0x7E).with_mandatory_prefix(0x66).calls(movd_from_xmm).with_gas(Low)
+        .with_rm32()
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .into_table(ops);
+    //0x66 0F 7F movdqa xmm/m128, xmm
+    define_opcode(0x7F).with_mandatory_prefix(0x66).calls(mov).is_kind(OpcodeKind::Mov).with_gas(Low)
+        .with_arg(ModRM, Fixed(Dqword))
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .into_table(ops);
+    //0x66 0F D6 movq xmm/m64, xmm
+    define_opcode(0xD6).with_mandatory_prefix(0x66).calls(movq_from_xmm).with_gas(Low)
+        .with_arg(ModRM, Fixed(Qword))
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .into_table(ops);
+    //0x66 0F DB pand xmm, xmm/m128
+    define_opcode(0xDB).with_mandatory_prefix(0x66).calls(pand).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x66 0F DF pandn xmm, xmm/m128
+    define_opcode(0xDF).with_mandatory_prefix(0x66).calls(pandn).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x66 0F EB por xmm, xmm/m128
+    define_opcode(0xEB).with_mandatory_prefix(0x66).calls(por).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0x66 0F EF pxor xmm, xmm/m128
+    define_opcode(0xEF).with_mandatory_prefix(0x66).calls(pxor).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0xF3 0F 6F movdqu xmm, xmm/m128
+    define_opcode(0x6F).with_mandatory_prefix(0xF3).calls(mov).is_kind(OpcodeKind::Mov).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Dqword))
+        .into_table(ops);
+    //0xF3 0F 7E movq xmm, xmm/m64
+    define_opcode(0x7E).with_mandatory_prefix(0xF3).calls(movq_to_xmm).with_gas(Low)
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .with_arg(ModRM, Fixed(Qword))
+        .into_table(ops);
+    //0xF3 0F 7F movdqu xmm/m128, xmm
+    define_opcode(0x7F).with_mandatory_prefix(0xF3).calls(mov).is_kind(OpcodeKind::Mov).with_gas(Low)
+        .with_arg(ModRM, Fixed(Dqword))
+        .with_arg(ModRMReg, Fixed(Dqword))
+        .into_table(ops);
+}
+
+/// Adds the POPCNT, LZCNT, TZCNT and MOVBE opcodes to the specified opcode table
+#[cfg(feature = "bitops")]
+fn define_bitops_opcodes(ops: &mut [OpcodeProperties]){
+    use crate::ops::*;
+    use GasCost::*;
+    //0xF3 0F B8 popcnt rW, rmW
+    define_opcode(0xB8).with_mandatory_prefix(0xF3).calls(popcnt).with_gas(Low)
+        .with_rm_regw()
+        .with_rmw()
+        .into_table(ops);
+    //0xF3 0F BC tzcnt rW, rmW
+    define_opcode(0xBC).with_mandatory_prefix(0xF3).calls(tzcnt).with_gas(Low)
+        .with_rm_regw()
+        .with_rmw()
+        .into_table(ops);
+    //0xF3 0F BD lzcnt rW, rmW
+    define_opcode(0xBD).with_mandatory_prefix(0xF3).calls(lzcnt).with_gas(Low)
+        .with_rm_regw()
+        .with_rmw()
+        .into_table(ops);
+    //0x0F 38 F0 movbe rW, mW
+    define_opcode(0xF0).is_three_byte_op().calls(movbe).with_gas(Low)
+        .with_rm_regw()
+        .with_rmw()
+        .into_table(ops);
+    //0x0F 38 F1 movbe mW, rW
+    define_opcode(0xF1).is_three_byte_op().calls(movbe).with_gas(Low)
+        .with_rmw()
+        .with_rm_regw()
+        .into_table(ops);
+}
@@ -1,6 +1,7 @@
 use crate::vm::*;
 use crate::pipeline::*;
 use crate::structs::*;
+use crate::opcodes::op_undefined;
 use crate::flags::X86Flags;
 use crate::bitmanip::BitManipulation;
 
@@ -72,38 +73,32 @@ pub fn popa(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Resul
 }
 ///  The logic function for the 'enter' opcode
 pub fn enter(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
-    let locals = vm.get_arg(pipeline.args[0].location)?.u16_exact()?;
-    vm.eip += 2;
+    let alloc_size = vm.get_arg(pipeline.args[0].location)?.u16_exact()?;
     let nesting = vm.get_arg(pipeline.args[1].location)?.u8_exact()? % 32;
-    vm.eip += 1;
+    let (size, width) = if pipeline.size_override{
+        (ValueSize::Word, 2)
+    }else{
+        (ValueSize::Dword, 4)
+    };
     //push ebp
-    let ebp = vm.get_reg(Reg32::EBP as u8, ValueSize::Dword).u32_exact()?;
-    vm.push_stack(SizedValue::Dword(ebp), pipeline)?;
-    //temp . esp
-    let temp = vm.get_reg(Reg32::ESP as u8, ValueSize::Dword);
-    // todo: This portion appears to be erroring but I can't figure out why
-    // should return and fix this in the future
-    // while (nesting > 0)
-    //  nesting . nesting - 1
-    //  eBP . eBP - n
-    // push [SS:eBP]
+    let ebp = vm.regs[Reg32::EBP as usize];
+    vm.push_stack(SizedValue::Dword(ebp).convert_size_trunc(size), pipeline)?;
+    let frame_temp = vm.regs[Reg32::ESP as usize];
     if nesting > 0 {
-        for _ in 1..nesting - 1 {
-            if pipeline.size_override {
-                vm.set_reg(Reg32::EBP as u8, SizedValue::Dword(ebp - 2));
-            } else {
-                vm.set_reg(Reg32::EBP as u8, SizedValue::Dword(ebp - 4));
-            }
-            vm.push_stack(SizedValue::Dword(ebp), pipeline)?;
+        //copy the frame pointers of the enclosing frames (the "display") into the new frame
+        let mut frame = ebp;
+        for _ in 1..nesting {
+            frame = frame.wrapping_sub(width);
+            let v = vm.get_mem(frame, size)?;
+            vm.push_stack(v, pipeline)?;
         }
-        vm.push_stack(temp, pipeline)?;
+        //then push the pointer for the new frame
+        vm.push_stack(SizedValue::Dword(frame_temp).convert_size_trunc(size), pipeline)?;
     }
-    //ebp . temp
-    vm.set_reg(Reg32::EBP as u8, temp);
-    //esp . esp - locals
-    let esp = temp.u32_exact()?;
-    let (result, _) = esp.overflowing_sub(locals as u32);
-    vm.set_reg(Reg32::ESP as u8, SizedValue::Dword(result));
+    //ebp = frame_temp (only bp if 16 bit)
+    vm.set_reg(Reg32::EBP as u8, SizedValue::Dword(frame_temp).convert_size_trunc(size));
+    //esp = esp - alloc_size
+    vm.regs[Reg32::ESP as usize] = vm.regs[Reg32::ESP as usize].wrapping_sub(alloc_size as u32);
     Ok(())
 }
 ///  The logic function for the 'leave' opcode
@@ -374,6 +369,69 @@ pub fn bit_scan_reverse(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervis
     Ok(())
 }
 
+/// The logic function for the `popcnt` opcode
+pub fn popcnt(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    let source = vm.get_arg(pipeline.args[1].location)?.u32_zx()?;
+    let count = source.count_ones();
+    vm.flags.carry = false;
+    vm.flags.parity = false;
+    vm.flags.adjust = false;
+    vm.flags.sign = false;
+    vm.flags.overflow = false;
+    vm.flags.zero = source == 0;
+    if pipeline.size_override {
+        vm.set_arg(pipeline.args[0].location, SizedValue::Word(count as u16))
+    } else {
+        vm.set_arg(pipeline.args[0].location, SizedValue::Dword(count))
+    }
+}
+
+/// The logic function for the `lzcnt` opcode
+/// Unlike `bsr`, a source of 0 is well defined and results in the operand size
+pub fn lzcnt(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    let result = if pipeline.size_override {
+        let source = vm.get_arg(pipeline.args[1].location)?.u16_exact()?;
+        vm.flags.carry = source == 0;
+        SizedValue::Word(source.leading_zeros() as u16)
+    } else {
+        let source = vm.get_arg(pipeline.args[1].location)?.u32_exact()?;
+        vm.flags.carry = source == 0;
+        SizedValue::Dword(source.leading_zeros())
+    };
+    vm.flags.zero = result.u32_zx()? == 0;
+    vm.set_arg(pipeline.args[0].location, result)
+}
+
+/// The logic function for the `tzcnt` opcode
+/// Unlike `bsf`, a source of 0 is well defined and results in the operand size
+pub fn tzcnt(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    let result = if pipeline.size_override {
+        let source = vm.get_arg(pipeline.args[1].location)?.u16_exact()?;
+        vm.flags.carry = source == 0;
+        SizedValue::Word(source.trailing_zeros() as u16)
+    } else {
+        let source = vm.get_arg(pipeline.args[1].location)?.u32_exact()?;
+        vm.flags.carry = source == 0;
+        SizedValue::Dword(source.trailing_zeros())
+    };
+    vm.flags.zero = result.u32_zx()? == 0;
+    vm.set_arg(pipeline.args[0].location, result)
+}
+
+/// The logic function for the `movbe` opcode
+/// Only memory operands are valid for the Mod R/M argument, and the register form is an invalid opcode
+pub fn movbe(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    if !pipeline.args[0].is_memory && !pipeline.args[1].is_memory {
+        return op_undefined(vm, pipeline, hv);
+    }
+    let result = match vm.get_arg(pipeline.args[1].location)? {
+        SizedValue::Word(v) => SizedValue::Word(v.swap_bytes()),
+        SizedValue::Dword(v) => SizedValue::Dword(v.swap_bytes()),
+        _ => return Err(VMError::WrongSizeExpectation)
+    };
+    vm.set_arg(pipeline.args[0].location, result)
+}
+
 pub fn xchg(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let source = vm.get_arg(pipeline.args[0].location)?;
     let destination = vm.get_arg(pipeline.args[1].location)?;
@@ -386,20 +444,20 @@ pub fn ret(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result
     let stack_clear = vm.get_arg(pipeline.args[0].location)?.u16_zx()?;
     if pipeline.size_override{
         let word = vm.pop16()?;
-        vm.eip = (word.u32_zx()? - (pipeline.eip_size as u32)) & 0xFFFF;
+        vm.eip = word.u32_zx()?.wrapping_sub(pipeline.eip_size as u32) & 0xFFFF;
     }else{
         let dword = vm.pop32()?;
-        vm.eip = dword.u32_zx()? - (pipeline.eip_size as u32);
+        vm.eip = dword.u32_zx()?.wrapping_sub(pipeline.eip_size as u32);
     };
     if stack_clear != 0 {
-        vm.regs[Reg32::ESP as usize] += stack_clear as u32;
+        vm.regs[Reg32::ESP as usize] = vm.regs[Reg32::ESP as usize].wrapping_add(stack_clear as u32);
     }
     Ok(())
 }
 
 pub fn call_rel(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let branch_to = vm.get_arg(pipeline.args[0].location)?.u32_zx()?;
-    vm.push_stack(SizedValue::Dword(vm.eip + pipeline.eip_size as u32), pipeline)?;
+    vm.push_stack(SizedValue::Dword(vm.eip.wrapping_add(pipeline.eip_size as u32)), pipeline)?;
     vm.set_arg(pipeline.args[1].location, SizedValue::Dword(branch_to))?;
     jmp_rel(vm, pipeline, _hv)?;
     Ok(())
@@ -407,7 +465,7 @@ pub fn call_rel(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> R
 
 pub fn call_abs(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let branch_to = vm.get_arg(pipeline.args[0].location)?.u32_zx()?;
-    vm.push_stack(SizedValue::Dword(vm.eip + pipeline.eip_size as u32), pipeline)?;
+    vm.push_stack(SizedValue::Dword(vm.eip.wrapping_add(pipeline.eip_size as u32)), pipeline)?;
     vm.set_arg(pipeline.args[1].location, SizedValue::Dword(branch_to))?;
     jmp_abs(vm, pipeline, _hv)?;
     Ok(())
@@ -416,12 +474,12 @@ pub fn call_abs(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> R
 /// The logic function for the `jmp` opcodes with a relative argument
 pub fn jmp_rel(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     //relative jumps are calculated from the EIP value AFTER the jump would've executed, ie, after EIP is advanced by the size of the instruction
-    let future_eip = vm.eip + (pipeline.eip_size as u32);
+    let future_eip = vm.eip.wrapping_add(pipeline.eip_size as u32);
     //rel must be sign extended, but is otherwise treated as a u32 for simplicity
     //an i32 and a u32 will behave the same way for wrapping_addition like this
     let rel = vm.get_arg(pipeline.args[0].location)?.u32_sx()?;
     //subtract out the eip_size that'll be advanced in the cycle() main loop
-    vm.eip = future_eip.wrapping_add(rel) - (pipeline.eip_size as u32);
+    vm.eip = future_eip.wrapping_add(rel).wrapping_sub(pipeline.eip_size as u32);
     if pipeline.size_override{
         vm.eip &= 0xFFFF;
     }
@@ -430,7 +488,7 @@ pub fn jmp_rel(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Re
 /// The logic function for the `jmp` opcodes with an absolute argument
 pub fn jmp_abs(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     //must subtract the size of this opcode to correct for the automatic eip_size advance in the cycle() main loop
-    vm.eip = vm.get_arg(pipeline.args[0].location)?.u32_zx()? - (pipeline.eip_size as u32);
+    vm.eip = vm.get_arg(pipeline.args[0].location)?.u32_zx()?.wrapping_sub(pipeline.eip_size as u32);
     if pipeline.size_override{
         vm.eip &= 0xFFFF;
     }
@@ -444,7 +502,7 @@ pub fn jmp_conditional_ecx_is_zero(vm: &mut VM, pipeline: &Pipeline, _hv: &mut d
     Ok(())
 }
 
-fn cc_matches(opcode: u8, flags: &X86Flags) -> bool{
+pub(crate) fn cc_matches(opcode: u8, flags: &X86Flags) -> bool{
     let cc = 0x0F & opcode;
     match cc{
         0x0 => flags.overflow,
@@ -474,6 +532,87 @@ pub fn jcc(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result
     Ok(())
 }
 
+/// Charges the gas of the second opcode of a fused pair, after the first opcode has executed.
+/// If there is not enough gas, EIP is left pointing to the second opcode exactly as if the opcodes had not been fused
+fn charge_fused(vm: &mut VM, pipeline: &Pipeline) -> Result<(), VMError>{
+    if pipeline.fused_gas_cost > vm.gas_remaining{
+        vm.eip = vm.eip.wrapping_add((pipeline.eip_size - pipeline.fused_eip_size) as u32);
+        return Err(VMError::OutOfGas);
+    }
+    vm.gas_remaining -= pipeline.fused_gas_cost;
+    Ok(())
+}
+
+/// The `jcc` half of a fused pair, with the jump target in the third argument
+fn fused_jcc(vm: &mut VM, pipeline: &Pipeline) -> Result<(), VMError>{
+    charge_fused(vm, pipeline)?;
+    if cc_matches(pipeline.fused_opcode, &vm.flags){
+        //eip_size includes both opcodes, so this is the same as for jmp_rel
+        let future_eip = vm.eip.wrapping_add(pipeline.eip_size as u32);
+        let rel = vm.get_arg(pipeline.args[2].location)?.u32_sx()?;
+        vm.eip = future_eip.wrapping_add(rel).wrapping_sub(pipeline.eip_size as u32);
+    }
+    Ok(())
+}
+
+/// The fused logic function for `cmp rm8, r8` style opcodes followed by `jcc`
+pub fn cmp_8bit_jcc(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    cmp_8bit(vm, pipeline, _hv)?;
+    fused_jcc(vm, pipeline)
+}
+/// The fused logic function for `cmp rmW, rW` style opcodes followed by `jcc`
+pub fn cmp_native_word_jcc(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    cmp_native_word(vm, pipeline, _hv)?;
+    fused_jcc(vm, pipeline)
+}
+/// The fused logic function for `test rm8, r8` style opcodes followed by `jcc`
+pub fn test_8bit_jcc(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    test_8bit(vm, pipeline, _hv)?;
+    fused_jcc(vm, pipeline)
+}
+/// The fused logic function for `test rmW, rW` style opcodes followed by `jcc`
+pub fn test_native_word_jcc(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    test_native_word(vm, pipeline, _hv)?;
+    fused_jcc(vm, pipeline)
+}
+/// The fused logic function for the common function prologue of `push ebp` followed by `mov ebp, esp`
+pub fn push_ebp_mov_ebp_esp(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    push(vm, pipeline, _hv)?;
+    charge_fused(vm, pipeline)?;
+    vm.regs[Reg32::EBP as usize] = vm.regs[Reg32::ESP as usize];
+    Ok(())
+}
+
+/// Decrements ECX (or CX with an operand size override) for the `loop` family of opcodes and returns true if the counter is now non-zero
+fn loop_decrement(vm: &mut VM, pipeline: &Pipeline) -> bool{
+    decrement_regw(vm, Reg32::ECX, pipeline.size_override) != 0
+}
+
+/// The logic function for the `loop` opcode
+pub fn loop_rel(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    if loop_decrement(vm, pipeline){
+        return jmp_rel(vm, pipeline, _hv);
+    }
+    Ok(())
+}
+
+/// The logic function for the `loope`/`loopz` opcode
+pub fn loope_rel(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    //note: ECX must be decremented regardless of the state of ZF
+    if loop_decrement(vm, pipeline) && vm.flags.zero{
+        return jmp_rel(vm, pipeline, _hv);
+    }
+    Ok(())
+}
+
+/// The logic function for the `loopne`/`loopnz` opcode
+pub fn loopne_rel(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    if loop_decrement(vm, pipeline) && !vm.flags.zero{
+        return jmp_rel(vm, pipeline, _hv);
+    }
+    Ok(())
+}
+
 pub fn div_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
     let first_arg = vm.reg16(Reg16::AX) as u16;
     let second_arg = vm.get_arg(pipeline.args[0].location)?.u16_zx()?;
@@ -517,12 +656,14 @@ pub fn div_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 
 pub fn idiv_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
     let first_arg = vm.reg16(Reg16::AX) as i16;
-    let second_arg = vm.get_arg(pipeline.args[0].location)?.u16_zx()? as i16;
-    if second_arg == 0 || ((first_arg / second_arg) > 0xFF) {
-        return Err(VMError::DivideByZero) // divide by 0 not allowed and result being too big for destination not allowed
-    }
-    vm.set_reg(Reg8::AL as u8, SizedValue::Byte((first_arg / second_arg) as u8));
-    vm.set_reg(Reg8::AH as u8, SizedValue::Byte((first_arg%second_arg) as u8));
+    let second_arg = vm.get_arg(pipeline.args[0].location)?.u16_sx()? as i16;
+    // divide by 0 and the quotient not fitting in the destination (including MIN / -1) are both a divide error
+    let (quotient, remainder) = match (first_arg.checked_div(second_arg), first_arg.checked_rem(second_arg)){
+        (Some(q), Some(r)) if q >= i8::MIN as i16 && q <= i8::MAX as i16 => (q, r),
+        _ => return Err(VMError::DivideByZero)
+    };
+    vm.set_reg(Reg8::AL as u8, SizedValue::Byte(quotient as u8));
+    vm.set_reg(Reg8::AH as u8, SizedValue::Byte(remainder as u8));
     Ok(())
 }
 
@@ -535,24 +676,28 @@ pub fn idiv_native_word(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervis
 }
 
 pub fn idiv_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
-    let first_arg = ((vm.reg16(Reg16::DX) as i16 as i32) << 16) | (vm.reg16(Reg16::AX) as u16 as i32);
-    let second_arg = vm.get_arg(pipeline.args[0].location)?.u32_zx()? as i32;
-    if second_arg == 0 || ((first_arg / second_arg) > 0xFFFF) {
-        return Err(VMError::DivideByZero) // divide by 0 not allowed and result being too big for destination not allowed
-    }
-    vm.set_reg(Reg16::AX as u8, SizedValue::Word((first_arg / second_arg) as u16));
-    vm.set_reg(Reg16::DX as u8, SizedValue::Word((first_arg%second_arg) as u16));
+    let first_arg = (((vm.reg16(Reg16::DX) as u32) << 16) | (vm.reg16(Reg16::AX) as u32)) as i32;
+    let second_arg = vm.get_arg(pipeline.args[0].location)?.u32_sx()? as i32;
+    // divide by 0 and the quotient not fitting in the destination (including MIN / -1) are both a divide error
+    let (quotient, remainder) = match (first_arg.checked_div(second_arg), first_arg.checked_rem(second_arg)){
+        (Some(q), Some(r)) if q >= i16::MIN as i32 && q <= i16::MAX as i32 => (q, r),
+        _ => return Err(VMError::DivideByZero)
+    };
+    vm.set_reg(Reg16::AX as u8, SizedValue::Word(quotient as u16));
+    vm.set_reg(Reg16::DX as u8, SizedValue::Word(remainder as u16));
     Ok(())
 }
 
 pub fn idiv_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
-    let first_arg = ((vm.reg32(Reg32::EDX) as i32 as i64) << 32) | (vm.reg32(Reg32::EAX) as i32 as i64);
+    let first_arg = (((vm.reg32(Reg32::EDX) as u64) << 32) | (vm.reg32(Reg32::EAX) as u64)) as i64;
     let second_arg = vm.get_arg(pipeline.args[0].location)?.u32_zx()? as i32 as i64;
-    if second_arg == 0 || ((first_arg / second_arg) > 0xFFFFFFFF) {
-        return Err(VMError::DivideByZero) // divide by 0 not allowed and result being too big for destination not allowed
-    }
-    vm.set_reg(Reg32::EAX as u8, SizedValue::Dword((first_arg / second_arg) as u32));
-    vm.set_reg(Reg32::EDX as u8, SizedValue::Dword((first_arg%second_arg) as u32));
+    // divide by 0 and the quotient not fitting in the destination (including MIN / -1) are both a divide error
+    let (quotient, remainder) = match (first_arg.checked_div(second_arg), first_arg.checked_rem(second_arg)){
+        (Some(q), Some(r)) if q >= i32::MIN as i64 && q <= i32::MAX as i64 => (q, r),
+        _ => return Err(VMError::DivideByZero)
+    };
+    vm.set_reg(Reg32::EAX as u8, SizedValue::Dword(quotient as u32));
+    vm.set_reg(Reg32::EDX as u8, SizedValue::Dword(remainder as u32));
     Ok(())
 }
 
@@ -1003,13 +1148,10 @@ pub fn sar_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> R
     if count == 0 {
         return Ok(());
     }
-    vm.flags.carry = ((destination >> (count - 1)) & 1) > 0;
-    // get MSB
-    if destination.get_bit_big_endian(0.into()) as bool {
-        destination = (destination >> count) | (!(0xFF >> count));
-    } else {
-        destination = destination >> count;
-    }
+    //counts can be larger than 8 bits, so this shifts a sign extended copy to fill in the MSB
+    let extended = destination as i8 as i32;
+    vm.flags.carry = ((extended >> (count - 1)) & 1) > 0;
+    destination = (extended >> count) as u8;
     vm.flags.calculate_parity(destination as u32);
     vm.flags.calculate_sign8(destination);
     vm.flags.calculate_zero(destination as u32);
@@ -1035,13 +1177,10 @@ pub fn sar_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
     if count == 0 {
         return Ok(());
     }
-    vm.flags.carry = ((destination >> (count - 1)) & 1) > 0;
-    // get MSB
-    if destination.get_bit_big_endian(0.into()) as bool {
-        destination = (destination >> count) | (!(0xFFFF >> count));
-    } else {
-        destination = destination >> count;
-    }
+    //counts can be larger than 16 bits, so this shifts a sign extended copy to fill in the MSB
+    let extended = destination as i16 as i32;
+    vm.flags.carry = ((extended >> (count - 1)) & 1) > 0;
+    destination = (extended >> count) as u16;
     vm.flags.calculate_parity(destination as u32);
     vm.flags.calculate_sign16(destination);
     vm.flags.calculate_zero(destination as u32);
@@ -1083,8 +1222,11 @@ pub fn shl_native_word(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hyperviso
 
 pub fn shl_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let destination = vm.get_arg(pipeline.args[0].location)?.u8_exact()?;
-    let count = vm.get_arg(pipeline.args[1].location)?.u8_exact()?;
-    let result= (destination as u16) << count;
+    let count = vm.get_arg(pipeline.args[1].location)?.u8_exact()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
+    let result= (destination as u32) << count;
     if count == 1 {
         vm.flags.overflow = (destination & 0x80) != ((result as u8) & 0x80);
     }
@@ -1098,7 +1240,10 @@ pub fn shl_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> R
 
 pub fn shl_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let destination = vm.get_arg(pipeline.args[0].location)?.u16_exact()?;
-    let count = vm.get_arg(pipeline.args[1].location)?.u16_sx()?;
+    let count = vm.get_arg(pipeline.args[1].location)?.u16_sx()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
     let result= (destination as u32) << count;
     if count == 1 {
         vm.flags.overflow = ((destination as u32) & 0x8000) != (result & 0x8000);
@@ -1113,7 +1258,10 @@ pub fn shl_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 
 pub fn shl_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let destination = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
-    let count = vm.get_arg(pipeline.args[1].location)?.u32_sx()?;
+    let count = vm.get_arg(pipeline.args[1].location)?.u32_sx()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
     let result= (destination as u64) << count;
     if count == 1 {
         vm.flags.overflow = ((destination as u64) & 0x80000000) != (result & 0x80000000);
@@ -1128,9 +1276,12 @@ pub fn shl_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 
 pub fn shr_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let destination = vm.get_arg(pipeline.args[0].location)?.u8_exact()?;
-    let count = vm.get_arg(pipeline.args[1].location)?.u8_exact()?;
-    let result= destination >> count;
-    let computation_result = destination >> (count - 1);
+    let count = vm.get_arg(pipeline.args[1].location)?.u8_exact()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
+    let result= ((destination as u32) >> count) as u8;
+    let computation_result = (destination as u32) >> (count - 1);
     if count == 1 {
         vm.flags.overflow = (destination & 0x80) != (result & 0x80);
     }
@@ -1152,7 +1303,10 @@ pub fn shr_native_word(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hyperviso
 
 pub fn shr_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let destination = vm.get_arg(pipeline.args[0].location)?.u16_exact()?;
-    let count = vm.get_arg(pipeline.args[1].location)?.u16_sx()?;
+    let count = vm.get_arg(pipeline.args[1].location)?.u16_sx()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
     let result= (destination as u32) >> count;
     let computation_result = (destination as u32) >> (count - 1);
     if count == 1 {
@@ -1168,7 +1322,10 @@ pub fn shr_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 
 pub fn shr_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let destination = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
-    let count = vm.get_arg(pipeline.args[1].location)?.u32_sx()?;
+    let count = vm.get_arg(pipeline.args[1].location)?.u32_sx()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
     let result= (destination as u64) >> count;
     let computation_result = destination >> (count - 1);
     if count == 1 {
@@ -1182,6 +1339,103 @@ pub fn shr_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
     Ok(())
 }
 
+pub fn shld_native_word(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    if pipeline.size_override {
+        shld_16bit(vm, pipeline, _hv)
+    } else {
+        shld_32bit(vm, pipeline, _hv)
+    }
+}
+
+pub fn shld_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let destination = vm.get_arg(pipeline.args[0].location)?.u16_exact()?;
+    let source = vm.get_arg(pipeline.args[1].location)?.u16_exact()?;
+    let count = vm.get_arg(pipeline.args[2].location)?.u8_exact()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
+    //counts greater than 16 are undefined on x86, so to keep it deterministic this
+    //shifts through destination:source:destination as a 48 bit value
+    let combined = ((destination as u64) << 32) | ((source as u64) << 16) | (destination as u64);
+    let result = (combined >> (32 - count)) as u16;
+    vm.flags.carry = (combined >> (48 - count)) & 1 != 0;
+    if count == 1 {
+        vm.flags.overflow = (destination & 0x8000) != (result & 0x8000);
+    }
+    vm.flags.calculate_zero(result as u32);
+    vm.flags.calculate_parity(result as u32);
+    vm.flags.calculate_sign16(result);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Word(result))?;
+    Ok(())
+}
+
+pub fn shld_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let destination = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
+    let source = vm.get_arg(pipeline.args[1].location)?.u32_exact()?;
+    let count = vm.get_arg(pipeline.args[2].location)?.u8_exact()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
+    let result = (destination << count) | (source >> (32 - count));
+    vm.flags.carry = (destination >> (32 - count)) & 1 != 0;
+    if count == 1 {
+        vm.flags.overflow = (destination & 0x80000000) != (result & 0x80000000);
+    }
+    vm.flags.calculate_zero(result);
+    vm.flags.calculate_parity(result);
+    vm.flags.calculate_sign32(result);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+
+pub fn shrd_native_word(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
+    if pipeline.size_override {
+        shrd_16bit(vm, pipeline, _hv)
+    } else {
+        shrd_32bit(vm, pipeline, _hv)
+    }
+}
+
+pub fn shrd_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let destination = vm.get_arg(pipeline.args[0].location)?.u16_exact()?;
+    let source = vm.get_arg(pipeline.args[1].location)?.u16_exact()?;
+    let count = vm.get_arg(pipeline.args[2].location)?.u8_exact()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
+    //see shld_16bit for why this is a 48 bit value
+    let combined = ((destination as u64) << 32) | ((source as u64) << 16) | (destination as u64);
+    let result = (combined >> count) as u16;
+    vm.flags.carry = (combined >> (count - 1)) & 1 != 0;
+    if count == 1 {
+        vm.flags.overflow = (destination & 0x8000) != (result & 0x8000);
+    }
+    vm.flags.calculate_zero(result as u32);
+    vm.flags.calculate_parity(result as u32);
+    vm.flags.calculate_sign16(result);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Word(result))?;
+    Ok(())
+}
+
+pub fn shrd_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let destination = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
+    let source = vm.get_arg(pipeline.args[1].location)?.u32_exact()?;
+    let count = vm.get_arg(pipeline.args[2].location)?.u8_exact()? & 0x1F;
+    if count == 0 {
+        return Ok(());
+    }
+    let result = (destination >> count) | (source << (32 - count));
+    vm.flags.carry = (destination >> (count - 1)) & 1 != 0;
+    if count == 1 {
+        vm.flags.overflow = (destination & 0x80000000) != (result & 0x80000000);
+    }
+    vm.flags.calculate_zero(result);
+    vm.flags.calculate_parity(result);
+    vm.flags.calculate_sign32(result);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+
 pub fn aaa(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let mut al = vm.get_reg(Reg8::AL as u8, ValueSize::Byte).u8_exact()?;
     let ah = vm.get_reg(Reg8::AH as u8, ValueSize::Byte).u8_exact()?;
@@ -1397,11 +1651,16 @@ pub fn add_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 pub fn add_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
     let adder = vm.get_arg(pipeline.args[1].location)?.u32_sx()?;
+    let result = add32(vm, base, adder);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+/// The flag calculation and result of a 32 bit `add`, shared with threaded code
+pub(crate) fn add32(vm: &mut VM, base: u32, adder: u32) -> u32{
     let (result, carry) = base.overflowing_add(adder);
     let (_, overflow) = (base as i32).overflowing_add(adder as i32);
     get_flags(vm, result as u32, adder as u32, base as u32, overflow, carry, AdjustType::Inc, SignType::Dword);
-    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
-    Ok(())
+    result
 }
 
 /// The logic function for the `hlt` opcode
@@ -1409,6 +1668,27 @@ pub fn hlt(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Resu
     Err(VMError::InternalVMStop)
 }
 
+/// The logic function for the `cpuid` opcode
+/// The leaf is selected by EAX and the results are loaded from the VM's CPUID table
+pub fn cpuid(vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let leaf = vm.cpuid.get(vm.regs[Reg32::EAX as usize]);
+    vm.regs[Reg32::EAX as usize] = leaf.eax;
+    vm.regs[Reg32::EBX as usize] = leaf.ebx;
+    vm.regs[Reg32::ECX as usize] = leaf.ecx;
+    vm.regs[Reg32::EDX as usize] = leaf.edx;
+    Ok(())
+}
+
+/// The logic function for the `rdtsc` opcode
+/// Rather than a timestamp, this loads EDX:EAX with the amount of gas used since `execute` began.
+/// Gas is deducted for each opcode immediately before it is executed, so this includes the cost of the rdtsc opcode itself
+pub fn rdtsc(vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let used = vm.gas_at_start.wrapping_sub(vm.gas_remaining);
+    vm.regs[Reg32::EAX as usize] = used as u32;
+    vm.regs[Reg32::EDX as usize] = (used >> 32) as u32;
+    Ok(())
+}
+
 pub fn increment_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u8_exact()?;
     let (result, overflow) = (base as i8).overflowing_add(1 as i8);
@@ -1443,14 +1723,19 @@ pub fn increment_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hyperviso
 
 pub fn increment_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
+    let result = increment32(vm, base);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+/// The flag calculation and result of a 32 bit `inc`, shared with threaded code
+pub(crate) fn increment32(vm: &mut VM, base: u32) -> u32{
     let (result, overflow) = (base as i32).overflowing_add(1 as i32);
     vm.flags.overflow = overflow;
     vm.flags.calculate_zero(result as u32);
     vm.flags.calculate_parity(result as u32);
     vm.flags.calculate_sign32(result as u32);
     vm.flags.adjust = (base&0x0F) + (1&0x0F) > 15;
-    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result as u32))?;
-    Ok(())
+    result as u32
 }
 
 pub fn sub_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
@@ -1484,11 +1769,16 @@ pub fn sub_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 pub fn sub_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
     let subt = vm.get_arg(pipeline.args[1].location)?.u32_sx()?;
+    let result = sub32(vm, base, subt);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+/// The flag calculation and result of a 32 bit `sub` or `cmp`, shared with threaded code
+pub(crate) fn sub32(vm: &mut VM, base: u32, subt: u32) -> u32{
     let (result, carry) = base.overflowing_sub(subt);
     let (_, overflow) = (base as i32).overflowing_sub(subt as i32);
     get_flags(vm, result as u32, subt as u32, base as u32, overflow, carry, AdjustType::Dec, SignType::Dword);
-    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
-    Ok(())
+    result
 }
 
 pub fn decrement_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError> {
@@ -1525,14 +1815,19 @@ pub fn decrement_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hyperviso
 
 pub fn decrement_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
+    let result = decrement32(vm, base);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+/// The flag calculation and result of a 32 bit `dec`, shared with threaded code
+pub(crate) fn decrement32(vm: &mut VM, base: u32) -> u32{
     let (result, overflow) = (base as i32).overflowing_sub(1 as i32);
     vm.flags.overflow = overflow;
     vm.flags.calculate_zero(result as u32);
     vm.flags.calculate_parity(result as u32);
     vm.flags.calculate_sign32(result as u32);
     vm.flags.adjust = ((base as i32)&0x0F) - ((1 as i32)&0x0F) < 0;
-    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result as u32))?;
-    Ok(())
+    result as u32
 }
 
 pub fn cmpxchg_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
@@ -1630,9 +1925,7 @@ pub fn cmp_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 pub fn cmp_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
     let cmpt = vm.get_arg(pipeline.args[1].location)?.u32_sx()?;
-    let (result, carry) = base.overflowing_sub(cmpt);
-    let (_, overflow) = (base as i32).overflowing_sub(cmpt as i32);
-    get_flags(vm, result as u32, cmpt as u32, base as u32, overflow, carry, AdjustType::Dec, SignType::Dword);
+    sub32(vm, base, cmpt);
     Ok(())
 }
 
@@ -1698,10 +1991,15 @@ pub fn and_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 pub fn and_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
     let mask = vm.get_arg(pipeline.args[1].location)?.u32_sx()?;
+    let result = and32(vm, base, mask);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+/// The flag calculation and result of a 32 bit `and`, shared with threaded code
+pub(crate) fn and32(vm: &mut VM, base: u32, mask: u32) -> u32{
     let result = base & mask;
     get_flags(vm, result as u32, mask as u32, base as u32, false, false, AdjustType::None, SignType::Dword);
-    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result as u32))?;
-    Ok(())
+    result
 }
 
 pub fn or_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
@@ -1733,10 +2031,15 @@ pub fn or_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> R
 pub fn or_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
     let mask = vm.get_arg(pipeline.args[1].location)?.u32_sx()?;
+    let result = or32(vm, base, mask);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+/// The flag calculation and result of a 32 bit `or`, shared with threaded code
+pub(crate) fn or32(vm: &mut VM, base: u32, mask: u32) -> u32{
     let result = base | mask;
     get_flags(vm, result as u32, mask as u32, base as u32, false, false, AdjustType::None, SignType::Dword);
-    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result as u32))?;
-    Ok(())
+    result
 }
 
 pub fn xor_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
@@ -1768,10 +2071,15 @@ pub fn xor_16bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) ->
 pub fn xor_32bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     let base = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
     let mask = vm.get_arg(pipeline.args[1].location)?.u32_sx()?;
+    let result = xor32(vm, base, mask);
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result))?;
+    Ok(())
+}
+/// The flag calculation and result of a 32 bit `xor`, shared with threaded code
+pub(crate) fn xor32(vm: &mut VM, base: u32, mask: u32) -> u32{
     let result = base ^ mask;
     get_flags(vm, result as u32, mask as u32, base as u32, false, false, AdjustType::None, SignType::Dword);
-    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(result as u32))?;
-    Ok(())
+    result
 }
 
 pub fn not_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
@@ -1847,6 +2155,11 @@ pub fn interrupt(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> R
     Ok(())
 }
 
+/// The logic function for the `ud2` opcode
+pub fn trap(vm: &mut VM, _pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    hv.trap(vm)
+}
+
 pub fn setcc_8bit(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
     if cc_matches(pipeline.opcode, &vm.flags){
         vm.set_arg(pipeline.args[0].location, SizedValue::Byte(1))?;
@@ -1943,18 +2256,18 @@ fn read_regw(vm: &VM, reg: Reg32, size_override: bool) -> u32{
 fn decrement_regw(vm: &mut VM, reg: Reg32, size_override: bool) -> u32{
     if size_override{
         let mut r = (vm.regs[reg as usize] & 0x0000FFFF) as u16;
-        r -= 1;
+        r = r.wrapping_sub(1);
         let write = (vm.regs[reg as usize] & 0xFFFF0000) | (r as u32);
         vm.regs[reg as usize] = write;
         r as u32
     }else{
-        vm.regs[reg as usize] -= 1;
+        vm.regs[reg as usize] = vm.regs[reg as usize].wrapping_sub(1);
         vm.regs[reg as usize]
     } 
 }
 
 pub fn repe(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
-    let opcodes = &crate::opcodes::OPCODES;
+    let opcodes = vm.opcodes.table();
     let function = opcodes[pipeline.opcode as usize].opcodes[0].function;
     let gas_cost = vm.charger.cost(opcodes[pipeline.opcode as usize].opcodes[0].gas_cost);
     /*      
@@ -1964,12 +2277,14 @@ pub fn repe(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Resul
     endwhile
     */
     while read_regw(vm, Reg32::ECX, pipeline.size_override) != 0{
-        if vm.gas_remaining == 0{
+        if vm.gas_remaining < gas_cost{
+            //the rep opcode is restarted from the current ECX when resuming, so refund its initial cost to avoid charging it twice
+            vm.gas_remaining += pipeline.gas_cost;
             return Err(VMError::OutOfGas);
         }
+        vm.gas_remaining -= gas_cost;
         function(vm, pipeline, _hv)?;
         decrement_regw(vm, Reg32::ECX, pipeline.size_override);
-        vm.gas_remaining = vm.gas_remaining.saturating_sub(gas_cost);
         if rep_flag_opcodes(pipeline.opcode) {
             if vm.flags.zero == false {
                 break;
@@ -1983,7 +2298,7 @@ pub fn repe(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Resul
     Ok(())
 }
 pub fn repne(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
-    let opcodes = &crate::opcodes::OPCODES;
+    let opcodes = vm.opcodes.table();
     if rep_flag_opcodes(pipeline.opcode){
         let function = opcodes[pipeline.opcode as usize].opcodes[0].function;
         let gas_cost = vm.charger.cost(opcodes[pipeline.opcode as usize].opcodes[0].gas_cost);
@@ -1994,12 +2309,14 @@ pub fn repne(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Resu
         endwhile
         */
         while read_regw(vm, Reg32::ECX, pipeline.size_override) != 0{
-            if vm.gas_remaining == 0{
+            if vm.gas_remaining < gas_cost{
+                //the rep opcode is restarted from the current ECX when resuming, so refund its initial cost to avoid charging it twice
+                vm.gas_remaining += pipeline.gas_cost;
                 return Err(VMError::OutOfGas);
             }
+            vm.gas_remaining -= gas_cost;
             function(vm, pipeline, _hv)?;
             decrement_regw(vm, Reg32::ECX, pipeline.size_override);
-            vm.gas_remaining = vm.gas_remaining.saturating_sub(gas_cost);
             if vm.flags.zero{
                 break;
             }
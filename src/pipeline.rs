@@ -2,6 +2,7 @@ use crate::opcodes::*;
 use crate::structs::*;
 use crate::vm::*;
 use crate::decoding::*;
+use std::collections::HashMap;
 
 #[allow(dead_code)] //remove after design stuff is done
 
@@ -20,39 +21,81 @@ impl Default for RepMode{
 #[derive(Default)]
 struct PrefixesActivated {
     size_override: bool,
+    address_override: bool,
+    lock: bool,
     two_bytes: bool,
+    three_bytes: bool,
     rep_mode: RepMode
 }
 
 impl PrefixesActivated {
-    fn get_prefixes(&mut self, buffer: &[u8], prefix_size: u8) -> Result<u8, VMError> {
-        match buffer[0]{
-            0x66 => {
-                self.size_override = true;
-                return self.get_prefixes(&buffer[1..], prefix_size+1);
-            },
-            0x0F => {
-                self.two_bytes = true;
-                Ok(prefix_size+1)
-            },
-            0x67 => {
-                // address size override. Only needed for LEA
-                unimplemented!();
-            },
-            0xF2 => {
-                //repne
-                self.rep_mode = RepMode::Repne;
-                return self.get_prefixes(&buffer[1..], prefix_size+1);
-            },
-            0xF3 => {
-                //rep/repe
-                self.rep_mode = RepMode::Repe;
-                return self.get_prefixes(&buffer[1..], prefix_size+1);
-            }
-            _ => {
-                Ok(prefix_size)
+    /// Parses the prefixes at the start of the buffer and returns the number of bytes they use.
+    /// Repeated prefixes are redundant and have no further effect, while for conflicting 0xF2 and 0xF3 prefixes the last one is used.
+    /// At least one byte following the prefixes must be within the buffer, otherwise the opcode is too long and is invalid
+    fn get_prefixes(&mut self, buffer: &[u8]) -> Result<u8, VMError> {
+        let mut prefix_size = 0;
+        while prefix_size < buffer.len(){
+            match buffer[prefix_size]{
+                0x66 => {
+                    self.size_override = true;
+                },
+                0x0F => {
+                    self.two_bytes = true;
+                    prefix_size += 1;
+                    if buffer.get(prefix_size) == Some(&0x38){
+                        self.three_bytes = true;
+                        prefix_size += 1;
+                    }
+                    break;
+                },
+                0x67 => {
+                    // address size override. Only valid for LEA
+                    self.address_override = true;
+                },
+                0x2E | 0x36 | 0x3E | 0x26 | 0x64 | 0x65 => {
+                    //segment overrides for CS, SS, DS, ES, FS, GS
+                    //segments are never used, so these are ignored
+                },
+                0xF0 => {
+                    //lock. Only valid for read-modify-write opcodes with a memory destination
+                    self.lock = true;
+                },
+                0xF2 => {
+                    //repne
+                    self.rep_mode = RepMode::Repne;
+                },
+                0xF3 => {
+                    //rep/repe
+                    self.rep_mode = RepMode::Repe;
+                }
+                _ => {
+                    break;
+                }
             }
+            prefix_size += 1;
+        }
+        if prefix_size >= buffer.len(){
+            return Err(VMError::InvalidOpcodeEncoding);
         }
+        Ok(prefix_size as u8)
+    }
+    /// Resolves the index within the opcode table of the opcode following these prefixes
+    /// For extended opcodes a 0x66 or 0xF3 prefix can be a mandatory prefix which selects a different opcode.
+    /// If there is no such opcode for 0x66, then it is instead treated as an operand size override
+    fn opcode_index(&self, opcodes: &[OpcodeProperties], opcode: u8) -> usize{
+        if !self.two_bytes{
+            return opcode as usize;
+        }
+        if self.three_bytes{
+            return opcode as usize | OP_THREEBYTE;
+        }
+        if self.rep_mode == RepMode::Repe{
+            return opcode as usize | OP_PREFIX_F3;
+        }
+        if self.size_override && opcodes[opcode as usize | OP_PREFIX_66].defined{
+            return opcode as usize | OP_PREFIX_66;
+        }
+        opcode as usize | OP_TWOBYTE
     }
 }
 
@@ -62,6 +105,8 @@ impl PrefixesActivated {
 pub struct Pipeline{
     /// The function pointer to the opcode logic function
     pub function: OpcodeFn,
+    /// The kind of the opcode, for opcodes which are handled specially after decoding
+    pub kind: OpcodeKind,
     /// The decoded arguments to be sent to the opcode logic function
     pub args: [OpArgument; MAX_ARGS],
     /// The gas cost of the current operation
@@ -71,18 +116,29 @@ pub struct Pipeline{
     /// Set to true if an operand size override prefix is present
     pub size_override: bool,
     /// The final opcode byte in the total opcode
-    pub opcode: u8
+    pub opcode: u8,
+    /// When a pair of opcodes is fused into this slot, the gas cost of the second opcode
+    /// This is charged by the fused logic function after the first opcode executes, rather than being included in gas_cost
+    pub fused_gas_cost: u64,
+    /// When a pair of opcodes is fused into this slot, the size of the second opcode. eip_size is the size of both opcodes
+    pub fused_eip_size: u8,
+    /// When a pair of opcodes is fused into this slot, the final opcode byte of the second opcode
+    pub fused_opcode: u8
 }
 
 impl Default for Pipeline{
     fn default() -> Pipeline {
         Pipeline{
             function: nop,
+            kind: OpcodeKind::Other,
             args: [OpArgument::default(), OpArgument::default(), OpArgument::default()],
             gas_cost: 0,
             eip_size: 1,
             size_override: false,
-            opcode: 0
+            opcode: 0,
+            fused_gas_cost: 0,
+            fused_eip_size: 0,
+            fused_opcode: 0
         }
     }
 }
@@ -95,90 +151,91 @@ pub fn clear_pipeline(pipeline: &mut [Pipeline]){
     }
 }
 
+/// The maximum size of a single opcode, including all of its prefixes
+pub const MAX_OPCODE_SIZE: u32 = 16;
+
+/// The buffer used for decoding is exactly MAX_OPCODE_SIZE bytes, so running out of bytes to decode means the opcode is too long
+fn too_long(e: VMError) -> VMError{
+    if e == VMError::DecodingOverrun{
+        VMError::InvalidOpcodeEncoding
+    }else{
+        e
+    }
+}
+
 /// Decode the stream of opcodes and fill the pipeline with decoded opcodes for later execution
 /// Note the pipeline is expected to be of fixed size and to not incur any allocation within the main loop of the VM
 pub fn fill_pipeline(vm: &VM, opcodes: &[OpcodeProperties], pipeline: &mut [Pipeline]) -> Result<(), VMError>{
+    decode_pipeline(vm, opcodes, pipeline, vm.gas_remaining, !vm.disable_fusion)?;
+    Ok(())
+}
+
+/// Determines if any breakpoints or watchpoints are set
+pub(crate) fn debugging(_vm: &VM) -> bool{
+    #[cfg(feature = "debugger")]
+    {
+        !_vm.debugger.breakpoints.is_empty() || !_vm.debugger.watchpoints.is_empty()
+    }
+    #[cfg(not(feature = "debugger"))]
+    {
+        false
+    }
+}
+
+/// Decodes opcodes into the pipeline as `fill_pipeline` does, but stops filling once `gas` is used rather than the remaining gas of the VM
+/// This returns the number of pipeline slots filled, and whether every decoded opcode was within read only memory
+pub(crate) fn decode_pipeline(vm: &VM, opcodes: &[OpcodeProperties], pipeline: &mut [Pipeline], gas: u64, fuse: bool) -> Result<(usize, bool), VMError>{
     let mut eip = vm.eip;
     let mut stop_filling = false;
-    let mut running_gas = vm.gas_remaining;
+    let mut running_gas = gas;
+    let mut filled = 0;
+    let mut read_only = true;
     //writeable if in memory with top bit set
     let writeable = vm.eip & 0x80000000 > 0;
+    //a fused pair can not be stopped between its opcodes, so fusion is not used when single stepping or debugging
+    let fuse = fuse && pipeline.len() > 1 && !debugging(vm);
     clear_pipeline(pipeline);
-    for n in 0..pipeline.len(){
-        let mut p = &mut pipeline[n];
+    let mut n = 0;
+    while n < pipeline.len(){
+        let p = &mut pipeline[n];
         p.gas_cost = 0; //this can be reused, so make sure to clear previous
+        p.fused_gas_cost = 0;
+        p.fused_eip_size = 0;
+        p.kind = OpcodeKind::Other;
         if stop_filling {
             p.function = nop;
             p.eip_size = 0;
             p.gas_cost = 0;
         }else{
-            let mut buffer = vm.memory.get_sized_memory(eip, 16)?;
-            let mut prefixes = PrefixesActivated::default();
-            let prefix_size = prefixes.get_prefixes(buffer, 0)?;
-            buffer = &buffer[prefix_size as usize..];
-
-            if prefixes.rep_mode != RepMode::None{
-                //rep opcodes are handled as a special case 
-                p.size_override = prefixes.size_override;               
-                p.gas_cost += vm.charger.cost(GasCost::Moderate);
-                p.eip_size = prefix_size + 1;
-                p.opcode = buffer[0];
-                if prefixes.rep_mode == RepMode::Repe{
-                    p.function = crate::ops::repe;
-                }else{
-                    p.function = crate::ops::repne;
+            filled = n + 1;
+            //relative jumps can lead into writeable memory
+            read_only &= eip & 0x80000000 == 0;
+            #[cfg(feature = "debugger")]
+            {
+                if vm.debugger.stops_at(eip, n == 0){
+                    //execution stops before the opcode at a breakpoint, so nothing after it can be in the pipeline
+                    p.function = crate::debugger::op_breakpoint;
+                    p.eip_size = 0;
+                    stop_filling = true;
+                    n += 1;
+                    continue;
                 }
-                //gas cost is unpredictable and potentially very large, so stop filling here. 
-                stop_filling = true;
-            }else{
-                let prop = &opcodes[buffer[0] as usize | ((prefixes.two_bytes as usize) << 8)];
-                p.opcode = buffer[0];
-                let mut modrm = Option::None;
-                let opcode = if prop.has_modrm{
-                    p.gas_cost += vm.charger.cost(GasCost::ModRMSurcharge);
-                    modrm = Some(ParsedModRM::from_bytes(buffer)?);
-                    &prop.opcodes[modrm.unwrap().modrm.reg as usize]
-                }else{
-                    &prop.opcodes[0]
-                };
-                p.function = opcode.function;
-                p.gas_cost += vm.charger.cost(opcode.gas_cost);
-                p.size_override = prefixes.size_override;
-                match opcode.pipeline_behavior{
-                    PipelineBehavior::None => {
-                        p.eip_size = decode_args_with_modrm(opcode, buffer, &mut p.args, prefixes.size_override, false, modrm)? as u8 + prefix_size;
-                    },
-                    PipelineBehavior::Unpredictable | PipelineBehavior::UnpredictableNoGas => {
-                        p.eip_size = decode_args_with_modrm(opcode, buffer, &mut p.args, prefixes.size_override, false, modrm)? as u8 + prefix_size;
-                        eip += p.eip_size as u32;
-                        stop_filling = true;
-                    },
-                    PipelineBehavior::RelativeJump => {
-                        p.eip_size = decode_args_with_modrm(opcode, buffer, &mut p.args, prefixes.size_override, false, modrm)? as u8 + prefix_size;
-                        //relative jumps are calculated from the EIP value AFTER the jump would've executed, ie, after EIP is advanced by the size of the instruction
-                        let future_eip = eip + (p.eip_size as u32);
-                        //rel must be sign extended, but is otherwise treated as a u32 for simplicity
-                        //an i32 and a u32 will behave the same way for wrapping_addition like this
-                        let rel = vm.get_arg(p.args[0].location)?.u32_sx()?;
-                        //subtract out the eip_size that'll be advanced in the main loop
-                        eip = future_eip.wrapping_add(rel) - (p.eip_size as u32);
-                        if p.size_override{
-                            return Err(VMError::ReadBadMemory(eip & 0xFFFF));
-                        }
+            }
+            match decode_slot(vm, opcodes, p, eip){
+                Ok((next, stop)) => {
+                    eip = next;
+                    stop_filling = stop;
+                },
+                Err(e) => {
+                    if n == 0{
+                        return Err(e);
                     }
-                };
-                p.gas_cost += match opcode.pipeline_behavior{
-                    PipelineBehavior::Unpredictable => vm.charger.cost(GasCost::ConditionalBranch),
-                    _ => 0
-                };
-                for i in 0..MAX_ARGS{
-                    p.gas_cost += if p.args[i].is_memory{
-                        vm.charger.cost(GasCost::MemoryAccess)
-                    }else{
-                        0
-                    };
+                    //an opcode which can not be decoded only faults once it is reached, so that EIP points to it
+                    //the opcodes before it are still executed, and this slot is cleared along with the rest of the pipeline
+                    filled = n;
+                    stop_filling = true;
+                    continue;
                 }
-                eip += p.eip_size as u32;
             }
             if writeable {
                 //if in writeable space, only use one pipeline slot at a time
@@ -189,8 +246,294 @@ pub fn fill_pipeline(vm: &VM, opcodes: &[OpcodeProperties], pipeline: &mut [Pipe
         }
         running_gas = running_gas.saturating_sub(p.gas_cost);
         stop_filling |= running_gas == 0;
+        if fuse && n > 0 && !writeable && fuse_opcodes(pipeline, n){
+            //this slot is now free to be filled with the next opcode
+            filled = n;
+            continue;
+        }
+        n += 1;
+    }
+    Ok((filled, read_only))
+}
+
+/// Decodes the single opcode at `eip`, ignoring breakpoints. This is used for reporting the opcode which caused a fault
+pub(crate) fn decode_opcode(vm: &VM, eip: u32) -> Result<Pipeline, VMError>{
+    let mut p = Pipeline::default();
+    decode_slot(vm, vm.opcodes.table(), &mut p, eip)?;
+    Ok(p)
+}
+
+/// Decodes the single opcode at `eip` into the pipeline slot.
+/// This returns the EIP at which decoding continues, and whether nothing further should be decoded into the pipeline
+fn decode_slot(vm: &VM, opcodes: &[OpcodeProperties], p: &mut Pipeline, eip: u32) -> Result<(u32, bool), VMError>{
+    let mut eip = eip;
+    let mut stop_filling = false;
+    let mut buffer = vm.memory.get_sized_memory(eip, MAX_OPCODE_SIZE)?;
+    let mut prefixes = PrefixesActivated::default();
+    let prefix_size = prefixes.get_prefixes(buffer)?;
+    buffer = &buffer[prefix_size as usize..];
+
+    if prefixes.rep_mode != RepMode::None && (prefixes.address_override || prefixes.lock){
+        //16 bit string operations are not supported, and string operations can not be locked
+        p.function = op_undefined;
+        p.eip_size = prefix_size + 1;
+        stop_filling = true;
+    }else if prefixes.two_bytes && (prefixes.rep_mode == RepMode::Repne || (prefixes.rep_mode == RepMode::Repe && prefixes.three_bytes)){
+        //there are no extended opcodes with a mandatory 0xF2 prefix, nor three byte opcodes with a mandatory 0xF3 prefix
+        p.function = op_undefined;
+        p.eip_size = prefix_size + 1;
+        stop_filling = true;
+    }else if prefixes.rep_mode != RepMode::None && !prefixes.two_bytes{
+        //rep opcodes are handled as a special case 
+        p.size_override = prefixes.size_override;               
+        p.gas_cost += vm.charger.cost(GasCost::Moderate);
+        p.eip_size = prefix_size + 1;
+        p.opcode = buffer[0];
+        p.kind = OpcodeKind::Rep;
+        if prefixes.rep_mode == RepMode::Repe{
+            p.function = crate::ops::repe;
+        }else{
+            p.function = crate::ops::repne;
+        }
+        //gas cost is unpredictable and potentially very large, so stop filling here. 
+        stop_filling = true;
+    }else{
+        let prop = &opcodes[prefixes.opcode_index(opcodes, buffer[0])];
+        p.opcode = buffer[0];
+        let mut modrm = Option::None;
+        let opcode = if prop.has_modrm{
+            p.gas_cost += vm.charger.cost(GasCost::ModRMSurcharge);
+            modrm = Some(if prefixes.address_override{
+                ParsedModRM::from_bytes16(buffer).map_err(too_long)?
+            }else{
+                ParsedModRM::from_bytes(buffer).map_err(too_long)?
+            });
+            &prop.opcodes[modrm.unwrap().modrm.reg as usize]
+        }else{
+            &prop.opcodes[0]
+        };
+        p.function = opcode.function;
+        p.kind = opcode.kind;
+        p.gas_cost += vm.charger.cost(opcode.gas_cost);
+        p.size_override = prefixes.size_override;
+        if (prefixes.address_override && !opcode.address_override) || (prefixes.lock && !opcode.lockable){
+            //only LEA can use an address size override, and only read-modify-write opcodes can use LOCK
+            //anything else is treated as an invalid opcode
+            p.function = op_undefined;
+            p.kind = OpcodeKind::Other;
+            p.eip_size = prefix_size + 1;
+            stop_filling = true;
+        }else{
+            match opcode.pipeline_behavior{
+                PipelineBehavior::None => {
+                    p.eip_size = decode_args_with_modrm(opcode, buffer, &mut p.args, prefixes.size_override, prefixes.address_override, modrm).map_err(too_long)? as u8 + prefix_size;
+                },
+                PipelineBehavior::Unpredictable | PipelineBehavior::UnpredictableNoGas => {
+                    p.eip_size = decode_args_with_modrm(opcode, buffer, &mut p.args, prefixes.size_override, prefixes.address_override, modrm).map_err(too_long)? as u8 + prefix_size;
+                    eip = eip.wrapping_add(p.eip_size as u32);
+                    stop_filling = true;
+                },
+                PipelineBehavior::RelativeJump | PipelineBehavior::RelativeJumpWithGas => {
+                    p.eip_size = decode_args_with_modrm(opcode, buffer, &mut p.args, prefixes.size_override, prefixes.address_override, modrm).map_err(too_long)? as u8 + prefix_size;
+                    //relative jumps are calculated from the EIP value AFTER the jump would've executed, ie, after EIP is advanced by the size of the instruction
+                    let future_eip = eip.wrapping_add(p.eip_size as u32);
+                    //rel must be sign extended, but is otherwise treated as a u32 for simplicity
+                    //an i32 and a u32 will behave the same way for wrapping_addition like this
+                    let rel = vm.get_arg(p.args[0].location)?.u32_sx()?;
+                    //subtract out the eip_size that'll be advanced in the main loop
+                    eip = future_eip.wrapping_add(rel).wrapping_sub(p.eip_size as u32);
+                    if p.size_override{
+                        return Err(VMError::ReadBadMemory(eip & 0xFFFF));
+                    }
+                    if future_eip.wrapping_add(rel) & 0x80000000 > 0{
+                        //do not follow into writeable memory, so that the next pipeline is decoded and charged as writeable
+                        stop_filling = true;
+                    }
+                }
+            };
+            if prefixes.lock && !p.args[0].is_memory{
+                //LOCK can not be used with a register destination
+                p.function = op_undefined;
+                p.kind = OpcodeKind::Other;
+                stop_filling = true;
+            }
+            p.gas_cost += match opcode.pipeline_behavior{
+                PipelineBehavior::Unpredictable | PipelineBehavior::RelativeJumpWithGas => vm.charger.cost(GasCost::ConditionalBranch),
+                _ => 0
+            };
+            for i in 0..MAX_ARGS{
+                p.gas_cost += if p.args[i].is_memory{
+                    vm.charger.cost(GasCost::MemoryAccess)
+                }else{
+                    0
+                };
+            }
+        }
+        eip = eip.wrapping_add(p.eip_size as u32);
+    }
+    Ok((eip, stop_filling))
+}
+
+/// Attempts to fuse the opcode in slot `n` into the opcode in the previous slot, returning true if they were fused.
+/// The fused logic function must have exactly the same result as executing both opcodes, including gas and EIP if running out of gas between them
+fn fuse_opcodes(pipeline: &mut [Pipeline], n: usize) -> bool{
+    use crate::ops::*;
+    let (before, after) = pipeline.split_at_mut(n);
+    let (first, second) = (&mut before[n - 1], &after[0]);
+    if first.fused_eip_size != 0 || second.eip_size == 0 || first.size_override || second.size_override{
+        return false;
+    }
+    let function: OpcodeFn = if second.kind == OpcodeKind::Jcc{
+        //cmp or test followed by jcc
+        match first.kind{
+            OpcodeKind::Cmp8 => cmp_8bit_jcc,
+            OpcodeKind::Cmp => cmp_native_word_jcc,
+            OpcodeKind::Test8 => test_8bit_jcc,
+            OpcodeKind::Test => test_native_word_jcc,
+            _ => return false
+        }
+    }else if first.kind == OpcodeKind::Push && first.opcode == 0x55 && second.kind == OpcodeKind::Mov
+        && second.args[0].location == ArgLocation::RegisterValue(Reg32::EBP as u8, ValueSize::Dword)
+        && second.args[1].location == ArgLocation::RegisterValue(Reg32::ESP as u8, ValueSize::Dword){
+        //push ebp; mov ebp, esp
+        push_ebp_mov_ebp_esp
+    }else{
+        return false;
+    };
+    first.function = function;
+    first.kind = OpcodeKind::Fused;
+    //cmp and test only use 2 arguments, so the jump target of jcc can be placed in the third
+    first.args[2] = second.args[0];
+    first.fused_gas_cost = second.gas_cost;
+    first.fused_eip_size = second.eip_size;
+    first.fused_opcode = second.opcode;
+    first.eip_size += second.eip_size;
+    true
+}
+
+/// The maximum number of decoded runs held by a PipelineCache. When this is reached, the cache is cleared
+const MAX_CACHED_RUNS: usize = 4096;
+
+/// Everything other than read only memory which affects how opcodes are decoded, used to determine when cached decoding results must be discarded
+#[derive(Default)]
+pub(crate) struct DecodeState{
+    generation: u64,
+    costs: [u64; GASCOST_COUNT],
+    opcodes: usize,
+    /// The VM's opcode table when last updated, which is held so that its address can not be reused by a different table
+    table: OpcodeTable,
+    disable_fusion: bool
+}
+
+impl DecodeState{
+    /// Updates the state to match the VM, returning true if anything has changed since it was last updated
+    pub(crate) fn update(&mut self, vm: &VM, opcodes: &[OpcodeProperties]) -> bool{
+        let table = opcodes.as_ptr() as usize;
+        if self.generation != vm.memory.generation() || self.costs != vm.charger.costs || self.opcodes != table || self.disable_fusion != vm.disable_fusion{
+            self.generation = vm.memory.generation();
+            self.costs = vm.charger.costs;
+            self.opcodes = table;
+            self.table = vm.opcodes.clone();
+            self.disable_fusion = vm.disable_fusion;
+            return true;
+        }
+        false
+    }
+}
+
+/// A cache of decoded opcodes within read only memory, keyed by the EIP which decoding began at.
+/// Read only memory can not be changed by the VM, so each run of opcodes only needs to be decoded once.
+/// Runs are decoded without a gas limit, and the gas cut-off is instead applied each time a run is copied into the pipeline.
+/// The cache is cleared when memory is added or read only memory is modified externally, or if the gas charger, opcode table, or fusion setting is changed
+#[derive(Default)]
+pub struct PipelineCache{
+    runs: HashMap<u32, Vec<Pipeline>>,
+    state: DecodeState
+}
+
+impl PipelineCache{
+    /// Clears the cache if anything used for decoding has changed since the cached runs were decoded
+    fn validate(&mut self, vm: &VM, opcodes: &[OpcodeProperties]){
+        if self.state.update(vm, opcodes){
+            self.runs.clear();
+        }
+    }
+    /// The number of cached runs
+    pub fn len(&self) -> usize{
+        self.runs.len()
+    }
+    /// Determines if there are no cached runs
+    pub fn is_empty(&self) -> bool{
+        self.runs.is_empty()
+    }
+}
+
+/// Fills the pipeline with exactly the same result as `fill_pipeline`, but reuses previously decoded opcodes when executing read only memory
+/// Only the gas cut-off is evaluated again when a cached run is used
+pub fn fill_pipeline_cached(vm: &VM, cache: &mut PipelineCache, opcodes: &[OpcodeProperties], pipeline: &mut [Pipeline]) -> Result<(), VMError>{
+    //breakpoints and watchpoints can be changed at any time, and cached runs may contain fused opcodes which can not be used while debugging
+    //likewise, fused opcodes can not be used for a pipeline with only a single slot
+    if debugging(vm) || vm.eip & 0x80000000 > 0 || pipeline.len() == 1 || pipeline.len() > PIPELINE_SIZE{
+        return fill_pipeline(vm, opcodes, pipeline);
+    }
+    cache.validate(vm, opcodes);
+    if let Some(run) = cache.runs.get(&vm.eip){
+        copy_run(vm, run, pipeline);
+        return Ok(());
+    }
+    if pipeline.len() < PIPELINE_SIZE{
+        //decode the full run so that it can also be used for larger pipelines
+        let mut run = [Pipeline::default(); PIPELINE_SIZE];
+        if let Some(filled) = decode_into_cache(vm, cache, opcodes, &mut run){
+            copy_run(vm, &run[0..filled], pipeline);
+            return Ok(());
+        }
+    }else if let Some(filled) = decode_into_cache(vm, cache, opcodes, pipeline){
+        gas_cut_off(vm, pipeline, filled);
+        return Ok(());
+    }
+    //runs which jump into writeable memory are never cached.
+    //errors are not cached either, since without a gas limit decoding can continue further than fill_pipeline would
+    fill_pipeline(vm, opcodes, pipeline)
+}
+
+/// Decodes a full run without a gas limit and adds it to the cache, returning the number of pipeline slots filled
+/// Nothing is cached if decoding fails or reaches writeable memory
+fn decode_into_cache(vm: &VM, cache: &mut PipelineCache, opcodes: &[OpcodeProperties], run: &mut [Pipeline]) -> Option<usize>{
+    match decode_pipeline(vm, opcodes, run, u64::MAX, !vm.disable_fusion){
+        Ok((filled, true)) => {
+            if cache.runs.len() >= MAX_CACHED_RUNS{
+                cache.runs.clear();
+            }
+            cache.runs.insert(vm.eip, run[0..filled].to_vec());
+            Some(filled)
+        },
+        _ => Option::None
+    }
+}
+
+/// Copies as much of a cached run as fits into the pipeline, and then applies the gas cut-off
+fn copy_run(vm: &VM, run: &[Pipeline], pipeline: &mut [Pipeline]){
+    let used = std::cmp::min(run.len(), pipeline.len());
+    pipeline[0..used].copy_from_slice(&run[0..used]);
+    gas_cut_off(vm, pipeline, used);
+}
+
+/// Fills the pipeline with nops after the first `filled` slots, or after the remaining gas of the VM is used
+fn gas_cut_off(vm: &VM, pipeline: &mut [Pipeline], filled: usize){
+    let mut running_gas = vm.gas_remaining;
+    let mut stop_filling = false;
+    for (n, p) in pipeline.iter_mut().enumerate(){
+        if stop_filling || n >= filled{
+            p.function = nop;
+            p.kind = OpcodeKind::Other;
+            p.eip_size = 0;
+            p.gas_cost = 0;
+        }else{
+            running_gas = running_gas.saturating_sub(p.gas_cost).saturating_sub(p.fused_gas_cost);
+            stop_filling = running_gas == 0;
+        }
     }
-    Ok(())
 }
 
 #[cfg(test)]
@@ -198,22 +541,41 @@ mod tests{
     use super::*;
 
     //just a simple test function for comparison
-    fn test_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Ok(())}
-    fn test2_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Ok(())}
-    fn test3_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Ok(())}
-    fn test4_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Ok(())}
-    fn test5_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Ok(())}
+    //each returns a distinct error, so that which one a pipeline slot calls can be checked by executing it
+    fn test_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Err(VMError::Trap(1))}
+    fn test2_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Err(VMError::Trap(2))}
+    fn test3_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Err(VMError::Trap(3))}
+    fn test4_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Err(VMError::Trap(4))}
+    fn test5_op(_vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{Err(VMError::Trap(5))}
+
+    struct TestHypervisor{}
+    impl Hypervisor for TestHypervisor{
+        fn interrupt(&mut self, _vm: &mut VM, _num: u8) -> Result<(), VMError>{
+            Ok(())
+        }
+    }
+    /// Executes the logic function of a pipeline slot within an empty VM
+    fn called(p: &Pipeline) -> Result<(), VMError>{
+        (p.function)(&mut VM::default(), p, &mut TestHypervisor{})
+    }
+    /// The result of `called` for op_undefined, which reads the opcode byte at EIP
+    const UNDEFINED: Result<(), VMError> = Err(VMError::ReadUnloadedMemory(0));
     /* Opcodes defined:
     0x00 -- undefined (purposefully)
     0x01 (), 10 gas -- test_op
     0x02 (imm8), 2 gas -- nop
     0x03 (imm32), 50 gas -- test3_op, conditional jump behavior
     0x10 + r (reg32, off32), 23 gas -- test2_op
+    0x0F 0x05 -- test5_op
+    0x66 0x0F 0x06 -- test2_op
+    0xF3 0x0F 0x05 -- test4_op
+    0x0F 0x38 0x05 -- test2_op
     */
-    fn test_opcodes() -> [OpcodeProperties; OPCODE_TABLE_SIZE]{
+    fn test_opcodes() -> Vec<OpcodeProperties>{
         use OpcodeValueSize::*;
         use ValueSize::*;
-        let mut table = [OpcodeProperties::default(); OPCODE_TABLE_SIZE];
+        //this is allocated on the heap since the full table is too large for the stack of a test thread
+        let mut table = vec![OpcodeProperties::default(); OPCODE_TABLE_SIZE];
 
         define_opcode(0x01)
             .with_gas(GasCost::Low)
@@ -245,6 +607,18 @@ mod tests{
             .with_gas(GasCost::Low)
             .calls(test5_op)
             .into_table(&mut table);
+        define_opcode(0x06).with_mandatory_prefix(0x66)
+            .with_gas(GasCost::Low)
+            .calls(test2_op)
+            .into_table(&mut table);
+        define_opcode(0x05).with_mandatory_prefix(0xF3)
+            .with_gas(GasCost::Low)
+            .calls(test4_op)
+            .into_table(&mut table);
+        define_opcode(0x05).is_three_byte_op()
+            .with_gas(GasCost::Low)
+            .calls(test2_op)
+            .into_table(&mut table);
 
         table
     }
@@ -271,11 +645,11 @@ mod tests{
         //See also: https://www.reddit.com/r/rust/comments/98xlh3/how_can_i_compare_two_function_pointers_to_see_if/
         //If weird things break here later, it might be worth figuring out if this is the reason
 
-        assert!(pipeline[0].function as usize == test_op as usize);
+        assert_eq!(called(&pipeline[0]), Err(VMError::Trap(1)));
         assert!(pipeline[0].args[0].location == ArgLocation::None);
         assert!(pipeline[0].eip_size == 1);
 
-        assert!(pipeline[1].function as usize == nop as usize);
+        assert_eq!(called(&pipeline[1]), Ok(()));
         assert!(pipeline[1].args[0].location == ArgLocation::Immediate(SizedValue::Byte(0x15)));
         assert!(pipeline[1].args[1].location == ArgLocation::None);
         assert!(pipeline[1].eip_size == 2);
@@ -283,7 +657,7 @@ mod tests{
         vm.eip += pipeline[0].eip_size as u32 + pipeline[1].eip_size as u32;
         fill_pipeline(&vm, &opcodes, &mut pipeline).unwrap();
 
-        assert_eq!(pipeline[0].function as usize, test2_op as usize);
+        assert_eq!(called(&pipeline[0]), Err(VMError::Trap(2)));
         assert!(pipeline[0].args[0].location == ArgLocation::RegisterValue(2, ValueSize::Dword));
         assert!(pipeline[0].args[1].location == ArgLocation::Address(0x44332211, ValueSize::Dword));
         assert!(pipeline[0].eip_size == 5); 
@@ -306,17 +680,17 @@ mod tests{
         pipeline.resize(3, Pipeline::default());
         fill_pipeline(&vm, &opcodes, &mut pipeline).unwrap();
 
-        assert!(pipeline[0].function as usize == test_op as usize);
+        assert_eq!(called(&pipeline[0]), Err(VMError::Trap(1)));
         assert!(pipeline[0].args[0].location == ArgLocation::None);
         assert!(pipeline[0].eip_size == 1);
 
-        assert!(pipeline[1].function as usize == test3_op as usize);
+        assert_eq!(called(&pipeline[1]), Err(VMError::Trap(3)));
         assert!(pipeline[1].args[0].location == ArgLocation::Immediate(SizedValue::Dword(0x44332211)));
         assert!(pipeline[1].args[1].location == ArgLocation::None);
         assert!(pipeline[1].eip_size == 5); 
 
         //ensure next opcode after conditional jump is nop
-        assert!(pipeline[2].function as usize == nop as usize);
+        assert_eq!(called(&pipeline[2]), Ok(()));
         assert!(pipeline[2].args[0].location == ArgLocation::None);
         assert!(pipeline[2].args[1].location == ArgLocation::None);
         assert!(pipeline[2].eip_size == 0);  
@@ -337,7 +711,7 @@ mod tests{
         pipeline.resize(3, Pipeline::default());
         fill_pipeline(&vm, &opcodes, &mut pipeline).unwrap();
 
-        assert_eq!(pipeline[0].function as usize, test4_op as usize);
+        assert_eq!(called(&pipeline[0]), Err(VMError::Trap(4)));
         assert_eq!(pipeline[0].args[0].location, ArgLocation::RegisterAddress(Reg32::EDX as u8, ValueSize::Dword));
         assert_eq!(pipeline[0].eip_size, 2);
     }
@@ -358,12 +732,188 @@ mod tests{
         pipeline.resize(3, Pipeline::default());
         fill_pipeline(&vm, &opcodes, &mut pipeline).unwrap();
 
-        assert_eq!(pipeline[0].function as usize, test5_op as usize);
+        assert_eq!(called(&pipeline[0]), Err(VMError::Trap(5)));
         assert_eq!(pipeline[0].size_override, true);
         assert_eq!(pipeline[0].args[0].location, ArgLocation::None);
         assert_eq!(pipeline[0].eip_size, 3);
     }
-}
+    #[test]
+    fn test_mandatory_prefix_opcodes(){
+        let opcodes = test_opcodes();
+        let mut vm = VM::default();
+        vm.gas_remaining = 1;
+        let vm_mem = vm.memory.add_memory(0x10000, 0x100).unwrap();
+        vm.eip = 0x10000;
+        let bytes = vec![
+            0x66, 0x0F, 0x06, //test2_op, only defined with the 0x66 prefix
+            0xF3, 0x0F, 0x05, //test4_op, takes priority over the unprefixed opcode
+            0x66, 0x0F, 0x05, //falls back to test5_op with a size override
+            0x0F, 0x06, //undefined without the 0x66 prefix
+        ];
+        (&mut vm_mem[0..bytes.len()]).copy_from_slice(&bytes);
+        let mut pipeline = vec![];
+        pipeline.resize(4, Pipeline::default());
+        fill_pipeline(&vm, &opcodes, &mut pipeline).unwrap();
+
+        assert_eq!(called(&pipeline[0]), Err(VMError::Trap(2)));
+        assert_eq!(pipeline[0].eip_size, 3);
+        assert_eq!(called(&pipeline[1]), Err(VMError::Trap(4)));
+        assert_eq!(pipeline[1].eip_size, 3);
+        assert_eq!(called(&pipeline[2]), Err(VMError::Trap(5)));
+        assert!(pipeline[2].size_override);
+        assert_eq!(called(&pipeline[3]), UNDEFINED);
+    }
+    #[test]
+    fn test_three_byte_opcodes(){
+        let opcodes = test_opcodes();
+        let mut vm = VM::default();
+        vm.gas_remaining = 1;
+        let vm_mem = vm.memory.add_memory(0x10000, 0x100).unwrap();
+        vm.eip = 0x10000;
+        let bytes = vec![
+            0x66, 0x0F, 0x38, 0x05, //test2_op with a size override
+            0x0F, 0x38, 0x05, //test2_op
+            0xF3, 0x0F, 0x38, 0x05, //there are no three byte opcodes with a mandatory prefix
+        ];
+        (&mut vm_mem[0..bytes.len()]).copy_from_slice(&bytes);
+        let mut pipeline = vec![];
+        pipeline.resize(3, Pipeline::default());
+        fill_pipeline(&vm, &opcodes, &mut pipeline).unwrap();
 
+        assert_eq!(called(&pipeline[0]), Err(VMError::Trap(2)));
+        assert!(pipeline[0].size_override);
+        assert_eq!(pipeline[0].eip_size, 4);
+        assert_eq!(called(&pipeline[1]), Err(VMError::Trap(2)));
+        assert_eq!(pipeline[1].eip_size, 3);
+        assert_eq!(called(&pipeline[2]), UNDEFINED);
+    }
 
+    fn assert_same_pipeline(a: &[Pipeline], b: &[Pipeline]){
+        for n in 0..a.len(){
+            assert_eq!(called(&a[n]), called(&b[n]));
+            assert_eq!(a[n].eip_size, b[n].eip_size);
+            assert_eq!(a[n].gas_cost, b[n].gas_cost);
+            if a[n].eip_size != 0{
+                assert_eq!(a[n].opcode, b[n].opcode);
+                for i in 0..MAX_ARGS{
+                    assert!(a[n].args[i].location == b[n].args[i].location);
+                }
+            }
+        }
+    }
 
+    #[test]
+    fn test_cached_pipeline(){
+        let opcodes = test_opcodes();
+        let mut vm = VM::default();
+        vm.charger = GasCharger::test_schedule();
+        vm.memory.add_memory(0x10000, 0x100).unwrap();
+        vm.memory.add_memory(0x80000000, 0x100).unwrap();
+        vm.eip = 0x10000;
+        let bytes = vec![
+            0x01, //test_op
+            0x02, 0x15, //nop, imm8
+            0x12, 0x11, 0x22, 0x33, 0x44, //test2_op, EDX, off32
+            0x01, //test_op
+            0x03, 0x11, 0x22, 0x33, 0x44, //test3_op, imm32, cond jump
+            0x01 //test_op
+        ];
+        vm.copy_into_memory(0x10000, &bytes).unwrap();
+        let mut cache = PipelineCache::default();
+        let mut expected = vec![Pipeline::default(); 4];
+        let mut pipeline = vec![Pipeline::default(); 4];
+        //the gas cut-off must be the same as for an uncached pipeline
+        for gas in 0..60{
+            vm.gas_remaining = gas;
+            fill_pipeline(&vm, &opcodes, &mut expected).unwrap();
+            fill_pipeline_cached(&vm, &mut cache, &opcodes, &mut pipeline).unwrap();
+            assert_same_pipeline(&pipeline, &expected);
+        }
+        assert_eq!(cache.len(), 1);
+        vm.eip = 0x10008;
+        fill_pipeline_cached(&vm, &mut cache, &opcodes, &mut pipeline).unwrap();
+        assert_eq!(called(&pipeline[0]), Err(VMError::Trap(1)));
+        assert_eq!(called(&pipeline[1]), Err(VMError::Trap(3)));
+        assert_eq!(pipeline[2].eip_size, 0);
+        assert_eq!(cache.len(), 2);
+
+        //changing read only memory externally invalidates the cache
+        vm.memory.set_u8(0x10008, 0x02).unwrap();
+        fill_pipeline_cached(&vm, &mut cache, &opcodes, &mut pipeline).unwrap();
+        assert_eq!(called(&pipeline[0]), Ok(()));
+        assert_eq!(pipeline[0].eip_size, 2);
+        assert_eq!(cache.len(), 1);
+        //as does changing the gas charger
+        vm.charger.costs[GasCost::Low as usize] = 100;
+        fill_pipeline_cached(&vm, &mut cache, &opcodes, &mut pipeline).unwrap();
+        fill_pipeline(&vm, &opcodes, &mut expected).unwrap();
+        assert_same_pipeline(&pipeline, &expected);
+        //as does adding memory
+        vm.memory.add_memory(0x20000, 0x100).unwrap();
+        vm.eip = 0x10000;
+        fill_pipeline_cached(&vm, &mut cache, &opcodes, &mut pipeline).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        //writeable memory is never cached
+        vm.copy_into_memory(0x80000000, &bytes).unwrap();
+        vm.eip = 0x80000000;
+        fill_pipeline_cached(&vm, &mut cache, &opcodes, &mut pipeline).unwrap();
+        fill_pipeline(&vm, &opcodes, &mut expected).unwrap();
+        assert_same_pipeline(&pipeline, &expected);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_fused_pipeline(){
+        let mut vm = VM::default();
+        vm.gas_remaining = 1000;
+        vm.memory.add_memory(0x10000, 0x100).unwrap();
+        vm.eip = 0x10000;
+        let bytes = vec![
+            0x55, //push ebp
+            0x89, 0xE5, //mov ebp, esp
+            0x39, 0xD8, //cmp eax, ebx
+            0x75, 0xF9 //jne -7
+        ];
+        vm.copy_into_memory(0x10000, &bytes).unwrap();
+        let mut pipeline = vec![Pipeline::default(); 4];
+        fill_pipeline(&vm, vm.opcodes.table(), &mut pipeline).unwrap();
+        assert_eq!(pipeline[0].kind, OpcodeKind::Fused);
+        assert_eq!(pipeline[0].eip_size, 3);
+        assert_eq!(pipeline[0].fused_eip_size, 2);
+        assert_eq!(pipeline[1].kind, OpcodeKind::Fused);
+        assert_eq!(pipeline[1].args[2].location, ArgLocation::Immediate(SizedValue::Byte(0xF9)));
+        assert_eq!(pipeline[1].eip_size, 4);
+        assert_eq!(pipeline[1].fused_opcode, 0x75);
+        assert_eq!(pipeline[2].eip_size, 0);
+        //the fused logic functions have the effects of both opcodes
+        let mut stack = VM::default();
+        stack.memory.add_memory(0x80000000, 0x100).unwrap();
+        stack.gas_remaining = 1000;
+        stack.regs[Reg32::ESP as usize] = 0x80000100;
+        stack.regs[Reg32::EBP as usize] = 0x1234;
+        (pipeline[0].function)(&mut stack, &pipeline[0], &mut TestHypervisor{}).unwrap();
+        assert_eq!(stack.regs[Reg32::EBP as usize], 0x800000FC);
+        assert_eq!(stack.regs[Reg32::ESP as usize], 0x800000FC);
+        assert_eq!(stack.memory.get_u32(0x800000FC).unwrap(), 0x1234);
+        stack.regs[Reg32::EAX as usize] = 5;
+        stack.regs[Reg32::EBX as usize] = 5;
+        (pipeline[1].function)(&mut stack, &pipeline[1], &mut TestHypervisor{}).unwrap();
+        assert!(stack.flags.zero);
+
+        //the second opcode is not fused when there is not enough gas to decode it
+        vm.gas_remaining = pipeline[0].gas_cost;
+        fill_pipeline(&vm, vm.opcodes.table(), &mut pipeline).unwrap();
+        assert_eq!(pipeline[0].kind, OpcodeKind::Push);
+        assert_eq!(pipeline[1].eip_size, 0);
+
+        //nor when fusion is disabled
+        vm.gas_remaining = 1000;
+        vm.disable_fusion = true;
+        fill_pipeline(&vm, vm.opcodes.table(), &mut pipeline).unwrap();
+        assert_eq!(pipeline[0].kind, OpcodeKind::Push);
+        assert_eq!(pipeline[1].kind, OpcodeKind::Mov);
+        assert_eq!(pipeline[2].kind, OpcodeKind::Cmp);
+        assert_eq!(pipeline[3].kind, OpcodeKind::Jcc);
+    }
+}
@@ -0,0 +1,533 @@
+//! Software IEEE-754 arithmetic on the bit patterns of f32 and f64 values.
+//! No host floating point operations are used, so results are identical on every platform.
+//! Values are unpacked into a significand with the leading 1 at bit 62 (leaving bit 63 free for carries)
+//! and a biased f64 exponent, so that the value is `sig / 2^62 * 2^(exp - 1023)`
+
+/// The rounding modes used by the x87 control word, in the order of their encoding
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum RoundingMode{
+    NearestEven = 0,
+    Down,
+    Up,
+    TowardZero
+}
+
+impl RoundingMode{
+    /// Decodes the RC field of an x87 control word
+    pub fn from_control_word(cw: u16) -> RoundingMode{
+        match (cw >> 10) & 3{
+            0 => RoundingMode::NearestEven,
+            1 => RoundingMode::Down,
+            2 => RoundingMode::Up,
+            _ => RoundingMode::TowardZero
+        }
+    }
+}
+
+//exception flags, using the same bit positions as the x87 status word
+pub const EXCEPTION_INVALID: u16 = 1 << 0;
+pub const EXCEPTION_DIVIDE_BY_ZERO: u16 = 1 << 2;
+pub const EXCEPTION_OVERFLOW: u16 = 1 << 3;
+pub const EXCEPTION_UNDERFLOW: u16 = 1 << 4;
+pub const EXCEPTION_PRECISION: u16 = 1 << 5;
+
+/// The "real indefinite" value, which x87 returns for invalid operations
+pub const DEFAULT_NAN: u64 = 0xFFF8_0000_0000_0000;
+pub const ONE: u64 = 0x3FF0_0000_0000_0000;
+const SIGN: u64 = 1 << 63;
+const FRAC_MASK: u64 = (1 << 52) - 1;
+const QUIET_BIT: u64 = 1 << 51;
+
+/// The result of an operation: the bit pattern of the value and any exceptions which occurred
+pub type FloatResult = (u64, u16);
+
+/// Describes the layout of a packed floating point format
+struct Format{
+    frac_bits: u32,
+    exp_max: i32
+}
+const F64: Format = Format{frac_bits: 52, exp_max: 0x7FF};
+const F32: Format = Format{frac_bits: 23, exp_max: 0xFF};
+
+fn shift_right_jam(v: u64, count: u32) -> u64{
+    if count == 0{
+        v
+    }else if count < 64{
+        (v >> count) | (((v << (64 - count)) != 0) as u64)
+    }else{
+        (v != 0) as u64
+    }
+}
+
+fn sign_of(a: u64) -> bool{
+    a & SIGN != 0
+}
+fn exp_of(a: u64) -> i32{
+    ((a >> 52) & 0x7FF) as i32
+}
+pub fn is_nan(a: u64) -> bool{
+    exp_of(a) == 0x7FF && a & FRAC_MASK != 0
+}
+pub fn is_signaling_nan(a: u64) -> bool{
+    is_nan(a) && a & QUIET_BIT == 0
+}
+fn is_inf(a: u64) -> bool{
+    exp_of(a) == 0x7FF && a & FRAC_MASK == 0
+}
+fn is_zero(a: u64) -> bool{
+    a & !SIGN == 0
+}
+fn inf(sign: bool) -> u64{
+    ((sign as u64) << 63) | (0x7FF << 52)
+}
+fn zero(sign: bool) -> u64{
+    (sign as u64) << 63
+}
+
+/// Unpacks a finite non-zero value into (sign, exponent, significand)
+fn unpack(a: u64) -> (bool, i32, u64){
+    let exp = exp_of(a);
+    let frac = a & FRAC_MASK;
+    if exp == 0{
+        //subnormal, normalize so the leading 1 is at bit 62
+        let shift = frac.leading_zeros() - 1;
+        (sign_of(a), 11 - shift as i32, frac << shift)
+    }else{
+        (sign_of(a), exp, (frac | (1 << 52)) << 10)
+    }
+}
+
+/// Rounds and packs a value into the specified format.
+/// `exp` is an f64 biased exponent and `sig` must be 0 or have its leading 1 at bit 62
+fn round_pack(format: &Format, sign: bool, exp: i32, sig: u64, mode: RoundingMode) -> FloatResult{
+    let exp_bits = if format.exp_max == 0x7FF { 11 } else { 8 };
+    let sign_bit = (sign as u64) << (format.frac_bits + exp_bits);
+    if sig == 0{
+        return (sign_bit, 0);
+    }
+    let mut exp = exp - 1023 + (format.exp_max >> 1);
+    let mut sig = sig;
+    let drop = 62 - format.frac_bits;
+    let round_mask = (1u64 << drop) - 1;
+    let half = 1u64 << (drop - 1);
+    let mut exceptions = 0;
+    let tiny = exp < 1;
+    if tiny{
+        sig = shift_right_jam(sig, (1 - exp) as u32);
+        exp = 1;
+    }
+    let round_bits = sig & round_mask;
+    if round_bits != 0{
+        exceptions |= EXCEPTION_PRECISION;
+        if tiny{
+            exceptions |= EXCEPTION_UNDERFLOW;
+        }
+    }
+    let increment = match mode{
+        RoundingMode::NearestEven => half,
+        RoundingMode::TowardZero => 0,
+        RoundingMode::Down => if sign { round_mask } else { 0 },
+        RoundingMode::Up => if sign { 0 } else { round_mask }
+    };
+    sig += increment;
+    if sig & SIGN != 0{
+        sig >>= 1;
+        exp += 1;
+    }
+    let mut frac = sig >> drop;
+    if mode == RoundingMode::NearestEven && round_bits == half{
+        //ties go to even
+        frac &= !1;
+    }
+    if exp >= format.exp_max{
+        exceptions |= EXCEPTION_OVERFLOW | EXCEPTION_PRECISION;
+        let to_inf = match mode{
+            RoundingMode::NearestEven => true,
+            RoundingMode::TowardZero => false,
+            RoundingMode::Down => sign,
+            RoundingMode::Up => !sign
+        };
+        let max_exp = format.exp_max as u64;
+        return if to_inf{
+            (sign_bit | (max_exp << format.frac_bits), exceptions)
+        }else{
+            (sign_bit | (((max_exp - 1) << format.frac_bits) | ((1 << format.frac_bits) - 1)), exceptions)
+        };
+    }
+    let implicit = 1u64 << format.frac_bits;
+    let packed_exp = if frac & implicit != 0 { exp as u64 } else { 0 };
+    (sign_bit | (packed_exp << format.frac_bits) | (frac & (implicit - 1)), exceptions)
+}
+
+/// Selects the NaN to return when either operand is a NaN
+fn propagate_nan(a: u64, b: u64) -> FloatResult{
+    let exceptions = if is_signaling_nan(a) || is_signaling_nan(b) { EXCEPTION_INVALID } else { 0 };
+    if is_nan(a){
+        (a | QUIET_BIT, exceptions)
+    }else{
+        (b | QUIET_BIT, exceptions)
+    }
+}
+
+/// Quiets a signaling NaN, raising an invalid exception. Any other value is returned unchanged
+pub fn quiet(a: u64) -> FloatResult{
+    if is_signaling_nan(a){
+        (a | QUIET_BIT, EXCEPTION_INVALID)
+    }else{
+        (a, 0)
+    }
+}
+
+pub fn add(a: u64, b: u64, mode: RoundingMode) -> FloatResult{
+    if is_nan(a) || is_nan(b){
+        return propagate_nan(a, b);
+    }
+    if is_inf(a) || is_inf(b){
+        if is_inf(a) && is_inf(b) && sign_of(a) != sign_of(b){
+            return (DEFAULT_NAN, EXCEPTION_INVALID);
+        }
+        return (if is_inf(a) { a } else { b }, 0);
+    }
+    if is_zero(a) && is_zero(b){
+        if sign_of(a) == sign_of(b){
+            return (a, 0);
+        }
+        return (zero(mode == RoundingMode::Down), 0);
+    }
+    if is_zero(a){
+        return (b, 0);
+    }
+    if is_zero(b){
+        return (a, 0);
+    }
+    let (sign_a, exp_a, sig_a) = unpack(a);
+    let (sign_b, exp_b, sig_b) = unpack(b);
+    //order so that a has the larger magnitude
+    let ((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b)) = if exp_a > exp_b || (exp_a == exp_b && sig_a >= sig_b){
+        ((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b))
+    }else{
+        ((sign_b, exp_b, sig_b), (sign_a, exp_a, sig_a))
+    };
+    let sig_b = shift_right_jam(sig_b, (exp_a - exp_b) as u32);
+    if sign_a == sign_b{
+        let mut sum = sig_a + sig_b;
+        let mut exp = exp_a;
+        if sum & SIGN != 0{
+            sum = shift_right_jam(sum, 1);
+            exp += 1;
+        }
+        round_pack(&F64, sign_a, exp, sum, mode)
+    }else{
+        let diff = sig_a - sig_b;
+        if diff == 0{
+            return (zero(mode == RoundingMode::Down), 0);
+        }
+        let shift = diff.leading_zeros() - 1;
+        round_pack(&F64, sign_a, exp_a - shift as i32, diff << shift, mode)
+    }
+}
+
+pub fn sub(a: u64, b: u64, mode: RoundingMode) -> FloatResult{
+    if is_nan(b){
+        return propagate_nan(a, b);
+    }
+    add(a, b ^ SIGN, mode)
+}
+
+pub fn mul(a: u64, b: u64, mode: RoundingMode) -> FloatResult{
+    if is_nan(a) || is_nan(b){
+        return propagate_nan(a, b);
+    }
+    let sign = sign_of(a) != sign_of(b);
+    if is_inf(a) || is_inf(b){
+        if is_zero(a) || is_zero(b){
+            return (DEFAULT_NAN, EXCEPTION_INVALID);
+        }
+        return (inf(sign), 0);
+    }
+    if is_zero(a) || is_zero(b){
+        return (zero(sign), 0);
+    }
+    let (_, exp_a, sig_a) = unpack(a);
+    let (_, exp_b, sig_b) = unpack(b);
+    let product = (sig_a as u128) * (sig_b as u128);
+    let mut sig = (product >> 62) as u64 | (((product as u64) & ((1 << 62) - 1)) != 0) as u64;
+    let mut exp = exp_a + exp_b - 1023;
+    if sig & SIGN != 0{
+        sig = shift_right_jam(sig, 1);
+        exp += 1;
+    }
+    round_pack(&F64, sign, exp, sig, mode)
+}
+
+pub fn div(a: u64, b: u64, mode: RoundingMode) -> FloatResult{
+    if is_nan(a) || is_nan(b){
+        return propagate_nan(a, b);
+    }
+    let sign = sign_of(a) != sign_of(b);
+    if is_inf(a){
+        if is_inf(b){
+            return (DEFAULT_NAN, EXCEPTION_INVALID);
+        }
+        return (inf(sign), 0);
+    }
+    if is_inf(b){
+        return (zero(sign), 0);
+    }
+    if is_zero(b){
+        if is_zero(a){
+            return (DEFAULT_NAN, EXCEPTION_INVALID);
+        }
+        return (inf(sign), EXCEPTION_DIVIDE_BY_ZERO);
+    }
+    if is_zero(a){
+        return (zero(sign), 0);
+    }
+    let (_, exp_a, sig_a) = unpack(a);
+    let (_, exp_b, sig_b) = unpack(b);
+    let mut exp = exp_a - exp_b + 1023;
+    //keep the quotient's leading 1 at bit 62
+    let dividend = if sig_a < sig_b{
+        exp -= 1;
+        (sig_a as u128) << 63
+    }else{
+        (sig_a as u128) << 62
+    };
+    let quotient = (dividend / sig_b as u128) as u64;
+    let remainder = dividend % sig_b as u128;
+    round_pack(&F64, sign, exp, quotient | (remainder != 0) as u64, mode)
+}
+
+/// Compares two values, returning None if they are unordered (ie, either is a NaN)
+pub fn compare(a: u64, b: u64) -> Option<std::cmp::Ordering>{
+    use std::cmp::Ordering;
+    if is_nan(a) || is_nan(b){
+        return None;
+    }
+    if is_zero(a) && is_zero(b){
+        return Some(Ordering::Equal);
+    }
+    let order = match (sign_of(a), sign_of(b)){
+        (false, true) => Ordering::Greater,
+        (true, false) => Ordering::Less,
+        //for the same sign, the bit patterns order by magnitude
+        (false, false) => a.cmp(&b),
+        (true, true) => b.cmp(&a)
+    };
+    Some(order)
+}
+
+pub fn from_i64(v: i64, mode: RoundingMode) -> FloatResult{
+    if v == 0{
+        return (0, 0);
+    }
+    let magnitude = v.unsigned_abs();
+    let lz = magnitude.leading_zeros();
+    if lz == 0{
+        round_pack(&F64, v < 0, 1023 + 63, shift_right_jam(magnitude, 1), mode)
+    }else{
+        round_pack(&F64, v < 0, 1023 + 63 - lz as i32, magnitude << (lz - 1), mode)
+    }
+}
+
+/// Converts to an integer which must fit within the specified number of bits.
+/// Returns None if the value is a NaN, infinite, or out of range
+pub fn to_int(a: u64, bits: u32, mode: RoundingMode) -> (Option<i64>, u16){
+    if is_nan(a) || is_inf(a){
+        return (None, EXCEPTION_INVALID);
+    }
+    if is_zero(a){
+        return (Some(0), 0);
+    }
+    let (sign, exp, sig) = unpack(a);
+    //value is within [2^e, 2^(e+1))
+    let e = exp - 1023;
+    if e >= 64{
+        return (None, EXCEPTION_INVALID);
+    }
+    let shift = 62 - e;
+    let (mut integer, fraction) = if shift >= 128{
+        (0u128, 1u64)
+    }else if shift >= 0{
+        let wide = (sig as u128) << 64;
+        let fixed = wide >> shift;
+        let lost = shift > 0 && wide & ((1u128 << shift) - 1) != 0;
+        (fixed >> 64, fixed as u64 | lost as u64)
+    }else{
+        //only possible for e == 63
+        ((sig as u128) << 1, 0)
+    };
+    let increment = match mode{
+        RoundingMode::NearestEven => fraction > (1 << 63) || (fraction == (1 << 63) && integer & 1 == 1),
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => sign && fraction != 0,
+        RoundingMode::Up => !sign && fraction != 0
+    };
+    if increment{
+        integer += 1;
+    }
+    let limit = 1u128 << (bits - 1);
+    if (!sign && integer >= limit) || (sign && integer > limit){
+        return (None, EXCEPTION_INVALID);
+    }
+    let exceptions = if fraction != 0 { EXCEPTION_PRECISION } else { 0 };
+    let value = if sign { (integer as i128).wrapping_neg() as i64 } else { integer as i64 };
+    (Some(value), exceptions)
+}
+
+/// Converts an f32 bit pattern to an f64 bit pattern. This is always exact
+pub fn from_f32(a: u32) -> FloatResult{
+    let sign = ((a >> 31) as u64) << 63;
+    let exp = ((a >> 23) & 0xFF) as i32;
+    let frac = (a & 0x7FFFFF) as u64;
+    if exp == 0xFF{
+        if frac == 0{
+            return (sign | (0x7FF << 52), 0);
+        }
+        let exceptions = if frac & (1 << 22) == 0 { EXCEPTION_INVALID } else { 0 };
+        return (sign | (0x7FF << 52) | QUIET_BIT | (frac << 29), exceptions);
+    }
+    if exp == 0{
+        if frac == 0{
+            return (sign, 0);
+        }
+        //subnormal f32 values are normal as f64
+        let shift = frac.leading_zeros() - 40;
+        let normalized = (frac << shift) & 0x7FFFFF;
+        return (sign | (((1 - shift as i32 - 127 + 1023) as u64) << 52) | (normalized << 29), 0);
+    }
+    (sign | (((exp - 127 + 1023) as u64) << 52) | (frac << 29), 0)
+}
+
+/// Converts an f64 bit pattern to an f32 bit pattern, rounding as needed
+pub fn to_f32(a: u64, mode: RoundingMode) -> (u32, u16){
+    let sign = ((a >> 63) as u32) << 31;
+    if is_nan(a){
+        let exceptions = if is_signaling_nan(a) { EXCEPTION_INVALID } else { 0 };
+        return (sign | 0x7FC00000 | ((a & FRAC_MASK) >> 29) as u32, exceptions);
+    }
+    if is_inf(a){
+        return (sign | 0x7F800000, 0);
+    }
+    if is_zero(a){
+        return (sign, 0);
+    }
+    let (sign, exp, sig) = unpack(a);
+    let (v, exceptions) = round_pack(&F32, sign, exp, sig, mode);
+    (v as u32, exceptions)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use RoundingMode::*;
+
+    fn f(v: f64) -> u64{
+        v.to_bits()
+    }
+
+    #[test]
+    fn test_basic_arithmetic(){
+        assert_eq!(add(f(1.5), f(2.25), NearestEven), (f(3.75), 0));
+        assert_eq!(sub(f(1.5), f(2.25), NearestEven), (f(-0.75), 0));
+        assert_eq!(mul(f(1.5), f(-2.25), NearestEven), (f(-3.375), 0));
+        assert_eq!(div(f(1.0), f(4.0), NearestEven), (f(0.25), 0));
+        assert_eq!(div(f(1.0), f(3.0), NearestEven), (f(1.0 / 3.0), EXCEPTION_PRECISION));
+        assert_eq!(add(f(0.1), f(0.2), NearestEven), (f(0.1 + 0.2), EXCEPTION_PRECISION));
+        assert_eq!(mul(f(1e300), f(1e-300), NearestEven).0, f(1e300 * 1e-300));
+        assert_eq!(sub(f(1.0), f(1.0), NearestEven), (f(0.0), 0));
+        assert_eq!(sub(f(1.0), f(1.0), Down), (f(-0.0), 0));
+    }
+
+    #[test]
+    fn test_rounding_modes(){
+        //1/3 is not exact, so the rounding direction can be observed
+        let third = div(f(1.0), f(3.0), NearestEven).0;
+        assert_eq!(div(f(1.0), f(3.0), Down).0, third);
+        assert_eq!(div(f(1.0), f(3.0), Up).0, third + 1);
+        assert_eq!(div(f(1.0), f(3.0), TowardZero).0, third);
+        assert_eq!(div(f(-1.0), f(3.0), Down).0, (third + 1) | SIGN);
+        assert_eq!(div(f(-1.0), f(3.0), TowardZero).0, third | SIGN);
+    }
+
+    #[test]
+    fn test_special_values(){
+        assert_eq!(div(f(1.0), f(0.0), NearestEven), (f(f64::INFINITY), EXCEPTION_DIVIDE_BY_ZERO));
+        assert_eq!(div(f(0.0), f(0.0), NearestEven), (DEFAULT_NAN, EXCEPTION_INVALID));
+        assert_eq!(sub(f(f64::INFINITY), f(f64::INFINITY), NearestEven), (DEFAULT_NAN, EXCEPTION_INVALID));
+        assert_eq!(mul(f(f64::MAX), f(2.0), NearestEven), (f(f64::INFINITY), EXCEPTION_OVERFLOW | EXCEPTION_PRECISION));
+        assert_eq!(mul(f(f64::MAX), f(2.0), TowardZero).0, f(f64::MAX));
+        //subnormal results
+        let min = f(f64::MIN_POSITIVE);
+        assert_eq!(div(min, f(4.0), NearestEven), (f(f64::MIN_POSITIVE / 4.0), 0));
+        assert_eq!(mul(f(5e-324), f(0.5), NearestEven), (f(0.0), EXCEPTION_PRECISION | EXCEPTION_UNDERFLOW));
+        assert_eq!(add(f(5e-324), f(5e-324), NearestEven), (f(1e-323), 0));
+        assert_eq!(compare(f(f64::NAN), f(1.0)), None);
+        assert_eq!(compare(f(-0.0), f(0.0)), Some(std::cmp::Ordering::Equal));
+        assert_eq!(compare(f(-2.0), f(-1.0)), Some(std::cmp::Ordering::Less));
+    }
+
+    #[test]
+    fn test_integer_conversion(){
+        assert_eq!(from_i64(-5, NearestEven), (f(-5.0), 0));
+        assert_eq!(from_i64(i64::MIN, NearestEven), (f(-9223372036854775808.0), 0));
+        assert_eq!(from_i64(i64::MAX, NearestEven), (f(9223372036854775807.0), EXCEPTION_PRECISION));
+        assert_eq!(to_int(f(2.5), 32, NearestEven), (Some(2), EXCEPTION_PRECISION));
+        assert_eq!(to_int(f(3.5), 32, NearestEven), (Some(4), EXCEPTION_PRECISION));
+        assert_eq!(to_int(f(-2.7), 32, TowardZero), (Some(-2), EXCEPTION_PRECISION));
+        assert_eq!(to_int(f(-2.2), 32, Down), (Some(-3), EXCEPTION_PRECISION));
+        assert_eq!(to_int(f(0.1), 32, Up), (Some(1), EXCEPTION_PRECISION));
+        assert_eq!(to_int(f(32767.0), 16, NearestEven), (Some(32767), 0));
+        assert_eq!(to_int(f(32768.0), 16, NearestEven), (None, EXCEPTION_INVALID));
+        assert_eq!(to_int(f(-32768.0), 16, NearestEven), (Some(-32768), 0));
+        assert_eq!(to_int(f(-9223372036854775808.0), 64, NearestEven), (Some(i64::MIN), 0));
+        assert_eq!(to_int(f(9223372036854775808.0), 64, NearestEven), (None, EXCEPTION_INVALID));
+        assert_eq!(to_int(f(f64::NAN), 32, NearestEven), (None, EXCEPTION_INVALID));
+    }
+
+    #[test]
+    fn test_matches_host(){
+        //host arithmetic is IEEE-754 round to nearest, so it can be used as a reference in tests
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            //narrow the exponent range half of the time so that values interact rather than being absorbed
+            if seed & 1 == 0 { seed } else { (seed & 0x800F_FFFF_FFFF_FFFF) | (0x3F0 + (seed >> 52 & 0x1F)) << 52 }
+        };
+        for _ in 0..100000{
+            let (a, b) = (next(), next());
+            let expected = [f64::from_bits(a) + f64::from_bits(b), f64::from_bits(a) - f64::from_bits(b),
+                f64::from_bits(a) * f64::from_bits(b), f64::from_bits(a) / f64::from_bits(b)];
+            let actual = [add(a, b, NearestEven).0, sub(a, b, NearestEven).0, mul(a, b, NearestEven).0, div(a, b, NearestEven).0];
+            for (e, r) in expected.iter().zip(actual.iter()){
+                if e.is_nan(){
+                    assert!(is_nan(*r));
+                }else{
+                    assert_eq!(e.to_bits(), *r, "{:x} {:x}", a, b);
+                }
+            }
+            let single = f64::from_bits(a) as f32;
+            if !single.is_nan(){
+                assert_eq!(to_f32(a, NearestEven).0, single.to_bits());
+            }
+            let v = f64::from_bits(a);
+            if v.abs() < 2147483648.0{
+                assert_eq!(to_int(a, 32, TowardZero).0, Some(v as i32 as i64));
+            }
+        }
+    }
+
+    #[test]
+    fn test_f32_conversion(){
+        assert_eq!(from_f32(1.5f32.to_bits()), (f(1.5), 0));
+        assert_eq!(from_f32(f32::MIN_POSITIVE.to_bits() / 2), (f(f32::MIN_POSITIVE as f64 / 2.0), 0));
+        assert_eq!(from_f32(1e-45f32.to_bits()), (f(1e-45f32 as f64), 0));
+        assert_eq!(to_f32(f(1.5), NearestEven), (1.5f32.to_bits(), 0));
+        assert_eq!(to_f32(f(0.1), NearestEven), (0.1f32.to_bits(), EXCEPTION_PRECISION));
+        assert_eq!(to_f32(f(1e300), NearestEven), (f32::INFINITY.to_bits(), EXCEPTION_OVERFLOW | EXCEPTION_PRECISION));
+        assert_eq!(to_f32(f(1e-40), NearestEven).0, 1e-40f32.to_bits());
+        assert_eq!(to_f32(f(-0.0), NearestEven), ((-0.0f32).to_bits(), 0));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::vm::*;
+use crate::pipeline::*;
+use crate::structs::*;
+
+/*
+SSE2 implementation notes:
+Only the integer data movement and bitwise logic opcodes are implemented, which is what compilers emit for
+block copies and zeroing of memory. Alignment is not enforced for the aligned forms (ie, movdqa and movaps)
+and MXCSR is not implemented. Anything using floating point or integer arithmetic within XMM registers is an invalid opcode
+*/
+
+/// Retrieves the low 64 bits of an XMM register or memory argument
+fn get_qword_arg(vm: &VM, location: ArgLocation) -> Result<u64, VMError>{
+    match location{
+        ArgLocation::RegisterValue(r, _) => Ok(vm.xmm[r as usize] as u64),
+        _ => vm.get_arg(location)?.u64_exact()
+    }
+}
+
+/// The logic function for the `movd xmm, r/m32` opcode
+/// The value is zero extended to fill the entire XMM register
+pub fn movd_to_xmm(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let v = vm.get_arg(pipeline.args[1].location)?.u32_exact()?;
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dqword(v as u128))
+}
+
+/// The logic function for the `movq xmm, xmm/m64` opcode
+/// The value is zero extended to fill the entire XMM register
+pub fn movq_to_xmm(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let v = get_qword_arg(vm, pipeline.args[1].location)?;
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dqword(v as u128))
+}
+
+/// The logic function for the `movd r/m32, xmm` opcode
+pub fn movd_from_xmm(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let v = vm.get_arg(pipeline.args[1].location)?.u32_trunc();
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(v))
+}
+
+/// The logic function for the `movq xmm/m64, xmm` opcode
+/// When the destination is a register, the upper 64 bits are cleared
+pub fn movq_from_xmm(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let v = vm.get_arg(pipeline.args[1].location)?.u64_trunc();
+    match pipeline.args[0].location{
+        ArgLocation::RegisterValue(r, _) => {
+            vm.xmm[r as usize] = v as u128;
+            Ok(())
+        },
+        _ => vm.set_arg(pipeline.args[0].location, SizedValue::Qword(v))
+    }
+}
+
+/// Shared implementation of the bitwise logic opcodes which store `op(dest, source)` in the destination XMM register
+fn logic(vm: &mut VM, pipeline: &Pipeline, op: fn(u128, u128) -> u128) -> Result<(), VMError>{
+    let dest = vm.get_arg(pipeline.args[0].location)?.u128_exact()?;
+    let source = vm.get_arg(pipeline.args[1].location)?.u128_exact()?;
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dqword(op(dest, source)))
+}
+
+/// The logic function for the `pand` and `andps` opcodes
+pub fn pand(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    logic(vm, pipeline, |d, s| d & s)
+}
+
+/// The logic function for the `pandn` and `andnps` opcodes
+pub fn pandn(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    logic(vm, pipeline, |d, s| !d & s)
+}
+
+/// The logic function for the `por` and `orps` opcodes
+pub fn por(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    logic(vm, pipeline, |d, s| d | s)
+}
+
+/// The logic function for the `pxor` and `xorps` opcodes
+pub fn pxor(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    logic(vm, pipeline, |d, s| d ^ s)
+}
+
+/// The logic function for the `pshufd` opcode, which selects each dword of the destination from the source using 2 bits of the immediate
+pub fn pshufd(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let source = vm.get_arg(pipeline.args[1].location)?.u128_exact()?;
+    let order = vm.get_arg(pipeline.args[2].location)?.u8_exact()?;
+    let mut result = 0u128;
+    for i in 0..4{
+        let selected = (order >> (i * 2)) & 3;
+        let dword = (source >> (selected * 32)) as u32;
+        result |= (dword as u128) << (i * 32);
+    }
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dqword(result))
+}
@@ -20,6 +20,8 @@ pub enum ValueSize{
     Dword,
     /// Qword value size, this is 8 bytes
     Qword,
+    /// Double qword value size, this is 16 bytes. Used only for XMM registers
+    Dqword,
 }
 
 
@@ -40,10 +42,19 @@ pub enum SizedValue{
     /// A dword value of 4 bytes
     Dword(u32),
     /// A quadruple word value of 8 bytes
-    Qword(u64)
+    Qword(u64),
+    /// A double quadruple word value of 16 bytes
+    Dqword(u128)
 }
 
 impl SizedValue{
+    /// Unwraps the value expecting it to be exactly a Dqword. Returns an error if not
+    pub fn u128_exact(&self) -> Result<u128, VMError>{
+        match self{
+            SizedValue::Dqword(v) => Ok(*v),
+            _ => Err(VMError::WrongSizeExpectation)
+        }
+    }
     /// Unwraps the value expecting it to be exactly a Dword. Returns an error if not
     pub fn u64_exact(&self) -> Result<u64, VMError>{
         match self{
@@ -81,6 +92,7 @@ impl SizedValue{
             SizedValue::Word(v) => Ok(*v as u32),
             SizedValue::Byte(v) => Ok(*v as u32),
             SizedValue::Qword(v) => Err(VMError::TooBigSizeExpectation),
+            SizedValue::Dqword(_v) => Err(VMError::TooBigSizeExpectation),
             SizedValue::None => Ok(0),
         }
     }
@@ -94,6 +106,7 @@ impl SizedValue{
             SizedValue::None => Ok(0),
             SizedValue::Dword(_v) => Err(VMError::TooBigSizeExpectation),
             SizedValue::Qword(v) => Err(VMError::TooBigSizeExpectation),
+            SizedValue::Dqword(_v) => Err(VMError::TooBigSizeExpectation),
         }
     }
 
@@ -106,6 +119,7 @@ impl SizedValue{
             SizedValue::Byte(v) => Ok(*v as i8 as i32 as u32),
             SizedValue::None => Ok(0),
             SizedValue::Qword(v) => Err(VMError::TooBigSizeExpectation),
+            SizedValue::Dqword(_v) => Err(VMError::TooBigSizeExpectation),
         }
     }
 
@@ -118,6 +132,15 @@ impl SizedValue{
             SizedValue::None => Ok(0),
             SizedValue::Dword(_v) => Err(VMError::TooBigSizeExpectation),
             SizedValue::Qword(_v) => Err(VMError::TooBigSizeExpectation),
+            SizedValue::Dqword(_v) => Err(VMError::TooBigSizeExpectation),
+        }
+    }
+
+    /// Unwraps the value as a u128 by zero-extending smaller values
+    pub fn u128_trunc(&self) ->  u128{
+        match self{
+            SizedValue::Dqword(v) => *v,
+            _ => self.u64_trunc() as u128
         }
     }
 
@@ -126,6 +149,7 @@ impl SizedValue{
         match self{
             SizedValue::Dword(v) => *v as u64,
             SizedValue::Qword(v) => *v,
+            SizedValue::Dqword(v) => *v as u64,
             SizedValue::Word(v) => *v as u64,
             SizedValue::Byte(v) => *v as u64,
             SizedValue::None => 0,
@@ -137,6 +161,7 @@ impl SizedValue{
         match self{
             SizedValue::Dword(v) => *v,
             SizedValue::Qword(v) => *v as u32,
+            SizedValue::Dqword(v) => *v as u32,
             SizedValue::Word(v) => *v as u32,
             SizedValue::Byte(v) => *v as u32,
             SizedValue::None => 0,
@@ -150,6 +175,7 @@ impl SizedValue{
             SizedValue::None => 0,
             SizedValue::Dword(v) => *v as u16,
             SizedValue::Qword(v) => *v as u16,
+            SizedValue::Dqword(v) => *v as u16,
         }
     }
     /// Unwraps the value as a u8 by zero-extending smaller values and truncating larger values to keep only the least significant bits that will fit
@@ -160,6 +186,7 @@ impl SizedValue{
             SizedValue::None => 0,
             SizedValue::Dword(v) => *v as u8,
             SizedValue::Qword(v) => *v as u8,
+            SizedValue::Dqword(v) => *v as u8,
         }
     }
 
@@ -172,7 +199,12 @@ impl SizedValue{
             Word => Ok(SizedValue::Word(self.u16_zx()?)),
             Byte => Ok(SizedValue::Byte(self.u8_exact()?)),
             None => Err(VMError::WrongSizeExpectation),
-            Qword => Err(VMError::TooBigSizeExpectation),
+            Qword => match self{
+                SizedValue::Dqword(_v) => Err(VMError::TooBigSizeExpectation),
+                _ => Ok(SizedValue::Qword(self.u64_trunc()))
+            },
+            //every value will fit within a dqword
+            Dqword => Ok(SizedValue::Dqword(self.u128_trunc())),
         }
     }
     /// This will convert the current SizedValue to the specified ValueSize by sign-extending smaller values
@@ -185,12 +217,14 @@ impl SizedValue{
             Byte => Ok(SizedValue::Byte(self.u8_exact()?)),
             None => Err(VMError::WrongSizeExpectation),
             Qword=> Err(VMError::TooBigSizeExpectation),
+            Dqword => Err(VMError::TooBigSizeExpectation),
         }
     }
     /// This will convert the current SizedValue to the specified ValueSize by zero-extending smaller values and truncating larger values than will fit
     pub fn convert_size_trunc(&self, s: ValueSize) -> SizedValue{
         use ValueSize::*;
         match s{
+            Dqword => SizedValue::Dqword(self.u128_trunc()),
             Qword => SizedValue::Qword(self.u64_trunc()),
             Dword => SizedValue::Dword(self.u32_trunc()),
             Word => SizedValue::Word(self.u16_trunc()),
@@ -233,12 +267,12 @@ pub enum ArgLocation{
     Address(u32, ValueSize), //an immediate address
     RegisterValue(u8, ValueSize),
     RegisterAddress(u8, ValueSize),
-    /*ModRMAddress16{ //Not supported except for with LEA
-        offset: Option<u16>, 
+    ModRMAddress16{ //Not supported except for with LEA
+        offset: Option<u16>,
         reg1: Option<u8>,
         reg2: Option<u8>,
         size: ValueSize
-    }, */
+    },
 
     //note offset here can be negative (ie, top bit set) but with wrapping_add
     //the results will be identical without needing to juggle between u32 and i32 with type checks etc
@@ -0,0 +1,458 @@
+use crate::vm::*;
+use crate::pipeline::*;
+use crate::opcodes::*;
+use crate::structs::*;
+use crate::ops::*;
+use std::collections::HashMap;
+#[cfg(feature = "jit")]
+use crate::jit::*;
+use std::sync::Arc;
+
+/// Controls when blocks of read only code are translated into threaded code
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Translation{
+    /// Everything is executed by the interpreter
+    Disabled,
+    /// A block is translated once it has been executed TRANSLATION_THRESHOLD times
+    #[default]
+    HotBlocks,
+    /// Every block is translated the first time it is executed.
+    /// This is slower for code which is only executed once, and so is mostly useful for testing
+    AllBlocks
+}
+
+/// The number of times a block must be executed by the interpreter before it is translated
+pub const TRANSLATION_THRESHOLD: u32 = 8;
+/// The maximum number of opcodes in a single block
+const MAX_BLOCK_SIZE: usize = 32;
+/// The maximum number of blocks held by a ThreadedCache. When this is reached, the cache is cleared
+const MAX_CACHED_BLOCKS: usize = 4096;
+
+/// The logic of a single opcode with its arguments resolved at translation time
+type ThreadedFn = Box<dyn Fn(&mut VM, &mut dyn Hypervisor) -> Result<(), VMError> + Send + Sync>;
+
+struct ThreadedOp{
+    function: ThreadedFn,
+    eip_size: u32,
+    /// The gas cost of every opcode after this one in the block, which is refunded if this opcode stops execution
+    refund: u64
+}
+
+/// A straight-line run of opcodes translated into threaded code.
+/// The gas of the entire block is charged before it begins executing
+pub(crate) struct Block{
+    ops: Vec<ThreadedOp>,
+    gas: u64
+}
+
+enum Entry{
+    /// The number of times the block has been executed by the interpreter
+    Cold(u32),
+    Translated(Arc<Block>),
+    /// The block could not be translated, such as if it jumps into writeable memory or fails to decode
+    Untranslatable
+}
+
+/// The translated blocks of a VM, keyed by the EIP which the block begins at.
+/// Only read only memory is translated, so this is cleared under the same conditions as the PipelineCache
+#[derive(Default)]
+pub struct ThreadedCache{
+    blocks: HashMap<u32, Entry>,
+    state: DecodeState,
+    #[cfg(feature = "jit")]
+    arena: CodeArena,
+    #[cfg(feature = "jit")]
+    disable_jit: bool
+}
+
+impl ThreadedCache{
+    /// Retrieves the translated block at EIP, translating it if it has become hot.
+    /// None is returned if the block should be executed by the interpreter instead, including when there is not enough gas for the entire block
+    pub(crate) fn lookup(&mut self, vm: &VM, opcodes: &[OpcodeProperties]) -> Option<Arc<Block>>{
+        if vm.translation == Translation::Disabled || vm.eip & 0x80000000 > 0 || debugging(vm){
+            return None;
+        }
+        #[allow(unused_mut)]
+        let mut changed = self.state.update(vm, opcodes);
+        #[cfg(feature = "jit")]
+        {
+            changed |= self.disable_jit != vm.disable_jit;
+            self.disable_jit = vm.disable_jit;
+        }
+        if changed{
+            self.clear();
+        }
+        if self.blocks.len() >= MAX_CACHED_BLOCKS && !self.blocks.contains_key(&vm.eip){
+            self.clear();
+        }
+        let threshold = if vm.translation == Translation::AllBlocks { 0 } else { TRANSLATION_THRESHOLD };
+        let entry = self.blocks.entry(vm.eip).or_insert(Entry::Cold(0));
+        if let Entry::Cold(count) = entry{
+            if *count < threshold{
+                *count += 1;
+                return None;
+            }
+            let mut pipeline = [Pipeline::default(); MAX_BLOCK_SIZE];
+            let block = translate(vm, opcodes, &mut pipeline);
+            #[cfg(feature = "jit")]
+            let block = match block{
+                Some(mut block) if !vm.disable_jit => {
+                    compile_block(&mut block, &pipeline, &mut self.arena);
+                    Some(block)
+                },
+                block => block
+            };
+            *entry = match block{
+                Some(block) => Entry::Translated(Arc::new(block)),
+                None => Entry::Untranslatable
+            };
+        }
+        match entry{
+            Entry::Translated(block) if block.gas <= vm.gas_remaining => Some(block.clone()),
+            _ => None
+        }
+    }
+    fn clear(&mut self){
+        self.blocks.clear();
+        //any chunk of native code is freed once the last block using it is dropped
+        #[cfg(feature = "jit")]
+        {
+            self.arena = CodeArena::default();
+        }
+    }
+    /// The number of translated blocks
+    pub fn len(&self) -> usize{
+        self.blocks.values().filter(|e| matches!(e, Entry::Translated(_))).count()
+    }
+    /// Determines if there are no translated blocks
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+}
+
+/// Executes a translated block with exactly the same result as the interpreter.
+/// The caller must ensure there is enough gas for the entire block
+pub(crate) fn run_block(vm: &mut VM, block: &Block, hv: &mut dyn Hypervisor) -> Result<bool, VMError>{
+    vm.gas_remaining -= block.gas;
+    for op in &block.ops{
+        if let Err(e) = (op.function)(vm, hv){
+            vm.gas_remaining += op.refund;
+            return match e{
+                VMError::InternalVMStop => Ok(true),
+                VMError::Yield => {
+                    vm.fault_eip = Some(vm.eip);
+                    vm.eip = vm.eip.wrapping_add(op.eip_size);
+                    Err(VMError::Yield)
+                },
+                e => Err(e)
+            };
+        }
+        vm.eip = vm.eip.wrapping_add(op.eip_size);
+    }
+    Ok(false)
+}
+
+/// Decodes and translates the block at EIP, leaving the decoded opcodes of the block at the start of `pipeline`
+fn translate(vm: &VM, opcodes: &[OpcodeProperties], pipeline: &mut [Pipeline]) -> Option<Block>{
+    //each opcode is translated separately, so there is no benefit to fusion here
+    let filled = match decode_pipeline(vm, opcodes, pipeline, u64::MAX, false){
+        Ok((filled, true)) if filled > 0 => filled,
+        _ => return None
+    };
+    //custom opcodes may depend on the remaining gas, so they are treated the same as rdtsc
+    let custom = opcodes.as_ptr() != OPCODES.as_ptr();
+    let mut ops = vec![];
+    for p in &pipeline[0..filled]{
+        let specialized = specialize(p);
+        let last = specialized.is_none() && (custom || ends_block(p));
+        let p = *p;
+        ops.push(ThreadedOp{
+            function: specialized.unwrap_or_else(|| Box::new(move |vm, hv| (p.function)(vm, &p, hv))),
+            eip_size: p.eip_size as u32,
+            refund: p.gas_cost
+        });
+        if last{
+            break;
+        }
+    }
+    let gas = ops.iter().map(|op| op.refund).sum();
+    //each refund is the gas of the opcodes after it
+    let mut refund = 0;
+    for op in ops.iter_mut().rev(){
+        let cost = op.refund;
+        op.refund = refund;
+        refund += cost;
+    }
+    Some(Block{
+        ops,
+        gas
+    })
+}
+
+/// Replaces each run of opcodes within a translated block which can be compiled into native code with a single op which executes the native code.
+/// `pipeline` holds the decoded opcodes of the block
+#[cfg(feature = "jit")]
+fn compile_block(block: &mut Block, pipeline: &[Pipeline], arena: &mut CodeArena){
+    let pipeline = &pipeline[0..block.ops.len()];
+    let mut ops = vec![];
+    let mut threaded = std::mem::take(&mut block.ops).into_iter();
+    let mut i = 0;
+    while let Some(op) = threaded.next(){
+        match compile(&pipeline[i..], arena){
+            Some(native) => {
+                i += native.ops;
+                //compiled opcodes can not fail, but the refund is kept the same as for the last of them
+                let refund = threaded.by_ref().take(native.ops - 1).last().map_or(op.refund, |last| last.refund);
+                ops.push(ThreadedOp{
+                    function: Box::new(move |vm, _| {
+                        native.run(vm);
+                        Ok(())
+                    }),
+                    //the native code updates EIP itself
+                    eip_size: 0,
+                    refund
+                });
+            },
+            None => {
+                i += 1;
+                ops.push(op);
+            }
+        }
+    }
+    block.ops = ops;
+}
+
+/// Determines if an opcode must be the last in a block.
+/// This is the case for opcodes which depend on the remaining gas, since the gas of the entire block is charged up front,
+/// and for opcodes which call the hypervisor, since it may also look at the remaining gas
+fn ends_block(p: &Pipeline) -> bool{
+    matches!(p.kind, OpcodeKind::Rdtsc | OpcodeKind::Interrupt | OpcodeKind::Trap | OpcodeKind::Rep)
+}
+
+/// An operand of a 32 bit opcode, resolved at translation time
+#[derive(Copy, Clone)]
+pub(crate) enum Operand{
+    Reg(usize),
+    Imm(u32),
+    Mem(EffectiveAddress)
+}
+
+/// A memory address of the form `base + index * scale + offset`, resolved at translation time
+#[derive(Copy, Clone)]
+pub(crate) struct EffectiveAddress{
+    pub(crate) base: Option<usize>,
+    pub(crate) index: Option<usize>,
+    pub(crate) scale: u32,
+    pub(crate) offset: u32
+}
+
+impl EffectiveAddress{
+    /// Resolves any argument location which is a 32 bit memory address, regardless of the size of the value there
+    pub(crate) fn new(location: ArgLocation) -> Option<EffectiveAddress>{
+        let (base, index, scale, offset) = match location{
+            ArgLocation::Address(a, _) => (None, None, 0, a),
+            ArgLocation::RegisterAddress(r, _) => (Some(r), None, 0, 0),
+            ArgLocation::ModRMAddress{offset, reg, size: _} => (reg, None, 0, offset.unwrap_or(0)),
+            ArgLocation::SIBAddress{offset, base, scale, index, size: _} => (base, index, scale as u32, offset),
+            _ => return None
+        };
+        Some(EffectiveAddress{
+            base: base.map(|r| r as usize),
+            index: index.map(|r| r as usize),
+            scale,
+            offset
+        })
+    }
+    fn resolve(&self, vm: &VM) -> u32{
+        let base = self.base.map_or(0, |r| vm.regs[r]);
+        let index = self.index.map_or(0, |r| vm.regs[r]);
+        base.wrapping_add(index.wrapping_mul(self.scale)).wrapping_add(self.offset)
+    }
+}
+
+impl Operand{
+    /// Resolves an argument location which holds exactly a 32 bit value
+    pub(crate) fn new(location: ArgLocation) -> Option<Operand>{
+        match location{
+            ArgLocation::RegisterValue(r, ValueSize::Dword) => Some(Operand::Reg(r as usize)),
+            ArgLocation::Immediate(SizedValue::Dword(v)) => Some(Operand::Imm(v)),
+            ArgLocation::Address(_, ValueSize::Dword) | ArgLocation::RegisterAddress(_, ValueSize::Dword)
+                | ArgLocation::ModRMAddress{size: ValueSize::Dword, ..} | ArgLocation::SIBAddress{size: ValueSize::Dword, ..} => {
+                Some(Operand::Mem(EffectiveAddress::new(location)?))
+            },
+            _ => None
+        }
+    }
+    /// Resolves a source argument location, sign extending smaller immediates as the 32 bit arithmetic opcodes do
+    pub(crate) fn source(location: ArgLocation) -> Option<Operand>{
+        match location{
+            ArgLocation::Immediate(v) => Some(Operand::Imm(v.u32_sx().ok()?)),
+            _ => Operand::new(location)
+        }
+    }
+    fn get(&self, vm: &VM) -> Result<u32, VMError>{
+        Ok(match self{
+            Operand::Reg(r) => vm.regs[*r],
+            Operand::Imm(v) => *v,
+            Operand::Mem(a) => vm.get_mem(a.resolve(vm), ValueSize::Dword)?.u32_exact()?
+        })
+    }
+}
+
+/// Translates an opcode into a closure specialized for its operands, if it is one of the common 32 bit opcodes which this is done for
+fn specialize(p: &Pipeline) -> Option<ThreadedFn>{
+    if p.size_override{
+        return None;
+    }
+    let (arg0, arg1) = (p.args[0].location, p.args[1].location);
+    match p.kind{
+        OpcodeKind::Mov => move_dword(Operand::new(arg0)?, Operand::new(arg1)?),
+        OpcodeKind::Add => arithmetic(add32, true, Operand::new(arg0)?, Operand::source(arg1)?),
+        OpcodeKind::Sub => arithmetic(sub32, true, Operand::new(arg0)?, Operand::source(arg1)?),
+        OpcodeKind::Cmp => arithmetic(sub32, false, Operand::new(arg0)?, Operand::source(arg1)?),
+        OpcodeKind::And => arithmetic(and32, true, Operand::new(arg0)?, Operand::source(arg1)?),
+        OpcodeKind::Or => arithmetic(or32, true, Operand::new(arg0)?, Operand::source(arg1)?),
+        OpcodeKind::Xor => arithmetic(xor32, true, Operand::new(arg0)?, Operand::source(arg1)?),
+        OpcodeKind::Increment => unary(increment32, Operand::new(arg0)?),
+        OpcodeKind::Decrement => unary(decrement32, Operand::new(arg0)?),
+        OpcodeKind::Lea => {
+            match Operand::new(arg0)?{
+                Operand::Reg(d) => {
+                    let a = EffectiveAddress::new(arg1)?;
+                    Some(Box::new(move |vm, _| {
+                        vm.regs[d] = a.resolve(vm);
+                        Ok(())
+                    }))
+                },
+                _ => None
+            }
+        },
+        OpcodeKind::Push => {
+            let s = Operand::new(arg0)?;
+            Some(Box::new(move |vm, _| {
+                let v = s.get(vm)?;
+                let esp = vm.regs[Reg32::ESP as usize].wrapping_sub(4);
+                vm.regs[Reg32::ESP as usize] = esp;
+                vm.set_mem(esp, SizedValue::Dword(v))
+            }))
+        },
+        OpcodeKind::Pop => {
+            match Operand::new(arg0)?{
+                Operand::Reg(d) => Some(Box::new(move |vm, _| {
+                    let esp = vm.regs[Reg32::ESP as usize];
+                    vm.regs[Reg32::ESP as usize] = esp.wrapping_add(4);
+                    vm.regs[d] = vm.get_mem(esp, ValueSize::Dword)?.u32_exact()?;
+                    Ok(())
+                })),
+                _ => None
+            }
+        },
+        OpcodeKind::Jcc | OpcodeKind::JmpRel => {
+            //the target is relative to the end of the opcode, and eip_size is added after the opcode executes
+            let rel = match arg0{
+                ArgLocation::Immediate(v) => v.u32_sx().ok()?,
+                _ => return None
+            };
+            if p.kind == OpcodeKind::JmpRel{
+                return Some(Box::new(move |vm, _| {
+                    vm.eip = vm.eip.wrapping_add(rel);
+                    Ok(())
+                }));
+            }
+            let cc = p.opcode;
+            Some(Box::new(move |vm, _| {
+                if cc_matches(cc, &vm.flags){
+                    vm.eip = vm.eip.wrapping_add(rel);
+                }
+                Ok(())
+            }))
+        },
+        _ => None
+    }
+}
+
+fn move_dword(d: Operand, s: Operand) -> Option<ThreadedFn>{
+    use Operand::*;
+    Some(match (d, s){
+        (Reg(d), Reg(s)) => Box::new(move |vm, _| {
+            vm.regs[d] = vm.regs[s];
+            Ok(())
+        }),
+        (Reg(d), Imm(v)) => Box::new(move |vm, _| {
+            vm.regs[d] = v;
+            Ok(())
+        }),
+        (Reg(d), s) => Box::new(move |vm, _| {
+            vm.regs[d] = s.get(vm)?;
+            Ok(())
+        }),
+        (Mem(a), s) => Box::new(move |vm, _| {
+            let v = s.get(vm)?;
+            vm.set_mem(a.resolve(vm), SizedValue::Dword(v))
+        }),
+        _ => return None
+    })
+}
+
+/// Translates a 32 bit arithmetic opcode. `write` is false for opcodes which only set flags, such as `cmp`
+fn arithmetic<F>(f: F, write: bool, d: Operand, s: Operand) -> Option<ThreadedFn>
+where F: Fn(&mut VM, u32, u32) -> u32 + Send + Sync + 'static{
+    use Operand::*;
+    Some(match (d, s){
+        (Reg(d), Reg(s)) => Box::new(move |vm, _| {
+            let (base, v) = (vm.regs[d], vm.regs[s]);
+            let result = f(vm, base, v);
+            if write{
+                vm.regs[d] = result;
+            }
+            Ok(())
+        }),
+        (Reg(d), Imm(v)) => Box::new(move |vm, _| {
+            let base = vm.regs[d];
+            let result = f(vm, base, v);
+            if write{
+                vm.regs[d] = result;
+            }
+            Ok(())
+        }),
+        (Reg(d), s) => Box::new(move |vm, _| {
+            let base = vm.regs[d];
+            let v = s.get(vm)?;
+            let result = f(vm, base, v);
+            if write{
+                vm.regs[d] = result;
+            }
+            Ok(())
+        }),
+        (Mem(a), s) => Box::new(move |vm, _| {
+            let address = a.resolve(vm);
+            let base = vm.get_mem(address, ValueSize::Dword)?.u32_exact()?;
+            let v = s.get(vm)?;
+            let result = f(vm, base, v);
+            if write{
+                vm.set_mem(address, SizedValue::Dword(result))?;
+            }
+            Ok(())
+        }),
+        _ => return None
+    })
+}
+
+/// Translates a 32 bit opcode with a single operand which is both read and written, such as `inc`
+fn unary<F>(f: F, d: Operand) -> Option<ThreadedFn>
+where F: Fn(&mut VM, u32) -> u32 + Send + Sync + 'static{
+    Some(match d{
+        Operand::Reg(d) => Box::new(move |vm, _| {
+            let base = vm.regs[d];
+            vm.regs[d] = f(vm, base);
+            Ok(())
+        }),
+        Operand::Mem(a) => Box::new(move |vm, _| {
+            let address = a.resolve(vm);
+            let base = vm.get_mem(address, ValueSize::Dword)?.u32_exact()?;
+            let result = f(vm, base);
+            vm.set_mem(address, SizedValue::Dword(result))
+        }),
+        Operand::Imm(_) => return None
+    })
+}
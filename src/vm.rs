@@ -4,6 +4,13 @@ use crate::structs::*;
 use crate::memory::*;
 use crate::flags::*;
 use crate::decoding::*;
+#[cfg(feature = "x87")]
+use crate::x87::FpuState;
+#[cfg(feature = "debugger")]
+use crate::debugger::*;
+use crate::threaded::*;
+use std::cell::RefCell;
+use std::fmt;
 
 #[allow(dead_code)] //remove after design stuff is done
 
@@ -19,18 +26,58 @@ pub struct VM{
     pub flags: X86Flags,
     /// The memory of the VM, controlled by the MemorySystem struct
     pub memory: MemorySystem,
-    /// set to the EIP value when an error has occurred
-    pub error_eip: u32,
     /// The amount of gas remaining for execution
     pub gas_remaining: u64,
+    /// The amount of gas remaining when execution began. This is used by RDTSC to compute the gas used.
+    /// When execution is resumed this is instead adjusted so that gas added while stopped is not counted
+    pub gas_at_start: u64,
+    /// The amount of gas remaining when execution last stopped in a way which can be resumed
+    resume_gas: Option<u64>,
     /// The struct which determines how the GasCost tiers resolve into actual numbers
     pub charger: GasCharger,
+    /// The leaves returned by the CPUID opcode. This can be changed to advertise a different set of features
+    pub cpuid: CpuidTable,
+    /// The opcode table used for decoding. This can be changed to add custom opcodes
+    pub opcodes: OpcodeTable,
+    /// The x87 FPU register stack, control word, and status word
+    #[cfg(feature = "x87")]
+    pub fpu: FpuState,
+    /// The 128 bit XMM0 through XMM7 registers
+    #[cfg(feature = "sse2")]
+    pub xmm: [u128; 8],
+    /// The breakpoints and memory watchpoints
+    #[cfg(feature = "debugger")]
+    pub debugger: Debugger,
+    /// Disables fusing common pairs of opcodes, such as `cmp` followed by `jcc`, into a single pipeline slot.
+    /// Fusion does not change the result of execution, so this is only useful for testing
+    pub disable_fusion: bool,
+    /// Controls when read only code is translated into threaded code by `execute`.
+    /// Translation does not change the result of execution, only how quickly it is reached
+    pub translation: Translation,
+    /// Disables compiling translated blocks into native code.
+    /// Like translation, this does not change the result of execution
+    #[cfg(feature = "jit")]
+    pub disable_jit: bool,
+    /// The EIP of the opcode which caused execution to stop, when EIP has already been advanced past it
+    pub(crate) fault_eip: Option<u32>,
+    /// The memory access which caused execution to stop, when it can not be determined from the error alone
+    pub(crate) fault_access: Option<MemoryAccess>,
+    /// Previously decoded opcodes within read only memory
+    pipeline_cache: RefCell<PipelineCache>,
+    /// Previously translated blocks of read only memory
+    threaded_cache: RefCell<ThreadedCache>,
 }
 
 /// Implements an interface for the program within the VM to talk to the external world
 pub trait Hypervisor{
     /// Executed whenever an INT opcode occurs 
+    /// Returning a Yield error stops execution after the INT opcode, so that it can later be resumed by calling `execute` again
     fn interrupt(&mut self, _vm: &mut VM, _num: u8) -> Result<(), VMError>;
+    /// Executed whenever a UD2 opcode occurs, with EIP pointing to the UD2 opcode
+    /// Returning Ok will resume execution after the UD2. By default execution stops with a Trap error
+    fn trap(&mut self, vm: &mut VM) -> Result<(), VMError>{
+        Err(VMError::Trap(vm.eip))
+    }
 }
 
 /// The gas cost of an operation
@@ -46,6 +93,12 @@ pub enum GasCost{
     Moderate,
     /// This operation is of high cost. Used for very complex or slow operations
     High,
+    /// A floating point operation which only moves values, such as `fld` and `fxch`
+    FloatLow,
+    /// A floating point operation which requires rounding, such as `fadd` and `fistp`
+    FloatModerate,
+    /// A slow floating point operation, such as `fdiv`
+    FloatHigh,
 
     //surcharges (not intended to direct use outside of VM)
 
@@ -87,6 +140,9 @@ impl GasCharger{
         g.costs[Low as usize] = 4;
         g.costs[Moderate as usize] = 10;
         g.costs[High as usize] = 20;
+        g.costs[FloatLow as usize] = 2;
+        g.costs[FloatModerate as usize] = 8;
+        g.costs[FloatHigh as usize] = 30;
         g.costs[ConditionalBranch as usize] = 10;
         g.costs[MemoryAccess as usize] = 1;
         g.costs[WriteableMemoryExec as usize] = 15;
@@ -95,6 +151,112 @@ impl GasCharger{
     }
 }
 
+/// The ABI version of qx86 reported in EAX by CPUID leaf 0x40000001
+pub const QX86_ABI_VERSION: u32 = 1;
+
+/// CPUID leaf 1 EDX bit indicating an x87 FPU is present. This is only set when the `x87` feature is enabled
+pub const CPUID_EDX_FPU: u32 = 1 << 0;
+/// CPUID leaf 1 EDX bit indicating RDTSC is supported
+pub const CPUID_EDX_TSC: u32 = 1 << 4;
+/// CPUID leaf 1 EDX bit indicating CMPXCHG8B is supported
+pub const CPUID_EDX_CX8: u32 = 1 << 8;
+/// CPUID leaf 1 EDX bit indicating CMOVcc is supported
+pub const CPUID_EDX_CMOV: u32 = 1 << 15;
+/// CPUID leaf 1 ECX bit indicating MOVBE is supported. This is only set when the `bitops` feature is enabled
+pub const CPUID_ECX_MOVBE: u32 = 1 << 22;
+/// CPUID leaf 1 ECX bit indicating POPCNT is supported. This is only set when the `bitops` feature is enabled
+pub const CPUID_ECX_POPCNT: u32 = 1 << 23;
+/// CPUID leaf 0x80000001 ECX bit indicating LZCNT is supported. This is only set when the `bitops` feature is enabled
+pub const CPUID_EXT_ECX_LZCNT: u32 = 1 << 5;
+/// CPUID leaf 1 ECX bit indicating execution is within a hypervisor, and so leaves 0x40000000 and up are valid
+pub const CPUID_ECX_HYPERVISOR: u32 = 1 << 31;
+
+/// The values loaded into EAX, EBX, ECX, and EDX by the CPUID opcode for a single leaf
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub struct CpuidLeaf{
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32
+}
+
+impl CpuidLeaf{
+    /// Creates a leaf in the vendor string format of leaf 0 and leaf 0x40000000
+    /// The vendor string is encoded in EBX, EDX, ECX order, with EAX being the highest supported leaf in the range
+    pub fn vendor(max_leaf: u32, vendor: &[u8; 12]) -> CpuidLeaf{
+        let part = |n: usize| u32::from_le_bytes([vendor[n], vendor[n + 1], vendor[n + 2], vendor[n + 3]]);
+        CpuidLeaf{
+            eax: max_leaf,
+            ebx: part(0),
+            edx: part(4),
+            ecx: part(8)
+        }
+    }
+}
+
+/// The table of leaves used by the CPUID opcode, selected by the value of EAX
+/// Any leaf which is not in the table will return 0 in all registers
+#[derive(Debug, Clone)]
+pub struct CpuidTable{
+    leaves: Vec<(u32, CpuidLeaf)>
+}
+
+impl CpuidTable{
+    /// Creates a table with no leaves present
+    pub fn empty() -> CpuidTable{
+        CpuidTable{
+            leaves: vec![]
+        }
+    }
+    /// Retrieves the specified leaf from the table
+    pub fn get(&self, leaf: u32) -> CpuidLeaf{
+        for (n, v) in &self.leaves{
+            if *n == leaf{
+                return *v;
+            }
+        }
+        CpuidLeaf::default()
+    }
+    /// Sets the specified leaf in the table, replacing it if it already exists
+    pub fn set(&mut self, leaf: u32, value: CpuidLeaf){
+        for (n, v) in self.leaves.iter_mut(){
+            if *n == leaf{
+                *v = value;
+                return;
+            }
+        }
+        self.leaves.push((leaf, value));
+    }
+}
+
+impl Default for CpuidTable{
+    /// The default table describes the features implemented by this VM
+    fn default() -> CpuidTable{
+        let mut t = CpuidTable::empty();
+        t.set(0, CpuidLeaf::vendor(1, b"Qtum-x86 VM "));
+        t.set(1, CpuidLeaf{
+            ecx: CPUID_ECX_HYPERVISOR | if cfg!(feature = "bitops") { CPUID_ECX_MOVBE | CPUID_ECX_POPCNT } else { 0 },
+            edx: CPUID_EDX_TSC | CPUID_EDX_CX8 | CPUID_EDX_CMOV | if cfg!(feature = "x87") { CPUID_EDX_FPU } else { 0 },
+            ..Default::default()
+        });
+        if cfg!(feature = "bitops"){
+            t.set(0x80000000, CpuidLeaf{
+                eax: 0x80000001,
+                ..Default::default()
+            });
+            t.set(0x80000001, CpuidLeaf{
+                ecx: CPUID_EXT_ECX_LZCNT,
+                ..Default::default()
+            });
+        }
+        t.set(0x40000000, CpuidLeaf::vendor(0x40000001, b"Qtum-x86 VM "));
+        t.set(0x40000001, CpuidLeaf{
+            eax: QX86_ABI_VERSION,
+            ..Default::default()
+        });
+        t
+    }
+}
 
 /// The 32 bit x86 registers, encoded in the respective order for how the registers are encoded into opcodes and their arguments
 #[derive(PartialEq)]
@@ -171,6 +333,8 @@ pub enum VMError{
 
     /// Indicates that execution of an invalid opcode was attempted. The u8 attached is the primary opcode byte of the opcode
     InvalidOpcode(u8),
+    /// Indicates that the UD2 opcode was intentionally executed (such as by an abort). The u32 attached is the EIP of the UD2 opcode
+    Trap(u32),
     /// This is thrown when writing (ie, using set_arg) a read-only argument is attempted.
     /// This can be triggered for instance by using set_arg on an argument which has a location of ImmediateValue
     WroteUnwriteableArgument,
@@ -194,22 +358,157 @@ pub enum VMError{
     /// Used by the Hypervisor in order to stop execution in the case of an unrecoverable error
     SyscallError,
     /// This indicates that the execution reached the end of its' gas limit
-    /// This is not an actual execution error per-se, and resuming afterwards is possible if desired.
+    /// This is not an actual execution error per-se, and `execute` returns it as Termination::OutOfGas
     OutOfGas,
     /// Indicates that an opcode is invalidly encoded, for instance, a Mod R/M memory argument having a register encoded
-    InvalidOpcodeEncoding
+    InvalidOpcodeEncoding,
+    /// Indicates that execution reached a breakpoint. The u32 attached is the EIP of the breakpoint.
+    /// This is not an actual execution error, and `execute` returns it as Termination::Breakpoint
+    Breakpoint(u32),
+    /// Indicates that an opcode accessed memory covered by a watchpoint. The u32 attached is the address of the access.
+    /// This is not an actual execution error, and `execute` returns it as Termination::Watchpoint
+    Watchpoint(u32),
+    /// Returned by a Hypervisor callback to pause execution and return to the host after the current opcode.
+    /// This is not an actual execution error, and `execute` returns it as Termination::Yielded
+    Yield
+}
+
+/// The broad categories of VMError, for deciding how an error should be handled without matching every variant
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum ErrorCategory{
+    /// The program within the VM did something invalid, such as executing an invalid opcode or accessing memory which does not exist.
+    /// This is deterministic, so the same program and state will always fault in the same way
+    GuestFault,
+    /// The VM was set up or controlled incorrectly by the host, such as adding conflicting memory, or a Hypervisor returning an error
+    HostError,
+    /// An invariant within the VM itself was broken. This should never happen in a bug free VM
+    InternalBug,
+    /// Not an actual error, but a way for execution to stop early, such as running out of gas or reaching a breakpoint
+    ControlFlow
+}
+
+impl VMError{
+    /// Retrieves the category of this error
+    pub fn category(&self) -> ErrorCategory{
+        use VMError::*;
+        match self{
+            DivideByZero | ReadBadMemory(_) | WroteBadMemory(_) | WroteReadOnlyMemory(_) | ReadUnloadedMemory(_) |
+            InvalidOpcode(_) | Trap(_) | DecodingOverrun | InvalidOpcodeEncoding | NotYetImplemented => ErrorCategory::GuestFault,
+            UnalignedMemoryAddition | ConflictingMemoryAddition | SyscallError => ErrorCategory::HostError,
+            None | WroteUnwriteableArgument | WrongSizeExpectation | TooBigSizeExpectation => ErrorCategory::InternalBug,
+            InternalVMStop | OutOfGas | Breakpoint(_) | Watchpoint(_) | Yield => ErrorCategory::ControlFlow
+        }
+    }
+}
+
+impl std::error::Error for VMError{}
+
+/// The reason execution stopped without an error, returned by `execute`, `step`, and `run_for`
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Termination{
+    /// The `hlt` instruction was executed
+    Halted,
+    /// `step` or `run_for` executed all of the requested instructions
+    Stepped,
+    /// There was not enough gas for the next opcode. EIP is left pointing to it and no gas is deducted for it.
+    /// After adding to gas_remaining, calling `execute` again gives the same result as if there had been enough gas to begin with
+    OutOfGas,
+    /// A Hypervisor callback returned a Yield error. EIP is left pointing to the next opcode
+    Yielded,
+    /// Execution reached a breakpoint. The u32 attached is the EIP of the breakpoint, which is left unexecuted
+    Breakpoint(u32),
+    /// An opcode accessed memory covered by a watchpoint. EIP is left pointing to the next opcode
+    Watchpoint{
+        /// The EIP of the opcode which accessed the memory
+        eip: u32,
+        access: MemoryAccess
+    }
+}
+
+impl Termination{
+    /// Determines if execution can be resumed by calling `execute` again, rather than having finished
+    pub fn resumable(&self) -> bool{
+        *self != Termination::Halted
+    }
+}
+
+/// The kind of memory access which caused a fault
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum AccessKind{
+    Read,
+    Write,
+    /// Decoding an opcode from memory
+    Execute
+}
+
+/// A memory access which caused a fault
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct MemoryAccess{
+    /// The address which could not be accessed, or which is covered by a watchpoint
+    pub address: u32,
+    pub kind: AccessKind
+}
+
+/// The decoded form of the opcode which caused a fault
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct DecodedOpcode{
+    /// The final opcode byte in the total opcode
+    pub opcode: u8,
+    /// The decoded arguments. Unused arguments have a location of None
+    pub args: [ArgLocation; MAX_ARGS],
+    /// Set to true if an operand size override prefix is present
+    pub size_override: bool,
+    /// The gas cost of the opcode
+    pub gas_cost: u64
+}
+
+/// The report returned by `execute`, `step`, and `run_for` when execution stops with an error.
+/// The error is never in the ControlFlow category, since those are returned as a Termination instead
+#[derive(PartialEq, Debug, Clone)]
+pub struct Fault{
+    pub error: VMError,
+    /// The EIP of the opcode which caused the error. This is the same as the EIP of the VM unless the opcode had already completed,
+    /// as for Yield and Watchpoint
+    pub eip: u32,
+    /// The raw bytes of the opcode. If it could not be decoded then this is instead every byte which could be read for decoding it
+    pub bytes: Vec<u8>,
+    /// The decoded opcode, or None if it could not be decoded
+    pub opcode: Option<Box<DecodedOpcode>>,
+    /// The memory access which caused the error, if any
+    pub access: Option<MemoryAccess>,
+    /// The gas remaining when execution stopped
+    pub gas_remaining: u64
+}
+
+impl Fault{
+    /// Retrieves the category of the error
+    pub fn category(&self) -> ErrorCategory{
+        self.error.category()
+    }
+}
+
+impl fmt::Display for Fault{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at EIP 0x{:08X}", self.error, self.eip)
+    }
+}
+
+impl std::error::Error for Fault{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>{
+        Some(&self.error)
+    }
 }
 
 
 impl VM{
     pub fn pop16(&mut self) -> Result<SizedValue, VMError> {
         let esp = self.regs[Reg32::ESP as usize];
-        self.regs[Reg32::ESP as usize] += 2;
+        self.regs[Reg32::ESP as usize] = self.regs[Reg32::ESP as usize].wrapping_add(2);
         return self.get_mem(esp, ValueSize::Word)
     }
     pub fn pop32(&mut self) -> Result<SizedValue, VMError> {
         let esp = self.regs[Reg32::ESP as usize];
-        self.regs[Reg32::ESP as usize] += 4;
+        self.regs[Reg32::ESP as usize] = self.regs[Reg32::ESP as usize].wrapping_add(4);
         return self.get_mem(esp, ValueSize::Dword)
     }
     pub fn push_stack(&mut self, val: SizedValue, pipeline: &Pipeline) -> Result<(), VMError> {
@@ -222,9 +521,9 @@ impl VM{
         };
         Ok(())
     }
-    fn calculate_modrm_address(&self, arg: &ArgLocation) -> u32{
+    fn calculate_modrm_address(&self, arg: &ArgLocation) -> Result<u32, VMError>{
         use ArgLocation::*;
-        match arg{
+        Ok(match arg{
             ModRMAddress{offset, reg, size: _} => {
                 let o = match offset{
                     Some(x) => *x,
@@ -249,8 +548,21 @@ impl VM{
                 let address = b.wrapping_add(ind.wrapping_mul(*scale as u32)).wrapping_add(*offset);
                 address
             },
-            _ => panic!("This should not be reached")
-        }
+            ModRMAddress16{offset, reg1, reg2, size: _} => {
+                let o = offset.unwrap_or(0);
+                let r1 = match reg1{
+                    Some(x) => (self.regs[*x as usize] & 0xFFFF) as u16,
+                    Option::None => 0
+                };
+                let r2 = match reg2{
+                    Some(x) => (self.regs[*x as usize] & 0xFFFF) as u16,
+                    Option::None => 0
+                };
+                //16 bit addresses wrap around at 64Kb
+                o.wrapping_add(r1).wrapping_add(r2) as u32
+            },
+            _ => return Err(VMError::InvalidOpcodeEncoding)
+        })
     }
     /// Resolves an argument location into a SizedValue 
     pub fn get_arg(&self, arg: ArgLocation) -> Result<SizedValue, VMError>{
@@ -267,15 +579,15 @@ impl VM{
             RegisterAddress(r, s) => {
                 self.get_mem(self.get_reg(r, ValueSize::Dword).u32_exact()?, s)?
             },
-            /*
-            ModRMAddress16{offset, reg1, reg2, size} => {
-                SizedValue::None
-            },*/
+            ModRMAddress16{offset: _, reg1: _, reg2: _, size} => {
+                //this will always fail since memory below 0x10000 is inaccessible
+                self.get_mem(self.calculate_modrm_address(&arg)?, size)?
+            },
             ModRMAddress{offset: _, reg: _, size} => {
-                self.get_mem(self.calculate_modrm_address(&arg), size)?
+                self.get_mem(self.calculate_modrm_address(&arg)?, size)?
             },
             SIBAddress{offset: _, base: _, scale: _, index: _, size} => {
-                self.get_mem(self.calculate_modrm_address(&arg), size)?
+                self.get_mem(self.calculate_modrm_address(&arg)?, size)?
             }
         })
     }
@@ -288,15 +600,14 @@ impl VM{
             RegisterAddress(r, _) => {
                 self.get_reg(r, ValueSize::Dword).u32_exact()?
             },
-            /*
-            ModRMAddress16{offset, reg1, reg2, size} => {
-                SizedValue::None
-            },*/
+            ModRMAddress16{offset: _, reg1: _, reg2: _, size: _} => {
+                self.calculate_modrm_address(&arg)?
+            },
             ModRMAddress{offset: _, reg: _, size: _} => {
-                self.calculate_modrm_address(&arg)
+                self.calculate_modrm_address(&arg)?
             },
             SIBAddress{offset: _, base: _, scale: _, index: _, size: _} => {
-                self.calculate_modrm_address(&arg)
+                self.calculate_modrm_address(&arg)?
             }
             _ => return Err(VMError::InvalidOpcodeEncoding)
         })
@@ -318,18 +629,32 @@ impl VM{
             RegisterAddress(r, s) => {
                 self.set_mem(self.get_reg(r, ValueSize::Dword).u32_exact()?, v.convert_size_zx(s)?)?
             },
+            ModRMAddress16{offset: _, reg1: _, reg2: _, size} => {
+                let sized = v.convert_size_trunc(size);
+                self.set_mem(self.calculate_modrm_address(&arg)?, sized)?
+            },
             ModRMAddress{offset: _, reg: _, size} => {
                 let sized = v.convert_size_trunc(size);
-                self.set_mem(self.calculate_modrm_address(&arg), sized)?
+                self.set_mem(self.calculate_modrm_address(&arg)?, sized)?
             },
             SIBAddress{offset: _, base: _, scale: _, index: _, size} => {
                 let sized = v.convert_size_trunc(size);
-                self.set_mem(self.calculate_modrm_address(&arg), sized)?
+                self.set_mem(self.calculate_modrm_address(&arg)?, sized)?
             }
         };
 
         Ok(())
     }
+    /// Resolves a numerical 8 bit register index, where 4 through 7 are the high bytes of the first four registers
+    fn get_reg8(&self, reg: u8) -> u8{
+        if reg & 0x04 == 0{
+            //access lows, AL, CL, DL, BL
+            (self.regs[reg as usize] & 0xFF) as u8
+        }else{
+            //access highs, AH, CH, DH, BH
+            ((self.regs[(reg & 0x03) as usize] & 0xFF00) >> 8) as u8
+        }
+    }
     /// Resolves a numerical register index and ValueSize to a SizedValue indicating the value of that particular register
     pub fn get_reg(&self, reg: u8, size: ValueSize) -> SizedValue{
         use ValueSize::*;
@@ -337,13 +662,7 @@ impl VM{
         match size{
             ValueSize::None => SizedValue::None,
             Byte => {
-                if reg & 0x04 == 0{
-                    //access lows, AL, CL, DL, BL
-                    SizedValue::Byte((self.regs[r] & 0xFF) as u8)
-                }else{
-                    //access highs, AH, CH, DH, BH
-                    SizedValue::Byte(((self.regs[r & 0x03] & 0xFF00) >> 8) as u8)
-                }
+                SizedValue::Byte(self.get_reg8(reg))
             },
             Word => {
                 SizedValue::Word((self.regs[r] & 0xFFFF) as u16)
@@ -351,7 +670,11 @@ impl VM{
             Dword => {
                 SizedValue::Dword(self.regs[r])
             },
-            Qword => SizedValue::None // we aren't doing registers with quad words yet no need to implement this
+            Qword => SizedValue::None, // we aren't doing registers with quad words yet no need to implement this
+            #[cfg(feature = "sse2")]
+            Dqword => SizedValue::Dqword(self.xmm[r]),
+            #[cfg(not(feature = "sse2"))]
+            Dqword => SizedValue::None // there are no XMM registers without sse2
         }
     }
     /// Resolves a numerical register index and using the size of the SizedValue determiens which 
@@ -362,6 +685,12 @@ impl VM{
         match value{
             SizedValue::None => (), //could potentially throw an error here?
             SizedValue::Qword(_v) => (), //same for here too
+            #[cfg(feature = "sse2")]
+            Dqword(v) => {
+                self.xmm[r] = v;
+            },
+            #[cfg(not(feature = "sse2"))]
+            Dqword(_v) => (), //there are no XMM registers without sse2
             Byte(v) => {
                 if reg & 0x04 == 0{
                     //access lows, AL, CL, DL, BL
@@ -382,6 +711,8 @@ impl VM{
     /// Retreives a SizedValue from VM memory which matches the specified ValueSize
     pub fn get_mem(&self, address: u32, size: ValueSize) -> Result<SizedValue, VMError>{
         use ValueSize::*;
+        #[cfg(feature = "debugger")]
+        self.debugger.watch(address, size_in_bytes(size), false);
         match size{
             None => Ok(SizedValue::None),
             Byte => {
@@ -395,6 +726,9 @@ impl VM{
             },
             Qword => {
                 Ok(SizedValue::Qword(self.memory.get_u64(address)?))
+            },
+            Dqword => {
+                Ok(SizedValue::Dqword(self.memory.get_u128(address)?))
             }
         }
     }
@@ -405,6 +739,8 @@ impl VM{
             //in read-only memory
             return Err(VMError::WroteReadOnlyMemory(address));
         }
+        #[cfg(feature = "debugger")]
+        self.debugger.watch(address, value_size_in_bytes(&value), true);
         match value{
             None => (),
             Byte(v) => {
@@ -418,6 +754,9 @@ impl VM{
             },
             Qword(v) => {
                 self.memory.set_u64(address, v)?;
+            },
+            Dqword(v) => {
+                self.memory.set_u128(address, v)?;
             }
         };
         Ok(())
@@ -427,44 +766,180 @@ impl VM{
     /// A cycle includes filling the pipeline, executing the filled pipeline, and then handling any errors present
     /// Will return a result of true if an InternalVMStop was received, otherwise will return false
     fn cycle(&mut self, pipeline: &mut [Pipeline], hv: &mut dyn Hypervisor) -> Result<bool, VMError>{
-        fill_pipeline(self, &OPCODES[0..], pipeline)?;
+        #[cfg(feature = "debugger")]
+        self.debugger.take_hit();
+        if let Err(e) = fill_pipeline_cached(self, &mut self.pipeline_cache.borrow_mut(), self.opcodes.table(), pipeline){
+            self.fault_access = match e{
+                VMError::ReadBadMemory(address) | VMError::ReadUnloadedMemory(address) => Some(MemoryAccess{
+                    address,
+                    kind: AccessKind::Execute
+                }),
+                _ => Option::None
+            };
+            return Err(e);
+        }
+        #[cfg(feature = "debugger")]
+        self.debugger.resumed();
         //manually unroll loop later if needed?
         for n in 0..pipeline.len() {
             let p = &pipeline[n];
-            let (_, negative_gas) = self.gas_remaining.overflowing_sub(p.gas_cost);
-            self.gas_remaining = self.gas_remaining.saturating_sub(p.gas_cost);
-
             //the pipeline will not be filled beyond out of gas, so no worries about inconsistent errored state here
             //micro optimization note: removing this branch results in ~1% performance increase in naive tests
             //but changes the result of EIP to be incorrect.. Decide later if inaccurate EIP is worth that 1%
-            if negative_gas {
+            if p.gas_cost > self.gas_remaining {
+                //nothing is deducted so that execution can be resumed after adding more gas
                 return Err(VMError::OutOfGas);
             }
+            self.gas_remaining -= p.gas_cost;
             //errors[n] = (p.function)(self, p);
             let r = (p.function)(self,p, hv);
             if r.is_err(){
                 if r.err().unwrap() == VMError::InternalVMStop{
                     return Ok(true);
+                }else if r.err().unwrap() == VMError::Yield{
+                    //the opcode has completed, so resuming continues from the next opcode
+                    self.fault_eip = Some(self.eip);
+                    self.eip = self.eip.wrapping_add(p.eip_size as u32);
+                    return Err(VMError::Yield);
+                }else if r.err().unwrap() == VMError::OutOfGas{
+                    //from rep or a fused opcode, which leave EIP as if the gas check above had failed, so this is not an error to record either
+                    return Err(VMError::OutOfGas);
                 }else{
-                    self.error_eip = self.eip;
                     return Err(r.err().unwrap());
                 }
             }
+            #[cfg(feature = "debugger")]
+            {
+                if let Some(access) = self.debugger.take_hit(){
+                    self.fault_eip = Some(self.eip);
+                    self.fault_access = Some(access);
+                    self.eip = self.eip.wrapping_add(p.eip_size as u32);
+                    return Err(VMError::Watchpoint(access.address));
+                }
+            }
             self.eip = self.eip.wrapping_add(p.eip_size as u32);
         }
         return Ok(false);
 
     }
-    /// Executes the VM either until there is no remaining gas, an error occurs, or the `hlt` instruction is executed
-    pub fn execute(&mut self, hv: &mut dyn Hypervisor) -> Result<bool, VMError>{
-        //todo: gas handling
+    /// Called before executing. If the previous execution stopped in a way which can be resumed then this continues it,
+    /// otherwise this begins a new execution which RDTSC counts from
+    fn begin_execution(&mut self){
+        self.gas_at_start = match self.resume_gas.take(){
+            //exclude any gas added while stopped from RDTSC
+            Some(gas) => self.gas_at_start.wrapping_add(self.gas_remaining.wrapping_sub(gas)),
+            Option::None => self.gas_remaining
+        };
+    }
+    /// Called after executing to record if execution stopped in a way which can be resumed, and to report the fault if there was an error
+    fn end_execution(&mut self, result: Result<bool, VMError>) -> Result<Termination, Fault>{
+        self.resume_gas = match result{
+            Ok(false) | Err(VMError::OutOfGas) | Err(VMError::Yield) | Err(VMError::Breakpoint(_)) | Err(VMError::Watchpoint(_)) => {
+                Some(self.gas_remaining)
+            },
+            _ => Option::None
+        };
+        let eip = self.fault_eip.take().unwrap_or(self.eip);
+        let access = self.fault_access.take();
+        let error = match result{
+            Ok(true) => return Ok(Termination::Halted),
+            Ok(false) => return Ok(Termination::Stepped),
+            Err(VMError::OutOfGas) => return Ok(Termination::OutOfGas),
+            Err(VMError::Yield) => return Ok(Termination::Yielded),
+            Err(VMError::Breakpoint(address)) => return Ok(Termination::Breakpoint(address)),
+            Err(VMError::Watchpoint(address)) => return Ok(Termination::Watchpoint{
+                eip,
+                //the debugger always records the access along with the error
+                access: access.unwrap_or(MemoryAccess{
+                    address,
+                    kind: AccessKind::Read
+                })
+            }),
+            Err(e) => e
+        };
+        let access = access.or(match error{
+            VMError::ReadBadMemory(address) | VMError::ReadUnloadedMemory(address) => Some(MemoryAccess{
+                address,
+                kind: AccessKind::Read
+            }),
+            VMError::WroteBadMemory(address) | VMError::WroteReadOnlyMemory(address) => Some(MemoryAccess{
+                address,
+                kind: AccessKind::Write
+            }),
+            _ => Option::None
+        });
+        let (bytes, opcode) = match decode_opcode(self, eip){
+            Ok(p) => {
+                let bytes = self.memory.get_sized_memory(eip, p.eip_size as u32).map(|b| b.to_vec()).unwrap_or_default();
+                (bytes, Some(Box::new(DecodedOpcode{
+                    opcode: p.opcode,
+                    args: [p.args[0].location, p.args[1].location, p.args[2].location],
+                    size_override: p.size_override,
+                    gas_cost: p.gas_cost
+                })))
+            },
+            Err(_) => {
+                let bytes = (0..MAX_OPCODE_SIZE).map_while(|i| self.memory.get_u8(eip.wrapping_add(i)).ok()).collect();
+                (bytes, Option::None)
+            }
+        };
+        Err(Fault{
+            error,
+            eip,
+            bytes,
+            opcode,
+            access,
+            gas_remaining: self.gas_remaining
+        })
+    }
+    /// Executes the VM either until there is no remaining gas, an error occurs, or the `hlt` instruction is executed.
+    /// If the previous execution stopped due to OutOfGas, Yield, a breakpoint, or a watchpoint then this resumes it
+    pub fn execute(&mut self, hv: &mut dyn Hypervisor) -> Result<Termination, Fault>{
         let mut pipeline = vec![];
         pipeline.resize(PIPELINE_SIZE, Pipeline::default());
-        loop{
-            if self.cycle(&mut pipeline, hv)? {
-                return Ok(true);
+        self.begin_execution();
+        let result = loop{
+            let r = match self.run_threaded(hv){
+                Some(r) => r,
+                Option::None => self.cycle(&mut pipeline, hv)
+            };
+            match r{
+                Ok(false) => (),
+                r => break r
+            }
+        };
+        self.end_execution(result)
+    }
+    /// Executes the translated block at EIP, if there is one and there is enough gas for all of it.
+    /// Otherwise None is returned and the interpreter must be used
+    fn run_threaded(&mut self, hv: &mut dyn Hypervisor) -> Option<Result<bool, VMError>>{
+        let block = self.threaded_cache.borrow_mut().lookup(self, self.opcodes.table())?;
+        #[cfg(feature = "debugger")]
+        self.debugger.resumed();
+        Some(run_block(self, &block, hv))
+    }
+    /// Executes exactly one instruction using a pipeline of size 1, leaving the VM at the next instruction boundary.
+    /// The result is the same as for `execute`, with Stepped indicating that the instruction was executed without stopping.
+    /// Stepping can be mixed freely with `execute`, and the next call will resume execution rather than begin it again
+    pub fn step(&mut self, hv: &mut dyn Hypervisor) -> Result<Termination, Fault>{
+        let mut pipeline = [Pipeline::default()];
+        self.begin_execution();
+        let result = self.cycle(&mut pipeline, hv);
+        self.end_execution(result)
+    }
+    /// Executes at most `count` instructions using a pipeline of size 1, stopping early if an error occurs or the `hlt` instruction is executed.
+    /// This returns Halted if `hlt` was executed, and Stepped if all `count` instructions were executed
+    pub fn run_for(&mut self, count: u64, hv: &mut dyn Hypervisor) -> Result<Termination, Fault>{
+        let mut pipeline = [Pipeline::default()];
+        self.begin_execution();
+        let mut result = Ok(false);
+        for _ in 0..count{
+            result = self.cycle(&mut pipeline, hv);
+            if result != Ok(false){
+                break;
             }
         }
+        self.end_execution(result)
     }
     /// Helper function to simplify copying a set of data into VM memory
     pub fn copy_into_memory(&mut self, address: u32, data: &[u8]) -> Result<(), VMError>{
@@ -477,13 +952,13 @@ impl VM{
         return Ok(self.memory.get_sized_memory(address, size)?);
     }
     pub fn reg8(&self, r: Reg8) -> u8{
-        self.get_reg(r as u8, ValueSize::Byte).u8_exact().unwrap()
+        self.get_reg8(r as u8)
     }
     pub fn reg16(&self, r: Reg16) -> u16{
-        self.get_reg(r as u8, ValueSize::Word).u16_exact().unwrap()
+        self.regs[r as usize] as u16
     }
     pub fn reg32(&self, r: Reg32) -> u32{
-        self.get_reg(r as u8, ValueSize::Dword).u32_exact().unwrap()
+        self.regs[r as usize]
     }
     pub fn set_reg8(&mut self, r: Reg8, v: u8){
         self.set_reg(r as u8, SizedValue::Byte(v));
@@ -509,7 +984,7 @@ impl VM{
         println!("EIP: 0x{:X?}", self.eip);
         println!("Surrounding bytes in opcode stream:");
         if self.eip >= 0x10000 {
-            for n in std::cmp::max(self.eip - 8, 0x10000)..(self.eip + 8){
+            for n in std::cmp::max(self.eip.saturating_sub(8), 0x10000)..self.eip.saturating_add(8){
                 let tmp = self.get_mem(n, ValueSize::Byte);
                 if tmp.is_err(){
                     println!("error reading memory");
@@ -537,7 +1012,7 @@ impl VM{
 /// 
 /// Somewhere in between 10 and 30 seems to be about right judging from reverse engineered programs, but ultimately
 /// it will require extensive benchmarking to be completely sure about the final value.
-const PIPELINE_SIZE:usize = 16;
+pub(crate) const PIPELINE_SIZE:usize = 16;
 
 #[cfg(test)]
 mod tests{
@@ -687,19 +1162,19 @@ mod tests{
             size: ValueSize::Byte
         };
         vm.regs[Reg32::EBX as usize] = 0x88112233;
-        assert_eq!(vm.calculate_modrm_address(&a), 0x88112233);
+        assert_eq!(vm.calculate_modrm_address(&a).unwrap(), 0x88112233);
         let a = ArgLocation::ModRMAddress{
             offset: Some(0x11223344),
             reg: Some(Reg32::EBX as u8),
             size: ValueSize::Byte
         };
-        assert_eq!(vm.calculate_modrm_address(&a), 0x99335577);
+        assert_eq!(vm.calculate_modrm_address(&a).unwrap(), 0x99335577);
         let a = ArgLocation::ModRMAddress{
             offset: Some(0x11223344),
             reg: None,
             size: ValueSize::Byte
         };
-        assert_eq!(vm.calculate_modrm_address(&a), 0x11223344);
+        assert_eq!(vm.calculate_modrm_address(&a).unwrap(), 0x11223344);
     }
     #[test]
     fn test_sib_address_calculation(){
@@ -717,7 +1192,7 @@ mod tests{
         //(ffddbb98) + 11223344 + 1
         //10ffeddc + 1
         //10ffeddd
-        assert_eq!(vm.calculate_modrm_address(&a), 0x10FFEEDD);
+        assert_eq!(vm.calculate_modrm_address(&a).unwrap(), 0x10FFEEDD);
 
     }
 }
@@ -0,0 +1,424 @@
+use crate::vm::*;
+use crate::pipeline::*;
+use crate::structs::*;
+use crate::opcodes::op_undefined;
+use crate::softfloat;
+use crate::softfloat::RoundingMode;
+
+/*
+x87 implementation notes:
+All arithmetic is done in software (see softfloat.rs) so that results are identical across host platforms.
+This is a deliberately reduced model of the x87 FPU with the following deviations from real hardware:
+
+* Registers hold 64 bit double precision values rather than 80 bit extended precision values.
+  This is equivalent to the precision control field of the control word always being set to double precision
+* Exceptions are always treated as masked. The exception bits of the status word are still set, but never cause a fault
+* The denormal operand exception is never raised
+* Only the opcodes commonly generated by compilers for f32 and f64 code are implemented. Anything else is an invalid opcode
+*/
+
+/// The status word bit set when a stack overflow or underflow occurs
+pub const STATUS_STACK_FAULT: u16 = 1 << 6;
+pub const STATUS_C0: u16 = 1 << 8;
+pub const STATUS_C1: u16 = 1 << 9;
+pub const STATUS_C2: u16 = 1 << 10;
+pub const STATUS_C3: u16 = 1 << 14;
+const STATUS_TOP_SHIFT: u16 = 11;
+/// The bits of the status word which are not set by this VM (ie, exception summary and busy)
+const STATUS_CLEAR_MASK: u16 = 0x8080;
+
+/// The default control word after FNINIT. All exceptions masked, round to nearest
+pub const DEFAULT_CONTROL_WORD: u16 = 0x037F;
+
+/// The state of the x87 FPU register stack
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FpuState{
+    /// The physical registers R0 through R7, stored as the bit pattern of an f64.
+    /// ST(i) refers to R((top + i) % 8)
+    pub regs: [u64; 8],
+    /// Bit n is set when physical register Rn holds a value (ie, its tag is not empty)
+    pub valid: u8,
+    /// The physical register which is currently ST(0)
+    pub top: u8,
+    pub control_word: u16,
+    /// The status word, excluding the TOP field which is tracked in `top`
+    pub status: u16
+}
+
+impl Default for FpuState{
+    fn default() -> FpuState{
+        FpuState{
+            regs: [0; 8],
+            valid: 0,
+            top: 0,
+            control_word: DEFAULT_CONTROL_WORD,
+            status: 0
+        }
+    }
+}
+
+impl FpuState{
+    fn physical(&self, i: u8) -> usize{
+        (self.top.wrapping_add(i) & 7) as usize
+    }
+    /// Retrieves the full status word including the TOP field, as FNSTSW would
+    pub fn status_word(&self) -> u16{
+        (self.status & !(7 << STATUS_TOP_SHIFT)) | ((self.top as u16) << STATUS_TOP_SHIFT)
+    }
+    pub fn rounding_mode(&self) -> RoundingMode{
+        RoundingMode::from_control_word(self.control_word)
+    }
+    /// Sets the specified exception bits in the status word
+    pub fn raise(&mut self, exceptions: u16){
+        self.status |= exceptions;
+    }
+    /// Returns true if ST(i) does not hold a value
+    pub fn is_empty(&self, i: u8) -> bool{
+        self.valid & (1 << self.physical(i)) == 0
+    }
+    /// Retrieves the value of ST(i).
+    /// Reading an empty register is a stack underflow, which results in the indefinite NaN value
+    pub fn st(&mut self, i: u8) -> u64{
+        if self.is_empty(i){
+            self.raise(softfloat::EXCEPTION_INVALID | STATUS_STACK_FAULT);
+            self.status &= !STATUS_C1;
+            return softfloat::DEFAULT_NAN;
+        }
+        self.regs[self.physical(i)]
+    }
+    /// Sets the value of ST(i), marking it as holding a value
+    pub fn set_st(&mut self, i: u8, value: u64){
+        let p = self.physical(i);
+        self.regs[p] = value;
+        self.valid |= 1 << p;
+    }
+    /// Pushes a new value onto the register stack.
+    /// If the new ST(0) already holds a value, this is a stack overflow and the indefinite NaN value is pushed instead
+    pub fn push(&mut self, value: u64){
+        self.top = self.top.wrapping_sub(1) & 7;
+        if self.is_empty(0){
+            self.set_st(0, value);
+        }else{
+            self.raise(softfloat::EXCEPTION_INVALID | STATUS_STACK_FAULT | STATUS_C1);
+            self.set_st(0, softfloat::DEFAULT_NAN);
+        }
+    }
+    /// Marks ST(0) as empty and removes it from the register stack
+    pub fn pop(&mut self){
+        let p = self.physical(0);
+        self.valid &= !(1 << p);
+        self.top = (self.top + 1) & 7;
+    }
+}
+
+/// Retrieves the size of a memory argument, or None for register arguments
+fn memory_size(location: &ArgLocation) -> Option<ValueSize>{
+    use ArgLocation::*;
+    match location{
+        Address(_, s) | RegisterAddress(_, s) => Some(*s),
+        ModRMAddress{offset: _, reg: _, size} => Some(*size),
+        SIBAddress{offset: _, base: _, scale: _, index: _, size} => Some(*size),
+        ModRMAddress16{offset: _, reg1: _, reg2: _, size} => Some(*size),
+        _ => Option::None
+    }
+}
+
+/// Retrieves the register index i for an ST(i) register form of an opcode, or None for memory forms
+fn register_form(pipeline: &Pipeline) -> Option<u8>{
+    match pipeline.args[0].location{
+        ArgLocation::RegisterValue(r, _) => Some(r),
+        _ => Option::None
+    }
+}
+
+/// Loads a floating point value from a memory argument, converting it to the register format if needed
+fn load_float(vm: &mut VM, location: ArgLocation) -> Result<u64, VMError>{
+    let value = vm.get_arg(location)?;
+    let (v, exceptions) = match value{
+        SizedValue::Dword(v) => softfloat::from_f32(v),
+        SizedValue::Qword(v) => softfloat::quiet(v),
+        _ => return Err(VMError::WrongSizeExpectation)
+    };
+    vm.fpu.raise(exceptions);
+    Ok(v)
+}
+
+/// Stores a floating point value to a memory argument, rounding it to the argument's size if needed
+fn store_float(vm: &mut VM, location: ArgLocation, value: u64) -> Result<(), VMError>{
+    let v = match memory_size(&location){
+        Some(ValueSize::Dword) => {
+            let (v, exceptions) = softfloat::to_f32(value, vm.fpu.rounding_mode());
+            vm.fpu.raise(exceptions);
+            SizedValue::Dword(v)
+        },
+        Some(ValueSize::Qword) => SizedValue::Qword(value),
+        _ => return Err(VMError::WrongSizeExpectation)
+    };
+    vm.set_arg(location, v)
+}
+
+/// Shared implementation of the arithmetic opcodes.
+/// With ST(0) as `a` and the other operand as `b`, `op(a, b)` is computed, or `op(b, a)` if reversed.
+/// 0xD8 and the memory forms of 0xDC store the result in ST(0), while the register forms of 0xDC and 0xDE store it in ST(i).
+/// 0xDE additionally pops the register stack
+fn arithmetic(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor, op: fn(u64, u64, RoundingMode) -> softfloat::FloatResult, reverse: bool) -> Result<(), VMError>{
+    let (b, dest) = match register_form(pipeline){
+        Some(r) => {
+            let dest = if pipeline.opcode == 0xD8 { 0 } else { r };
+            (vm.fpu.st(r), dest)
+        },
+        Option::None => {
+            if pipeline.opcode == 0xDE{
+                //integer memory forms are not supported
+                return op_undefined(vm, pipeline, hv);
+            }
+            (load_float(vm, pipeline.args[0].location)?, 0)
+        }
+    };
+    let a = vm.fpu.st(0);
+    let (result, exceptions) = if reverse{
+        op(b, a, vm.fpu.rounding_mode())
+    }else{
+        op(a, b, vm.fpu.rounding_mode())
+    };
+    vm.fpu.raise(exceptions);
+    vm.fpu.set_st(dest, result);
+    if pipeline.opcode == 0xDE{
+        vm.fpu.pop();
+    }
+    Ok(())
+}
+
+/// The logic function for the `fadd` and `faddp` opcodes
+pub fn fadd(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    arithmetic(vm, pipeline, hv, softfloat::add, false)
+}
+
+/// The logic function for the `fmul` and `fmulp` opcodes
+pub fn fmul(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    arithmetic(vm, pipeline, hv, softfloat::mul, false)
+}
+
+/// The logic function for /4 of the arithmetic opcodes, computing ST(0) - operand.
+/// This is `fsub` for 0xD8 and memory forms of 0xDC, but `fsubr` and `fsubrp` for register forms of 0xDC and 0xDE
+pub fn fsub(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    arithmetic(vm, pipeline, hv, softfloat::sub, false)
+}
+
+/// The logic function for /5 of the arithmetic opcodes, computing operand - ST(0)
+pub fn fsubr(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    arithmetic(vm, pipeline, hv, softfloat::sub, true)
+}
+
+/// The logic function for /6 of the arithmetic opcodes, computing ST(0) / operand
+pub fn fdiv(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    arithmetic(vm, pipeline, hv, softfloat::div, false)
+}
+
+/// The logic function for /7 of the arithmetic opcodes, computing operand / ST(0)
+pub fn fdivr(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    arithmetic(vm, pipeline, hv, softfloat::div, true)
+}
+
+/// The logic function for the `fld` opcode, loading either from memory or from ST(i)
+pub fn fld(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let v = match register_form(pipeline){
+        //0xDD C0+i is ffree, which is not supported
+        Some(_) if pipeline.opcode == 0xDD => return op_undefined(vm, pipeline, hv),
+        Some(r) => vm.fpu.st(r),
+        Option::None => load_float(vm, pipeline.args[0].location)?
+    };
+    vm.fpu.push(v);
+    Ok(())
+}
+
+/// The logic function for the `fst` opcode, storing ST(0) to either memory or ST(i)
+pub fn fst(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    match register_form(pipeline){
+        //0xD9 D0 is fnop
+        Some(0) if pipeline.opcode == 0xD9 => Ok(()),
+        Some(_) if pipeline.opcode == 0xD9 => op_undefined(vm, pipeline, hv),
+        Some(r) => {
+            let v = vm.fpu.st(0);
+            vm.fpu.set_st(r, v);
+            Ok(())
+        },
+        Option::None => {
+            let v = vm.fpu.st(0);
+            store_float(vm, pipeline.args[0].location, v)
+        }
+    }
+}
+
+/// The logic function for the `fstp` opcode
+pub fn fstp(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    if pipeline.opcode == 0xD9 && register_form(pipeline).is_some(){
+        return op_undefined(vm, pipeline, hv);
+    }
+    fst(vm, pipeline, hv)?;
+    vm.fpu.pop();
+    Ok(())
+}
+
+/// The logic function for the `fxch` opcode
+pub fn fxch(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let r = match register_form(pipeline){
+        Some(r) => r,
+        Option::None => return op_undefined(vm, pipeline, hv)
+    };
+    let a = vm.fpu.st(0);
+    let b = vm.fpu.st(r);
+    vm.fpu.set_st(0, b);
+    vm.fpu.set_st(r, a);
+    Ok(())
+}
+
+/// The logic function for 0xD9 /4, which is `fchs` (0xD9 E0) or `fabs` (0xD9 E1)
+pub fn fchs_fabs(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let v = vm.fpu.st(0);
+    let result = match register_form(pipeline){
+        Some(0) => v ^ (1 << 63),
+        Some(1) => v & !(1 << 63),
+        _ => return op_undefined(vm, pipeline, hv)
+    };
+    vm.fpu.set_st(0, result);
+    Ok(())
+}
+
+/// The logic function for 0xD9 /5, which is `fldcw m16`
+/// The register forms are the constant loading opcodes, of which `fld1` (0xD9 E8) and `fldz` (0xD9 EE) are supported
+pub fn fldcw(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    match register_form(pipeline){
+        Some(0) => vm.fpu.push(softfloat::ONE),
+        Some(6) => vm.fpu.push(0),
+        Some(_) => return op_undefined(vm, pipeline, hv),
+        Option::None => {
+            //reserved bits are ignored, with bit 6 always reading as set
+            let cw = vm.get_arg(pipeline.args[0].location)?.u16_exact()?;
+            vm.fpu.control_word = (cw & 0x1F3F) | 0x0040;
+        }
+    };
+    Ok(())
+}
+
+/// The logic function for the `fnstcw m16` opcode
+pub fn fnstcw(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    if register_form(pipeline).is_some(){
+        return op_undefined(vm, pipeline, hv);
+    }
+    vm.set_arg(pipeline.args[0].location, SizedValue::Word(vm.fpu.control_word))
+}
+
+/// The logic function for 0xDB /4, which is `fnclex` (0xDB E2) or `fninit` (0xDB E3)
+pub fn fninit(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    match register_form(pipeline){
+        Some(2) => vm.fpu.status &= !(0x3F | STATUS_STACK_FAULT | STATUS_CLEAR_MASK),
+        Some(3) => vm.fpu = FpuState::default(),
+        _ => return op_undefined(vm, pipeline, hv)
+    };
+    Ok(())
+}
+
+/// The logic function for the `fild` opcode, which loads a 16, 32, or 64 bit integer from memory
+pub fn fild(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    if register_form(pipeline).is_some(){
+        return op_undefined(vm, pipeline, hv);
+    }
+    let v = match vm.get_arg(pipeline.args[0].location)?{
+        SizedValue::Word(v) => v as i16 as i64,
+        SizedValue::Dword(v) => v as i32 as i64,
+        SizedValue::Qword(v) => v as i64,
+        _ => return Err(VMError::WrongSizeExpectation)
+    };
+    let (result, exceptions) = softfloat::from_i64(v, vm.fpu.rounding_mode());
+    vm.fpu.raise(exceptions);
+    vm.fpu.push(result);
+    Ok(())
+}
+
+/// The logic function for the `fist` opcode, which stores ST(0) as a 16 or 32 bit integer rounded per the control word
+/// If the value does not fit, the "integer indefinite" value (only the top bit set) is stored
+pub fn fist(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let bits = match memory_size(&pipeline.args[0].location){
+        Some(ValueSize::Word) => 16,
+        Some(ValueSize::Dword) => 32,
+        Some(ValueSize::Qword) => 64,
+        _ => return op_undefined(vm, pipeline, hv)
+    };
+    let v = vm.fpu.st(0);
+    let (result, exceptions) = softfloat::to_int(v, bits, vm.fpu.rounding_mode());
+    vm.fpu.raise(exceptions);
+    let integer = match result{
+        Some(i) => i as u64,
+        Option::None => 1 << (bits - 1)
+    };
+    let sized = match bits{
+        16 => SizedValue::Word(integer as u16),
+        32 => SizedValue::Dword(integer as u32),
+        _ => SizedValue::Qword(integer)
+    };
+    vm.set_arg(pipeline.args[0].location, sized)
+}
+
+/// The logic function for the `fistp` opcode
+pub fn fistp(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    if register_form(pipeline).is_some(){
+        return op_undefined(vm, pipeline, hv);
+    }
+    fist(vm, pipeline, hv)?;
+    vm.fpu.pop();
+    Ok(())
+}
+
+/// Shared implementation of the comparison opcodes which set EFLAGS.
+/// ZF, PF, and CF are set as if by an unsigned integer comparison of ST(0) and ST(i), or all three are set if unordered.
+/// When `ordered` is set (fcomi), any NaN raises an invalid exception rather than only signaling NaNs (fucomi)
+fn compare_eflags(vm: &mut VM, pipeline: &Pipeline, ordered: bool, pop: bool) -> Result<(), VMError>{
+    use std::cmp::Ordering;
+    let r = match register_form(pipeline){
+        Some(r) => r,
+        Option::None => return Err(VMError::InvalidOpcodeEncoding)
+    };
+    let a = vm.fpu.st(0);
+    let b = vm.fpu.st(r);
+    let order = softfloat::compare(a, b);
+    if softfloat::is_signaling_nan(a) || softfloat::is_signaling_nan(b) || (ordered && order.is_none()){
+        vm.fpu.raise(softfloat::EXCEPTION_INVALID);
+    }
+    let (zero, parity, carry) = match order{
+        Some(Ordering::Greater) => (false, false, false),
+        Some(Ordering::Less) => (false, false, true),
+        Some(Ordering::Equal) => (true, false, false),
+        Option::None => (true, true, true)
+    };
+    vm.flags.zero = zero;
+    vm.flags.parity = parity;
+    vm.flags.carry = carry;
+    vm.flags.overflow = false;
+    vm.flags.sign = false;
+    vm.flags.adjust = false;
+    if pop{
+        vm.fpu.pop();
+    }
+    Ok(())
+}
+
+/// The logic function for 0xDB /5 and 0xDF /5.
+/// The register forms are `fucomi` (0xDB E8+i) and `fucomip` (0xDF E8+i). The memory form of 0xDF /5 is `fild m64`
+pub fn fucomi(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    if register_form(pipeline).is_none(){
+        if pipeline.opcode == 0xDF{
+            return fild(vm, pipeline, hv);
+        }
+        return op_undefined(vm, pipeline, hv);
+    }
+    compare_eflags(vm, pipeline, false, pipeline.opcode == 0xDF)
+}
+
+/// The logic function for `fcomi` (0xDB F0+i) and `fcomip` (0xDF F0+i)
+pub fn fcomi(vm: &mut VM, pipeline: &Pipeline, hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    if register_form(pipeline).is_none(){
+        return op_undefined(vm, pipeline, hv);
+    }
+    compare_eflags(vm, pipeline, true, pipeline.opcode == 0xDF)
+}
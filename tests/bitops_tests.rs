@@ -0,0 +1,132 @@
+#![cfg(feature = "bitops")]
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use common::*;
+
+#[test]
+fn test_popcnt(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x80000000
+        mov dword [ebx], 0xF0F0F0F1
+        popcnt eax, [ebx]
+        mov ecx, 0x8001
+        popcnt dx, cx
+        stc
+        popcnt esi, edi ;edi is 0
+        hlt");
+    vm.regs[Reg32::EDX as usize] = 0xFFFF0000;
+    vm.regs[Reg32::ESI as usize] = 0xFFFFFFFF;
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::EAX), 17);
+    assert_eq!(vm.reg32(Reg32::EDX), 0xFFFF0002);
+    assert_eq!(vm.reg32(Reg32::ESI), 0);
+    assert!(vm.flags.zero);
+    assert!(!vm.flags.carry);
+}
+
+#[test]
+fn test_lzcnt_tzcnt(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x80000000
+        mov dword [ebx], 0x00010000
+        lzcnt eax, [ebx]
+        tzcnt ecx, [ebx]
+        mov si, 0x0100
+        lzcnt dx, si
+        setc byte [ebx + 4]
+        tzcnt di, si
+        xor esi, esi
+        lzcnt ebp, esi
+        setc byte [ebx + 5]
+        setz byte [ebx + 6]
+        mov esi, 1
+        tzcnt esi, esi
+        setz byte [ebx + 7]
+        xor esi, esi
+        tzcnt esi, esi
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::EAX), 15);
+    assert_eq!(vm.reg32(Reg32::ECX), 16);
+    assert_eq!(vm.reg16(Reg16::DX), 7);
+    assert_eq!(vm.reg16(Reg16::DI), 8);
+    assert_eq!(vm.reg32(Reg32::EBP), 32);
+    assert_eq!(vm.reg32(Reg32::ESI), 32);
+    assert_eq!(vm.memory.get_u8(DATA_MEM + 4).unwrap(), 0);
+    //a source of 0 sets carry
+    assert_eq!(vm.memory.get_u8(DATA_MEM + 5).unwrap(), 1);
+    assert_eq!(vm.memory.get_u8(DATA_MEM + 6).unwrap(), 0);
+    //a result of 0 sets zero
+    assert_eq!(vm.memory.get_u8(DATA_MEM + 7).unwrap(), 1);
+    assert!(vm.flags.carry);
+}
+
+#[test]
+fn test_movbe(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x80000000
+        mov dword [ebx], 0x11223344
+        movbe eax, [ebx]
+        movbe [ebx + 4], eax
+        mov cx, 0xAABB
+        movbe [ebx + 8], cx
+        movbe dx, [ebx + 8]
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::EAX), 0x44332211);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 4).unwrap(), 0x11223344);
+    assert_eq!(vm.memory.get_u16(DATA_MEM + 8).unwrap(), 0xBBAA);
+    assert_eq!(vm.reg16(Reg16::DX), 0xAABB);
+}
+
+#[test]
+fn test_movbe_register_invalid(){
+    //movbe eax, ecx
+    let mut vm = create_vm_with_asm("
+        db 0x0F, 0x38, 0xF0, 0xC1
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0x0F));
+    //movbe cx, ax
+    let mut vm = create_vm_with_asm("
+        db 0x66, 0x0F, 0x38, 0xF1, 0xC1
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0x66));
+    //crc32 and other prefixed three byte opcodes are not supported
+    let mut vm = create_vm_with_asm("
+        crc32 eax, ecx
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0xF2));
+}
+
+#[test]
+fn test_bitops_gas(){
+    let mut vm = create_vm_with_asm("
+        popcnt eax, ecx
+        tzcnt eax, ecx
+        movbe eax, [0x80000000]
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    let c = &vm.charger;
+    let expected = c.cost(GasCost::Low) * 3 + c.cost(GasCost::ModRMSurcharge) * 3 + c.cost(GasCost::MemoryAccess);
+    assert_eq!(INITIAL_GAS - vm.gas_remaining, expected);
+}
+
+#[test]
+fn test_bitops_cpuid(){
+    let vm = execute_vm_with_asm("
+        mov eax, 1
+        cpuid
+        mov esi, ecx
+        mov eax, 0x80000000
+        cpuid
+        mov edi, eax
+        mov eax, 0x80000001
+        cpuid
+        hlt");
+    assert_eq!(vm.reg32(Reg32::ESI) & CPUID_ECX_POPCNT, CPUID_ECX_POPCNT);
+    assert_eq!(vm.reg32(Reg32::ESI) & CPUID_ECX_MOVBE, CPUID_ECX_MOVBE);
+    assert_eq!(vm.reg32(Reg32::EDI), 0x80000001);
+    assert_eq!(vm.reg32(Reg32::ECX), CPUID_EXT_ECX_LZCNT);
+}
@@ -42,6 +42,17 @@ pub fn create_vm() -> VM{
     vm.gas_remaining = INITIAL_GAS;
     vm.memory.add_memory(CODE_MEM, 0x10000).unwrap();
     vm.memory.add_memory(DATA_MEM, 0x10000).unwrap();
+    //unfused_tests.rs runs every test in simple_tests.rs a second time with fusion disabled
+    vm.disable_fusion = module_path!().starts_with("unfused_tests");
+    //and translated_tests.rs runs them with every block translated into threaded code
+    if module_path!().starts_with("translated_tests"){
+        vm.translation = qx86::threaded::Translation::AllBlocks;
+    }
+    //with the jit feature every test compiles every block into native code, so that the results can be compared with the interpreter
+    #[cfg(feature = "jit")]
+    {
+        vm.translation = qx86::threaded::Translation::AllBlocks;
+    }
     vm
 }
 #[cfg(test)]
@@ -52,12 +63,54 @@ pub fn reset_vm(vm: &mut VM){
 
 #[cfg(test)]
 pub fn create_vm_with_asm(input: &str) -> VM{
+    create_vm_with_bytes(&asm(input))
+}
+
+#[cfg(test)]
+pub fn create_vm_with_bytes(bytes: &[u8]) -> VM{
     let mut vm = create_vm();
-    let bytes = asm(input);
-    vm.copy_into_memory(CODE_MEM, &bytes).unwrap();
+    vm.copy_into_memory(CODE_MEM, bytes).unwrap();
     vm
 }
 
+/// Asserts that the registers, EIP, flags, gas remaining, and the start of data memory are identical in both VMs
+#[allow(dead_code)]
+#[cfg(test)]
+pub fn assert_same_state(a: &VM, b: &VM){
+    assert_eq!(a.regs, b.regs);
+    assert_eq!(a.eip, b.eip);
+    assert_eq!(a.flags, b.flags);
+    assert_eq!(a.gas_remaining, b.gas_remaining);
+    for i in 0..0x400{
+        assert_eq!(a.memory.get_u8(DATA_MEM + i).unwrap(), b.memory.get_u8(DATA_MEM + i).unwrap());
+    }
+}
+
+/// Runs out of gas at every gas amount which is not enough to finish the program, using VMs from `create`.
+/// Each must stop with the same state as a VM from `reference` given the same gas, and then finish with the same state
+/// as a `reference` VM given enough gas once the rest of the gas is added. The finished `reference` VM is returned
+#[allow(dead_code)]
+#[cfg(test)]
+pub fn assert_resumes_at_every_gas_boundary(create: &dyn Fn() -> VM, reference: &dyn Fn() -> VM) -> VM{
+    let mut hv = TestHypervisor::default();
+    let mut expected = reference();
+    assert_eq!(expected.execute(&mut hv).unwrap(), Termination::Halted);
+    let used = INITIAL_GAS - expected.gas_remaining;
+    for gas in 0..used{
+        let mut vm = create();
+        let mut stopped = reference();
+        vm.gas_remaining = gas;
+        stopped.gas_remaining = gas;
+        assert_eq!(vm.execute(&mut hv).unwrap(), Termination::OutOfGas);
+        assert_eq!(stopped.execute(&mut hv).unwrap(), Termination::OutOfGas);
+        assert_same_state(&vm, &stopped);
+        vm.gas_remaining += INITIAL_GAS - gas;
+        assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+        assert_same_state(&vm, &expected);
+    }
+    expected
+}
+
 #[cfg(test)]
 pub fn execute_vm_with_asm(input: &str) -> VM{
     let mut vm = create_vm_with_asm(input);
@@ -88,6 +141,12 @@ pub fn execute_vm_with_diagnostics_and_hypervisor(vm: &mut VM, hv: &mut dyn Hype
 #[allow(dead_code)]
 #[cfg(test)]
 pub fn execute_vm_with_error(vm: &mut VM) -> VMError{
+    execute_vm_with_fault(vm).error
+}
+
+#[allow(dead_code)]
+#[cfg(test)]
+pub fn execute_vm_with_fault(vm: &mut VM) -> Fault{
     let mut hv = TestHypervisor::default();
     let r = vm.execute(&mut hv);
     vm_diagnostics(vm);
@@ -0,0 +1,151 @@
+#![cfg(feature = "debugger")]
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use qx86::debugger::*;
+use common::*;
+
+#[test]
+fn test_breakpoints(){
+    let expected = execute_vm_with_asm("
+        mov eax, 1
+        mov ecx, 2
+        add eax, ecx
+        mov edx, 3
+        hlt");
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        mov ecx, 2
+        add eax, ecx
+        mov edx, 3
+        hlt");
+    vm.debugger.add_breakpoint(CODE_MEM);
+    vm.debugger.add_breakpoint(CODE_MEM + 10);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Breakpoint(CODE_MEM));
+    assert_eq!(vm.eip, CODE_MEM);
+    assert_eq!(vm.gas_remaining, INITIAL_GAS);
+    //the breakpoint is in the middle of the pipeline
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Breakpoint(CODE_MEM + 10));
+    assert_eq!(vm.eip, CODE_MEM + 10);
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+    assert_eq!(vm.reg32(Reg32::ECX), 2);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(vm.regs, expected.regs);
+    assert_eq!(vm.eip, expected.eip);
+    assert_eq!(vm.gas_remaining, expected.gas_remaining);
+}
+
+#[test]
+fn test_breakpoint_in_loop(){
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        mov ecx, 3
+        xor eax, eax
+    top:
+        inc eax
+        loop top
+        hlt");
+    vm.debugger.add_breakpoint(CODE_MEM + 7);
+    for i in 0..3{
+        assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Breakpoint(CODE_MEM + 7));
+        assert_eq!(vm.reg32(Reg32::EAX), i);
+        assert_eq!(vm.reg32(Reg32::ECX), 3 - i);
+    }
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(vm.reg32(Reg32::EAX), 3);
+
+    //breakpoints work the same when stepping
+    let mut vm = create_vm_with_asm("
+        mov ecx, 3
+        xor eax, eax
+    top:
+        inc eax
+        loop top
+        hlt");
+    vm.debugger.add_breakpoint(CODE_MEM + 7);
+    assert_eq!(vm.run_for(2, &mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Breakpoint(CODE_MEM + 7));
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+    vm.debugger.remove_breakpoint(CODE_MEM + 7);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(vm.reg32(Reg32::EAX), 3);
+}
+
+#[test]
+fn test_watchpoints(){
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x80000000
+        mov dword [ebx], 1
+        mov eax, [ebx + 8]
+        mov word [ebx + 6], 2
+        mov esp, 0x80000100
+        push eax
+        mov ecx, [esp]
+        hlt");
+    vm.memory.set_u32(DATA_MEM + 8, 7).unwrap();
+    vm.debugger.add_watchpoint(DATA_MEM + 4, 4, WatchAccess::Write);
+    vm.debugger.add_watchpoint(DATA_MEM + 8, 4, WatchAccess::Write);
+    vm.debugger.add_watchpoint(DATA_MEM + 0xFC, 4, WatchAccess::Read);
+    //the write overlaps the watched range
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Watchpoint{
+        eip: CODE_MEM + 14,
+        access: MemoryAccess{
+            address: DATA_MEM + 6,
+            kind: AccessKind::Write
+        }
+    });
+    assert_eq!(vm.eip, CODE_MEM + 20);
+    assert_eq!(vm.memory.get_u16(DATA_MEM + 6).unwrap(), 2);
+    //the push is a write, so only the read afterwards stops
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Watchpoint{
+        eip: CODE_MEM + 26,
+        access: MemoryAccess{
+            address: DATA_MEM + 0xFC,
+            kind: AccessKind::Read
+        }
+    });
+    assert_eq!(vm.reg32(Reg32::ECX), 7);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+
+    let mut vm = create_vm_with_asm("
+        mov esi, 0x80000000
+        mov edi, 0x80000010
+        mov ecx, 8
+        rep movsb
+        hlt");
+    vm.debugger.add_watchpoint(DATA_MEM + 0x14, 1, WatchAccess::ReadWrite);
+    //the entire rep opcode is executed before stopping
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Watchpoint{
+        eip: CODE_MEM + 15,
+        access: MemoryAccess{
+            address: DATA_MEM + 0x14,
+            kind: AccessKind::Write
+        }
+    });
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+    vm.debugger.remove_watchpoint(DATA_MEM + 0x14);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+}
+
+#[test]
+fn test_breakpoint_between_fused_opcodes(){
+    //cmp and jcc would normally be fused into a single pipeline slot
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        cmp eax, 1
+        je done
+        mov ebx, 2
+    done:
+        hlt");
+    vm.debugger.add_breakpoint(CODE_MEM + 8);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Breakpoint(CODE_MEM + 8));
+    assert_eq!(vm.eip, CODE_MEM + 8);
+    assert!(vm.flags.zero);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(vm.reg32(Reg32::EBX), 0);
+}
@@ -0,0 +1,95 @@
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use qx86::opcodes::*;
+use qx86::pipeline::*;
+use qx86::structs::*;
+use common::*;
+
+//a custom opcode which multiplies its argument by 3
+fn triple(vm: &mut VM, pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    let v = vm.get_arg(pipeline.args[0].location)?.u32_exact()?;
+    vm.set_arg(pipeline.args[0].location, SizedValue::Dword(v.wrapping_mul(3)))?;
+    Ok(())
+}
+
+//a replacement for movsb which counts in EAX instead
+fn count_eax(vm: &mut VM, _pipeline: &Pipeline, _hv: &mut dyn Hypervisor) -> Result<(), VMError>{
+    vm.regs[Reg32::EAX as usize] += 1;
+    Ok(())
+}
+
+fn custom_table() -> OpcodeTable{
+    let mut table = copy_opcode_table();
+    //0x0F FF triple r/m32
+    define_opcode(0xFF).is_two_byte_op().calls(triple).with_gas(GasCost::Moderate)
+        .with_rm32()
+        .into_table(&mut table[..]);
+    //0xA4 replaced with count_eax
+    table[0xA4] = OpcodeProperties::default();
+    define_opcode(0xA4).calls(count_eax).with_gas(GasCost::Low)
+        .into_table(&mut table[..]);
+    OpcodeTable::new(table)
+}
+
+#[test]
+fn test_custom_opcode(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 5
+        mov dword [0x80000000], 7
+        db 0x0F, 0xFF, 0xC3 ;triple ebx
+        db 0x0F, 0xFF, 0x05, 0x00, 0x00, 0x00, 0x80 ;triple [0x80000000]
+        hlt");
+    vm.opcodes = custom_table();
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::EBX), 15);
+    assert_eq!(vm.get_mem(0x80000000, ValueSize::Dword).unwrap().u32_exact().unwrap(), 21);
+    //gas is charged as normal for custom opcodes
+    use GasCost::*;
+    let used = [VeryLow, VeryLow, MemoryAccess, ModRMSurcharge, Moderate, ModRMSurcharge, Moderate, ModRMSurcharge, MemoryAccess]
+        .iter().map(|c| vm.charger.cost(*c)).sum::<u64>();
+    assert_eq!(vm.gas_remaining, INITIAL_GAS - used);
+}
+
+#[test]
+fn test_custom_opcode_not_in_default_table(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 5
+        db 0x0F, 0xFF, 0xC3 ;triple ebx
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::InvalidOpcode(0x0F));
+    assert_eq!(fault.eip, CODE_MEM + 5);
+}
+
+#[test]
+fn test_custom_opcode_with_rep(){
+    let mut vm = create_vm_with_asm("
+        mov eax, 0
+        mov ecx, 3
+        rep movsb
+        hlt");
+    vm.opcodes = custom_table();
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::EAX), 3);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+}
+
+#[test]
+fn test_custom_table_replaced(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 5
+        db 0x0F, 0xFF, 0xC3 ;triple ebx
+        hlt");
+    //each replacement is a new table, which must not be mistaken for a dropped table decoded from before
+    for _ in 0..3{
+        vm.opcodes = custom_table();
+        vm.eip = CODE_MEM;
+        execute_vm_with_diagnostics(&mut vm);
+        assert_eq!(vm.reg32(Reg32::EBX), 15);
+    }
+    vm.opcodes = OpcodeTable::default();
+    vm.eip = CODE_MEM;
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0x0F));
+}
@@ -0,0 +1,135 @@
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use qx86::structs::*;
+use common::*;
+
+#[test]
+fn test_fault_memory_write(){
+    let mut vm = create_vm_with_asm("
+        mov eax, 5
+        mov [0x10000], eax
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::WroteReadOnlyMemory(0x10000));
+    assert_eq!(fault.eip, CODE_MEM + 5);
+    assert_eq!(vm.eip, CODE_MEM + 5);
+    assert_eq!(fault.bytes, vec![0xA3, 0x00, 0x00, 0x01, 0x00]);
+    assert_eq!(fault.opcode, Some(Box::new(DecodedOpcode{
+        opcode: 0xA3,
+        args: [ArgLocation::Address(0x10000, ValueSize::Dword), ArgLocation::RegisterValue(Reg32::EAX as u8, ValueSize::Dword), ArgLocation::None],
+        size_override: false,
+        gas_cost: 3
+    })));
+    assert_eq!(fault.access, Some(MemoryAccess{
+        address: 0x10000,
+        kind: AccessKind::Write
+    }));
+    assert_eq!(fault.gas_remaining, vm.gas_remaining);
+}
+
+#[test]
+fn test_fault_memory_read(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x20000
+        mov eax, [ebx + 4]
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::ReadUnloadedMemory(0x20004));
+    assert_eq!(fault.eip, CODE_MEM + 5);
+    assert_eq!(fault.bytes, vec![0x8B, 0x43, 0x04]);
+    assert_eq!(fault.opcode.unwrap().opcode, 0x8B);
+    assert_eq!(fault.access, Some(MemoryAccess{
+        address: 0x20004,
+        kind: AccessKind::Read
+    }));
+}
+
+#[test]
+fn test_fault_decoding_after_valid_opcodes(){
+    //the opcodes before the one which can not be decoded are executed first, even within the same pipeline
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        mov ebx, 2
+        db 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::InvalidOpcodeEncoding);
+    assert_eq!(fault.eip, CODE_MEM + 10);
+    assert_eq!(vm.eip, CODE_MEM + 10);
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+    assert_eq!(vm.reg32(Reg32::EBX), 2);
+    assert_eq!(fault.bytes, vec![0x66; 16]);
+    assert_eq!(fault.opcode, None);
+    assert_eq!(fault.access, None);
+    assert_eq!(fault.gas_remaining, vm.gas_remaining);
+    assert!(vm.gas_remaining < INITIAL_GAS);
+}
+
+#[test]
+fn test_fault_executing_unloaded_memory(){
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        jmp 0x20000
+        hlt");
+    let mut hv = TestHypervisor::default();
+    let fault = vm.execute(&mut hv).unwrap_err();
+    assert_eq!(fault.error, VMError::ReadUnloadedMemory(0x20000));
+    assert_eq!(fault.eip, 0x20000);
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+    assert_eq!(fault.bytes, vec![]);
+    assert_eq!(fault.opcode, None);
+    assert_eq!(fault.access, Some(MemoryAccess{
+        address: 0x20000,
+        kind: AccessKind::Execute
+    }));
+}
+
+#[test]
+fn test_fault_same_for_step(){
+    let program = "
+        mov eax, 1
+        div ecx
+        hlt";
+    let mut vm = create_vm_with_asm(program);
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::DivideByZero);
+    assert_eq!(fault.eip, CODE_MEM + 5);
+    assert_eq!(fault.bytes, vec![0xF7, 0xF1]);
+    assert_eq!(fault.access, None);
+    let mut hv = TestHypervisor::default();
+    let mut stepped = create_vm_with_asm(program);
+    assert_eq!(stepped.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(stepped.step(&mut hv).unwrap_err(), fault);
+}
+
+/// Fails every interrupt, as a host would when its own state is broken
+struct FailingHypervisor{}
+impl Hypervisor for FailingHypervisor{
+    fn interrupt(&mut self, _vm: &mut VM, _num: u8) -> Result<(), VMError>{
+        Err(VMError::SyscallError)
+    }
+}
+
+#[test]
+fn test_fault_category(){
+    let mut vm = create_vm_with_asm("
+        div ecx
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.category(), ErrorCategory::GuestFault);
+    assert_eq!(fault.to_string(), format!("DivideByZero at EIP 0x{:08X}", CODE_MEM));
+    let error: Box<dyn std::error::Error> = Box::new(fault);
+    assert_eq!(error.source().unwrap().to_string(), "DivideByZero");
+
+    let mut vm = create_vm_with_asm("
+        int 0x10
+        hlt");
+    let fault = vm.execute(&mut FailingHypervisor{}).unwrap_err();
+    assert_eq!(fault.error, VMError::SyscallError);
+    assert_eq!(fault.category(), ErrorCategory::HostError);
+    assert_eq!(VMError::ConflictingMemoryAddition.category(), ErrorCategory::HostError);
+    assert_eq!(VMError::WrongSizeExpectation.category(), ErrorCategory::InternalBug);
+    assert_eq!(VMError::OutOfGas.category(), ErrorCategory::ControlFlow);
+}
@@ -0,0 +1,103 @@
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use common::*;
+
+const FUSION_PROGRAM: &str = "
+        mov esp, 0x80000100
+        mov esi, 0x80000200
+        mov ecx, 12
+    top:
+        push ecx
+        call function
+        add esp, 4
+        mov [esi + ecx * 4], eax
+        test cl, 1
+        jnz odd
+        cmp byte [esi], 3
+        jb skip
+    odd:
+        inc dword [esi + 0x40]
+    skip:
+        dec ecx
+        test ecx, ecx
+        jnz top
+        mov al, [esi]
+        cmp al, 6
+        je done
+        cmp eax, ebx
+        jne near far_target
+        hlt
+    %rep 200
+        hlt
+    %endrep
+    far_target:
+        mov ebx, 0x1234
+    done:
+        hlt
+    function:
+        push ebp
+        mov ebp, esp
+        mov eax, [ebp + 8]
+        add byte [esi], 1
+        cmp eax, 5
+        jle small
+        add eax, eax
+    small:
+        pop ebp
+        ret";
+
+fn create_fusion_vm(bytes: &[u8], fusion: bool) -> VM{
+    let mut vm = create_vm_with_bytes(bytes);
+    vm.disable_fusion = !fusion;
+    vm
+}
+
+#[test]
+fn test_fusion_matches_unfused(){
+    let bytes = asm(FUSION_PROGRAM);
+    let mut hv = TestHypervisor::default();
+    let mut fused = create_fusion_vm(&bytes, true);
+    let mut unfused = create_fusion_vm(&bytes, false);
+    assert_eq!(fused.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(unfused.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_same_state(&fused, &unfused);
+    //sanity check that every branch was taken at least once
+    assert_eq!(fused.memory.get_u8(DATA_MEM + 0x200).unwrap(), 12);
+    assert_eq!(fused.memory.get_u32(DATA_MEM + 0x240).unwrap(), 11);
+    assert_eq!(fused.memory.get_u32(DATA_MEM + 0x200 + 12 * 4).unwrap(), 24);
+    assert_eq!(fused.memory.get_u32(DATA_MEM + 0x200 + 4 * 4).unwrap(), 4);
+    assert_eq!(fused.reg32(Reg32::EBX), 0x1234);
+}
+
+#[test]
+fn test_fusion_at_every_gas_boundary(){
+    let bytes = asm(FUSION_PROGRAM);
+    //running out of gas between a fused pair must leave exactly the same state as when unfused
+    assert_resumes_at_every_gas_boundary(&|| create_fusion_vm(&bytes, true), &|| create_fusion_vm(&bytes, false));
+}
+
+#[test]
+fn test_fusion_with_step(){
+    //single stepping never fuses opcodes, so each step executes exactly one opcode
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        push ebp
+        mov ebp, esp
+        cmp eax, ebx
+        je done
+        hlt
+    done:
+        hlt");
+    vm.set_reg32(Reg32::ESP, 0x80000100);
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.eip, CODE_MEM + 1);
+    assert_eq!(vm.reg32(Reg32::EBP), 0);
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.reg32(Reg32::EBP), 0x800000FC);
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.eip, CODE_MEM + 5);
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.eip, CODE_MEM + 8);
+}
@@ -34,7 +34,106 @@ fn test_simple_gas(){
     assert_eq!(vm.gas_remaining, INITIAL_GAS - cost_from_list(&vm.charger, &[VeryLow, VeryLow, MemoryAccess, ModRMSurcharge]));
 }
 
-//Need more tests here once more opcodes are implemented, especially jmp and jcc
+#[test]
+fn test_lock_gas(){
+    use GasCost::*;
+    let vm = execute_vm_with_asm("
+        mov eax, 0x80000000 ;VeryLow
+        lock add [eax], ecx ;Low + Memory + ModRM
+        hlt ;None
+        ");
+    assert_eq!(vm.gas_remaining, INITIAL_GAS - cost_from_list(&vm.charger, &[VeryLow, Low, MemoryAccess, ModRMSurcharge]));
+    assert_eq!(vm.eip, CODE_MEM + 8);
+}
+
+#[test]
+fn test_rdtsc_gas(){
+    use GasCost::*;
+    let vm = execute_vm_with_asm("
+        rdtsc ;Low
+        mov esi, eax ;VeryLow + ModRM
+        mov ebx, 0x80000000 ;VeryLow
+        mov ecx, [ebx] ;VeryLow + Memory + ModRM
+        rdtsc ;Low
+        hlt ;None
+        ");
+    //the gas of rdtsc itself is included
+    assert_eq!(vm.reg32(Reg32::ESI), cost_from_list(&vm.charger, &[Low]) as u32);
+    assert_eq!(vm.reg32(Reg32::EAX), cost_from_list(&vm.charger, &[Low, VeryLow, ModRMSurcharge, VeryLow, VeryLow, MemoryAccess, ModRMSurcharge, Low]) as u32);
+    assert_eq!(vm.reg32(Reg32::EDX), 0);
+    assert_eq!(vm.gas_remaining, INITIAL_GAS - vm.reg32(Reg32::EAX) as u64);
+}
+
+#[test]
+fn test_rdtsc_across_pipelines(){
+    use GasCost::*;
+    //the jne stops pipeline filling, and the loop runs for more than one pipeline
+    let mut vm = create_vm_with_asm("
+        mov ecx, 20 ;VeryLow
+        _loop:
+        dec ecx ;Low
+        jne _loop ;Low + ConditionalBranch
+        rdtsc ;Low
+        hlt ;None
+        ");
+    let mut hv = TestHypervisor::default();
+    vm.execute(&mut hv).unwrap();
+    let expected = cost_from_list(&vm.charger, &[VeryLow, Low]) + 20 * cost_from_list(&vm.charger, &[Low, Low, ConditionalBranch]);
+    assert_eq!(vm.reg32(Reg32::EAX) as u64, expected);
+    //a second execution only counts the gas used since it began
+    vm.eip = CODE_MEM;
+    vm.execute(&mut hv).unwrap();
+    assert_eq!(vm.reg32(Reg32::EAX) as u64, expected);
+    assert_eq!(vm.gas_remaining, INITIAL_GAS - expected * 2);
+}
+
+#[test]
+fn test_followed_jump_gas(){
+    use GasCost::*;
+    let program = "
+        mov esp, 0x80000100 ;VeryLow
+        jmp _a ;Low + ConditionalBranch
+        ;too far for a short jump, so that the jmp relW form is used
+        %rep 200
+        hlt
+        %endrep
+        _a:
+        call _b ;Low
+        jmp short _c ;Low
+        _b:
+        ret ;Low + ConditionalBranch
+        _c:
+        hlt ;None
+        ";
+    let vm = execute_vm_with_asm(program);
+    let expected = cost_from_list(&vm.charger, &[VeryLow, Low, ConditionalBranch, Low, Low, Low, ConditionalBranch]);
+    assert_eq!(vm.gas_remaining, INITIAL_GAS - expected);
+    //following the jumps within the pipeline costs the same as executing one opcode at a time
+    let mut stepped = create_vm_with_asm(program);
+    let mut hv = TestHypervisor::default();
+    assert_eq!(stepped.run_for(100, &mut hv).unwrap(), Termination::Halted);
+    assert_eq!(stepped.gas_remaining, vm.gas_remaining);
+    assert_eq!(stepped.eip, vm.eip);
+}
+
+#[test]
+fn test_jump_into_writeable_memory_gas(){
+    use GasCost::*;
+    let mut vm = create_vm_with_asm("
+        mov esp, 0x80000100 ;VeryLow
+        call 0x80000000 ;Low
+        hlt ;None
+        ");
+    //mov eax, 5; ret
+    vm.copy_into_memory(DATA_MEM, &[0xB8, 0x05, 0x00, 0x00, 0x00, 0xC3]).unwrap();
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::EAX), 5);
+    //every opcode in writeable memory is charged for it, even when reached by a followed call
+    let expected = cost_from_list(&vm.charger, &[VeryLow, Low, VeryLow, WriteableMemoryExec, Low, ConditionalBranch, WriteableMemoryExec]);
+    assert_eq!(vm.gas_remaining, INITIAL_GAS - expected);
+}
+
+//Need more tests here once more opcodes are implemented, especially jcc
 
 #[test]
 fn test_perfect_gas_amount(){
@@ -61,8 +160,7 @@ fn test_out_of_gas(){
         ");
     vm.gas_remaining = cost_from_list(&vm.charger, &[VeryLow, VeryLow, MemoryAccess, ModRMSurcharge]) - 1;
     let mut hv = TestHypervisor::default();
-    let r = vm.execute(&mut hv);
-    assert_eq!(r.err().unwrap(), VMError::OutOfGas);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::OutOfGas);
     //should stop at the `mov ecx, [eax]`
     assert_eq!(vm.eip, CODE_MEM + 5); 
 }
\ No newline at end of file
@@ -0,0 +1,97 @@
+#![cfg(feature = "jit")]
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use qx86::threaded::*;
+use common::*;
+
+/// Builds a loop which covers every opcode compiled into native code, followed by a branch on every condition code
+fn jit_program() -> String{
+    let mut program = String::from("
+        mov esi, 0x80000200
+        mov ecx, 24
+        mov ebx, 0x7FFFFFF0
+        mov edx, 0x0F
+    top:
+        add ebx, 3
+        mov eax, ecx
+        and eax, 7
+        lea edi, [ebx + eax * 4 + 0x10]
+        lea ebp, [eax * 8 + 5]
+        lea ebp, [ebp + edi]
+        xor ebp, edi
+        or edx, eax
+        sub edx, 1
+        inc eax
+        dec edi
+        xor edx, 0x55
+        and edx, 0xFF
+        add eax, edx
+        sub edi, 0x7FFFFFFF
+        jmp next
+        hlt
+    next:
+        mov [esi + ecx * 4], ebp
+");
+    //each pair of opposite conditions is checked after opcodes which set the flags differently depending on ECX
+    let setters = [
+        ("mov ebp, 0x7FFFFFF0\n add ebp, ecx", "o", "no"),
+        ("cmp ecx, 10", "b", "ae"),
+        ("mov eax, ecx\n and eax, 3", "e", "ne"),
+        ("mov eax, ecx\n sub eax, 12", "be", "a"),
+        ("mov eax, ecx\n add eax, 0x7FFFFFF0\n inc eax", "s", "ns"),
+        ("mov eax, ecx\n or eax, edx", "p", "np"),
+        ("mov eax, 12\n xor eax, ecx\n cmp eax, ecx", "l", "ge"),
+        ("mov eax, ecx\n dec eax", "le", "g")
+    ];
+    let mut i = 0;
+    for (setter, cc, inverse) in setters.iter(){
+        for cc in [cc, inverse].iter(){
+            program += &format!("
+        {}
+        j{} taken{}
+        inc dword [esi + {}]
+    taken{}:
+", setter, cc, i, 0x100 + i * 4, i);
+            i += 1;
+        }
+    }
+    program += "
+        dec ecx
+        jnz top
+        hlt";
+    program
+}
+
+fn create_jit_vm(bytes: &[u8], translation: Translation, jit: bool) -> VM{
+    let mut vm = create_vm_with_bytes(bytes);
+    vm.translation = translation;
+    vm.disable_jit = !jit;
+    vm
+}
+
+#[test]
+fn test_jit_matches_interpreter(){
+    let bytes = asm(&jit_program());
+    let mut hv = TestHypervisor::default();
+    let mut expected = create_jit_vm(&bytes, Translation::Disabled, false);
+    assert_eq!(expected.execute(&mut hv).unwrap(), Termination::Halted);
+    //sanity check that every branch was both taken and not taken at least once
+    for i in 0..16{
+        let skipped = expected.memory.get_u32(DATA_MEM + 0x300 + i * 4).unwrap();
+        assert!(skipped > 0 && skipped < 24, "the branch of condition {} was not taken {} times out of 24", i, skipped);
+    }
+    for (translation, jit) in [(Translation::HotBlocks, true), (Translation::AllBlocks, true), (Translation::AllBlocks, false)].iter(){
+        let mut vm = create_jit_vm(&bytes, *translation, *jit);
+        assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+        assert_same_state(&vm, &expected);
+    }
+}
+
+#[test]
+fn test_jit_at_every_gas_boundary(){
+    let bytes = asm(&jit_program());
+    //compiled blocks are only entered when there is enough gas for the entire block, otherwise the interpreter is used
+    assert_resumes_at_every_gas_boundary(&|| create_jit_vm(&bytes, Translation::AllBlocks, true), &|| create_jit_vm(&bytes, Translation::Disabled, false));
+}
@@ -0,0 +1,236 @@
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use qx86::memory::*;
+use qx86::threaded::*;
+use common::*;
+use std::panic;
+
+/// A small deterministic xorshift generator, so that any failure can be reproduced from its seed
+struct Rng(u64);
+impl Rng{
+    fn next(&mut self) -> u32{
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 16) as u32
+    }
+    /// Register values are biased towards the edges of memory and of integer ranges
+    fn value(&mut self) -> u32{
+        const INTERESTING: [u32; 10] = [0, 1, 0xFFFFFFFF, 0x7FFFFFFF, 0x80000000, CODE_MEM, CODE_MEM + 0xFFFE, DATA_MEM, DATA_MEM + 0xFFFC, 0x8000];
+        match self.next() % 3{
+            0 => INTERESTING[(self.next() % 10) as usize],
+            1 => DATA_MEM + (self.next() & 0xFFFF),
+            _ => self.next()
+        }
+    }
+}
+
+/// Accepts every interrupt, since TestHypervisor expects interrupts to be used in a particular way
+struct NullHypervisor{}
+impl Hypervisor for NullHypervisor{
+    fn interrupt(&mut self, _vm: &mut VM, _num: u8) -> Result<(), VMError>{
+        Ok(())
+    }
+}
+
+/// Opcode bytes which lead into most of the interesting decoding paths
+const PREFIXES: [u8; 8] = [0x66, 0x67, 0xF0, 0xF2, 0xF3, 0x0F, 0x2E, 0xD9];
+
+fn random_vm(rng: &mut Rng) -> VM{
+    let mut vm = create_vm();
+    let mut code = vec![];
+    for _ in 0..64{
+        code.push(match rng.next() % 4{
+            0 => PREFIXES[(rng.next() % 8) as usize],
+            _ => rng.next() as u8
+        });
+    }
+    //code is placed either at the start of memory, at the very end of it, or within writeable memory
+    vm.eip = match rng.next() % 3{
+        0 => CODE_MEM,
+        1 => CODE_MEM + 0xFFC0 + (rng.next() % 64),
+        _ => DATA_MEM + 0x100
+    };
+    let size = std::cmp::min(code.len() as u32, 0x10000 - (vm.eip & 0xFFFF));
+    vm.copy_into_memory(vm.eip, &code[0..size as usize]).unwrap();
+    for r in 0..8{
+        vm.regs[r] = rng.value();
+    }
+    vm.gas_remaining = 20000;
+    vm
+}
+
+/// Runs random bytecode with random register values, checking that the host never panics no matter what the bytecode does
+fn run_random_programs(translation: Translation, count: u64){
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| ()));
+    let mut failed = vec![];
+    for seed in 1..=count{
+        let result = panic::catch_unwind(|| {
+            let mut rng = Rng(seed.wrapping_mul(0x9E3779B97F4A7C15));
+            let mut hv = NullHypervisor{};
+            let mut vm = random_vm(&mut rng);
+            vm.translation = translation;
+            //any result is fine, including errors, so long as it is returned rather than panicking
+            let _ = vm.execute(&mut hv);
+            let mut stepped = random_vm(&mut Rng(seed.wrapping_mul(0x9E3779B97F4A7C15)));
+            let _ = stepped.run_for(64, &mut hv);
+        });
+        if result.is_err(){
+            failed.push(seed);
+        }
+    }
+    panic::set_hook(hook);
+    assert!(failed.is_empty(), "random programs with seeds {:?} caused a panic", failed);
+}
+
+#[test]
+fn test_random_programs_do_not_panic(){
+    run_random_programs(Translation::Disabled, 5000);
+}
+
+#[test]
+fn test_random_translated_programs_do_not_panic(){
+    run_random_programs(Translation::AllBlocks, 5000);
+}
+
+#[test]
+fn test_shift_counts_are_masked(){
+    //only the low 5 bits of the count are used, even for 8 and 16 bit shifts
+    let vm = execute_vm_with_asm("
+        mov cl, 32
+        mov eax, 0x12345678
+        shl eax, cl
+        mov cl, 35
+        mov bl, 0x81
+        shr bl, cl
+        mov cl, 12
+        mov dl, 0x80
+        sar dl, cl
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 0x12345678);
+    assert_eq!(vm.reg8(Reg8::BL), 0x10);
+    assert_eq!(vm.reg8(Reg8::DL), 0xFF);
+    assert!(vm.flags.carry);
+}
+
+#[test]
+fn test_stack_wraps_around(){
+    let mut vm = create_vm_with_asm("
+        mov esp, 0xFFFFFFFE
+        pop eax
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::ReadUnloadedMemory(0xFFFFFFFE));
+}
+
+#[test]
+fn test_ret_wraps_around(){
+    let mut vm = create_vm_with_asm("
+        mov esp, 0x80000100
+        push dword 0
+        ret
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::ReadUnloadedMemory(0));
+    assert_eq!(fault.eip, 0);
+}
+
+#[test]
+fn test_zero_sized_memory(){
+    let mut vm = create_vm_with_asm("
+        mov eax, [0x20000]
+        hlt");
+    vm.memory.add_memory(0x20000, 0).unwrap();
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::ReadBadMemory(0x20000));
+    let buffer = BufferMemory{
+        memory: vec![0x41, 0xFF, 0x42]
+    };
+    assert_eq!(buffer.to_string(), "A\u{FFFD}B");
+}
+
+/// Executes `program` followed by hlt, returning the VM if it halted or the error it stopped with
+fn run_edge_case(program: &str) -> Result<VM, VMError>{
+    let mut vm = create_vm_with_asm(&format!("{}\nhlt", program));
+    match vm.execute(&mut NullHypervisor{}){
+        Ok(_) => Ok(vm),
+        Err(fault) => Err(fault.error)
+    }
+}
+
+#[test]
+fn test_division_edge_cases(){
+    //dividing by zero, dividing MIN by -1, and any other quotient too large for the destination are all divide errors
+    let errors = [
+        "mov ax, 5\n mov bl, 0\n div bl",
+        "mov dx, 0\n mov ax, 5\n mov bx, 0\n div bx",
+        "xor edx, edx\n mov eax, 5\n xor ebx, ebx\n div ebx",
+        "mov ax, 0x100\n mov bl, 1\n div bl",
+        "mov dx, 1\n mov ax, 0\n mov bx, 1\n div bx",
+        "mov edx, 1\n xor eax, eax\n mov ebx, 1\n div ebx",
+        "mov ax, 5\n mov bl, 0\n idiv bl",
+        "mov dx, 0\n mov ax, 5\n mov bx, 0\n idiv bx",
+        "xor edx, edx\n mov eax, 5\n xor ebx, ebx\n idiv ebx",
+        "mov ax, 0x8000\n mov bl, 0xFF\n idiv bl",
+        "mov ax, 0xFF80\n mov bl, 0xFF\n idiv bl",
+        "mov dx, 0x8000\n mov ax, 0\n mov bx, 0xFFFF\n idiv bx",
+        "mov dx, 0xFFFF\n mov ax, 0x8000\n mov bx, 0xFFFF\n idiv bx",
+        "mov edx, 0x80000000\n xor eax, eax\n mov ecx, 0xFFFFFFFF\n idiv ecx",
+        "mov edx, 0xFFFFFFFF\n mov eax, 0x80000000\n mov ecx, 0xFFFFFFFF\n idiv ecx",
+        "xor edx, edx\n mov eax, 0x80000000\n mov ecx, 1\n idiv ecx",
+        "mov al, 5\n db 0xD4, 0x00 ;aam 0",
+    ];
+    for program in errors.iter(){
+        assert_eq!(run_edge_case(program).err(), Some(VMError::DivideByZero), "{}", program);
+    }
+    //the most extreme quotients which do fit are not errors, and divisors are sign extended
+    let vm = run_edge_case("mov ax, 0xFF80\n mov bl, 1\n idiv bl").unwrap();
+    assert_eq!(vm.reg8(Reg8::AL), 0x80);
+    let vm = run_edge_case("mov ax, 0xFFF9\n mov bl, 2\n idiv bl").unwrap();
+    assert_eq!((vm.reg8(Reg8::AL), vm.reg8(Reg8::AH)), (0xFD, 0xFF));
+    let vm = run_edge_case("mov ax, 100\n mov bl, 0xFF\n idiv bl").unwrap();
+    assert_eq!((vm.reg8(Reg8::AL), vm.reg8(Reg8::AH)), (0x9C, 0));
+    let vm = run_edge_case("mov dx, 0xFFFF\n mov ax, 0xFFF9\n mov bx, 2\n idiv bx").unwrap();
+    assert_eq!((vm.reg16(Reg16::AX), vm.reg16(Reg16::DX)), (0xFFFD, 0xFFFF));
+    let vm = run_edge_case("mov dx, 0\n mov ax, 7\n mov bx, 0xFFFE\n idiv bx").unwrap();
+    assert_eq!((vm.reg16(Reg16::AX), vm.reg16(Reg16::DX)), (0xFFFD, 1));
+    let vm = run_edge_case("xor edx, edx\n mov eax, 0x80000000\n mov ecx, 2\n idiv ecx").unwrap();
+    assert_eq!((vm.reg32(Reg32::EAX), vm.reg32(Reg32::EDX)), (0x40000000, 0));
+    let vm = run_edge_case("mov edx, 0xFFFFFFFF\n mov eax, 0x80000000\n mov ecx, 1\n idiv ecx").unwrap();
+    assert_eq!(vm.reg32(Reg32::EAX), 0x80000000);
+    let vm = run_edge_case("mov edx, 0xFFFFFFFE\n mov eax, 0xFFFFFFFF\n mov ecx, 0xFFFFFFFF\n div ecx").unwrap();
+    assert_eq!((vm.reg32(Reg32::EAX), vm.reg32(Reg32::EDX)), (0xFFFFFFFF, 0xFFFFFFFE));
+}
+
+#[test]
+fn test_shift_edge_cases(){
+    //every shift and rotate with counts of the operand width, the masked width, and beyond, in both count forms
+    let shifts = ["shl", "shr", "sar", "rol", "ror", "rcl", "rcr"];
+    let registers = ["al", "ax", "eax"];
+    for shift in shifts.iter(){
+        for register in registers.iter(){
+            for count in [7, 8, 9, 15, 16, 17, 31, 32, 33, 255].iter(){
+                let program = format!("mov eax, 0x80000001\n stc\n {} {}, {}\n mov cl, {}\n stc\n {} {}, cl", shift, register, count, count, shift, register);
+                assert!(run_edge_case(&program).is_ok(), "{}", program);
+            }
+        }
+    }
+    for shift in ["shld", "shrd"].iter(){
+        for (destination, source) in [("ax", "bx"), ("eax", "ebx")].iter(){
+            for count in [15, 16, 17, 31, 32, 33, 255].iter(){
+                let program = format!("mov eax, 0x80000001\n mov ebx, 0x12345678\n {} {}, {}, {}\n mov cl, {}\n {} {}, {}, cl",
+                    shift, destination, source, count, count, shift, destination, source);
+                assert!(run_edge_case(&program).is_ok(), "{}", program);
+            }
+        }
+    }
+    //rotates by a multiple of the operand width, after masking, leave the value unchanged
+    let vm = run_edge_case("mov al, 0x81\n rol al, 16\n mov bx, 0x8001\n mov cl, 48\n ror bx, cl").unwrap();
+    assert_eq!((vm.reg8(Reg8::AL), vm.reg16(Reg16::BX)), (0x81, 0x8001));
+    //a masked count of 0 leaves the destination unchanged
+    let vm = run_edge_case("mov eax, 0x80000001\n mov ebx, 0xFFFFFFFF\n shld eax, ebx, 32\n mov edx, 5\n mov cl, 64\n shrd edx, ebx, cl").unwrap();
+    assert_eq!((vm.reg32(Reg32::EAX), vm.reg32(Reg32::EDX)), (0x80000001, 5));
+}
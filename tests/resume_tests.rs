@@ -0,0 +1,133 @@
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use common::*;
+
+const RESUME_PROGRAM: &str = "
+        mov esp, 0x80000100
+        mov esi, 0x80000200
+        mov edi, 0x80000300
+        mov ecx, 4
+        xor eax, eax
+    top:
+        call add_ecx
+        mov [esi + ecx * 4], eax
+        loop top
+        mov ecx, 16
+        rep movsb
+        mov ecx, 3
+        mov edi, 0x80000300
+        mov al, 6
+        repne scasb
+        mov [0x80000000], ecx
+        rdtsc
+        mov [0x80000004], eax
+        jmp done
+    add_ecx:
+        add eax, ecx
+        ret
+    done:
+        hlt";
+
+#[test]
+fn test_resume_at_every_gas_boundary(){
+    let bytes = asm(RESUME_PROGRAM);
+    let expected = assert_resumes_at_every_gas_boundary(&|| create_vm_with_bytes(&bytes), &|| create_vm_with_bytes(&bytes));
+    //sanity check that the rdtsc result was written
+    assert!(expected.memory.get_u32(DATA_MEM + 4).unwrap() > 0);
+}
+
+#[test]
+fn test_resume_with_small_gas_increments(){
+    let bytes = asm(RESUME_PROGRAM);
+    let mut hv = TestHypervisor::default();
+    let mut expected = create_vm_with_bytes(&bytes);
+    assert_eq!(expected.execute(&mut hv).unwrap(), Termination::Halted);
+    let used = INITIAL_GAS - expected.gas_remaining;
+    for increment in 1..25{
+        let mut vm = create_vm_with_bytes(&bytes);
+        vm.gas_remaining = 0;
+        let mut added = 0;
+        loop{
+            match vm.execute(&mut hv).unwrap(){
+                Termination::OutOfGas => {
+                    vm.gas_remaining += increment;
+                    added += increment;
+                },
+                r => {
+                    assert_eq!(r, Termination::Halted);
+                    break;
+                }
+            }
+        }
+        assert!(added >= used);
+        assert_eq!(added - vm.gas_remaining, used);
+        vm.gas_remaining = expected.gas_remaining;
+        assert_same_state(&vm, &expected);
+    }
+}
+
+#[test]
+fn test_out_of_gas_state(){
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        mov ebx, [0x80000000]
+        hlt");
+    vm.memory.set_u32(DATA_MEM, 2).unwrap();
+    vm.gas_remaining = vm.charger.cost(GasCost::VeryLow) + 1;
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::OutOfGas);
+    //EIP points to the opcode which could not be paid for, and nothing is deducted for it
+    assert_eq!(vm.eip, CODE_MEM + 5);
+    assert_eq!(vm.gas_remaining, 1);
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+    assert_eq!(vm.reg32(Reg32::EBX), 0);
+    vm.gas_remaining += 10;
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(vm.reg32(Reg32::EBX), 2);
+}
+
+#[derive(Default)]
+struct YieldingHypervisor{
+    yields: u32
+}
+impl Hypervisor for YieldingHypervisor{
+    fn interrupt(&mut self, vm: &mut VM, num: u8) -> Result<(), VMError>{
+        vm.set_reg32(Reg32::EDX, vm.reg32(Reg32::EDX) + num as u32);
+        self.yields += 1;
+        Err(VMError::Yield)
+    }
+}
+
+#[test]
+fn test_hypervisor_yield(){
+    let program = "
+        mov ecx, 3
+    top:
+        int 0x10
+        loop top
+        rdtsc
+        hlt";
+    let mut hv = YieldingHypervisor::default();
+    let mut vm = create_vm_with_asm(program);
+    //EIP is left after the 2 byte int 0x10, which follows the 5 byte mov ecx, 3
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Yielded);
+    assert_eq!(vm.eip, CODE_MEM + 7);
+    assert_eq!(vm.reg32(Reg32::EDX), 0x10);
+    //gas added while yielded is not counted by rdtsc
+    vm.gas_remaining += 1000;
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Yielded);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Yielded);
+    assert_eq!(vm.reg32(Reg32::EDX), 0x30);
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(hv.yields, 3);
+
+    let mut expected = create_vm_with_asm(program);
+    let mut tv = TestHypervisor::default();
+    assert_eq!(expected.execute(&mut tv).unwrap(), Termination::Halted);
+    assert_eq!(vm.eip, expected.eip);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+    assert_eq!(vm.reg32(Reg32::EAX), expected.reg32(Reg32::EAX));
+    assert_eq!(vm.gas_remaining, expected.gas_remaining + 1000);
+}
@@ -14,14 +14,98 @@ fn test_undefined_opcode(){
         0x90, //nop
         0x90,
         0x0F,
-        0x0B,
+        0x0A,
         0x90,
         0x90
     ];
     vm.copy_into_memory(CODE_MEM, &bytes).unwrap();
     let mut hv = TestHypervisor::default();
-    assert_eq!(vm.execute(&mut hv).err().unwrap(), VMError::InvalidOpcode(0x0F));
-    assert_eq!(vm.error_eip, CODE_MEM + 2);
+    let fault = vm.execute(&mut hv).unwrap_err();
+    assert_eq!(fault.error, VMError::InvalidOpcode(0x0F));
+    assert_eq!(fault.eip, CODE_MEM + 2);
+}
+
+#[test]
+#[cfg(not(feature = "x87"))]
+fn test_fpu_opcode_invalid(){
+    let mut vm = create_vm_with_asm("
+        nop
+        fld1
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::InvalidOpcode(0xD9));
+    assert_eq!(fault.eip, CODE_MEM + 1);
+}
+
+#[test]
+#[cfg(not(feature = "sse2"))]
+fn test_sse_opcode_invalid(){
+    let mut vm = create_vm_with_asm("
+        pxor xmm0, xmm0
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::InvalidOpcode(0x66));
+    assert_eq!(fault.eip, CODE_MEM);
+}
+
+#[test]
+#[cfg(not(feature = "bitops"))]
+fn test_popcnt_opcode_invalid(){
+    let mut vm = create_vm_with_asm("
+        popcnt eax, ecx
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0xF3));
+    let mut vm = create_vm_with_asm("
+        movbe eax, [0x80000000]
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0x0F));
+}
+
+#[test]
+fn test_ud2_trap(){
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        ud2
+        mov eax, 2
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::Trap(CODE_MEM + 5));
+    assert_eq!(fault.eip, CODE_MEM + 5);
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+}
+
+#[derive(Default)]
+struct TrapHypervisor{
+    traps: Vec<u32>
+}
+impl Hypervisor for TrapHypervisor{
+    fn interrupt(&mut self, _vm: &mut VM, _num: u8) -> Result<(), VMError>{
+        Ok(())
+    }
+    fn trap(&mut self, vm: &mut VM) -> Result<(), VMError>{
+        self.traps.push(vm.eip);
+        if vm.reg32(Reg32::EAX) == 1 {
+            //resume
+            Ok(())
+        }else{
+            Err(VMError::SyscallError)
+        }
+    }
+}
+
+#[test]
+fn test_ud2_trap_hypervisor(){
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        ud2
+        mov eax, 2
+        ud2
+        hlt");
+    let mut hv = TrapHypervisor::default();
+    let fault = vm.execute(&mut hv).unwrap_err();
+    assert_eq!(fault.error, VMError::SyscallError);
+    assert_eq!(hv.traps, vec![CODE_MEM + 5, CODE_MEM + 12]);
+    assert_eq!(fault.eip, CODE_MEM + 12);
 }
 
 #[test]
@@ -35,7 +119,7 @@ fn test_simple_nop_hlt(){
     bytes.push(0xF4); //hlt
     vm.copy_into_memory(CODE_MEM, &bytes).unwrap();
     let mut hv = TestHypervisor::default();
-    assert!(vm.execute(&mut hv).unwrap());
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
     assert_eq!(vm.eip, CODE_MEM + 100);
 }
 
@@ -100,6 +184,173 @@ fn test_push_pop(){
     assert_eq!(vm.get_mem(0x80000100 - 4, ValueSize::Dword).unwrap().u32_exact().unwrap(), 0x80001000);
 }
 
+#[test]
+fn test_cpuid(){
+    let vm = execute_vm_with_asm("
+        mov eax, 0
+        cpuid
+        mov esi, ebx
+        mov edi, edx
+        mov ebp, ecx
+        mov eax, 1
+        cpuid
+        hlt
+    ");
+    let mut vendor = vec![];
+    vendor.extend_from_slice(&vm.reg32(Reg32::ESI).to_le_bytes());
+    vendor.extend_from_slice(&vm.reg32(Reg32::EDI).to_le_bytes());
+    vendor.extend_from_slice(&vm.reg32(Reg32::EBP).to_le_bytes());
+    assert_eq!(&vendor[..], b"Qtum-x86 VM ");
+    assert_eq!(vm.reg32(Reg32::EAX), 0);
+    assert_eq!(vm.reg32(Reg32::EBX), 0);
+    let bitops = if cfg!(feature = "bitops") { CPUID_ECX_MOVBE | CPUID_ECX_POPCNT } else { 0 };
+    assert_eq!(vm.reg32(Reg32::ECX), CPUID_ECX_HYPERVISOR | bitops);
+    let fpu = if cfg!(feature = "x87") { CPUID_EDX_FPU } else { 0 };
+    assert_eq!(vm.reg32(Reg32::EDX), CPUID_EDX_TSC | CPUID_EDX_CX8 | CPUID_EDX_CMOV | fpu);
+}
+
+#[test]
+fn test_cpuid_custom_table(){
+    let mut vm = create_vm_with_asm("
+        mov eax, 0x40000001
+        cpuid
+        mov esi, eax
+        mov eax, 0x12345678
+        cpuid
+        mov edi, eax
+        mov eax, 0
+        mov ebx, 0xFFFFFFFF
+        mov ecx, 0xFFFFFFFF
+        mov edx, 0xFFFFFFFF
+        mov eax, 0x20000000
+        cpuid
+        hlt
+    ");
+    vm.cpuid.set(0x12345678, CpuidLeaf{eax: 10, ebx: 20, ecx: 30, edx: 40});
+    vm.cpuid.set(0x40000001, CpuidLeaf{eax: 99, ..Default::default()});
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::ESI), 99);
+    assert_eq!(vm.reg32(Reg32::EDI), 10);
+    //leaves not in the table return 0
+    assert_eq!(vm.reg32(Reg32::EAX), 0);
+    assert_eq!(vm.reg32(Reg32::EBX), 0);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+    assert_eq!(vm.reg32(Reg32::EDX), 0);
+}
+
+#[test]
+fn test_push_pop_segment(){
+    let vm = execute_vm_with_asm("
+        mov esp, 0x80000100
+        mov dword [0x800000F8], 0xFFFFFFFF
+        mov dword [0x800000FC], 0xFFFFFFFF
+        push es
+        push fs
+        mov eax, [esp]
+        mov ebx, [esp + 4]
+        mov ecx, esp
+        pop gs
+        pop ds
+        mov edx, esp
+        db 0x66, 0x1E ;push ds (16 bit)
+        mov esi, esp
+        db 0x66, 0x07 ;pop es (16 bit)
+        hlt
+    ");
+    assert_eq!(vm.reg32(Reg32::EAX), 0);
+    assert_eq!(vm.reg32(Reg32::EBX), 0);
+    assert_eq!(vm.reg32(Reg32::ECX), 0x800000F8);
+    assert_eq!(vm.reg32(Reg32::EDX), 0x80000100);
+    assert_eq!(vm.reg32(Reg32::ESI), 0x800000FE);
+    assert_eq!(vm.reg32(Reg32::ESP), 0x80000100);
+}
+
+#[test]
+fn test_segment_override_ignored(){
+    let vm = execute_vm_with_asm("
+        mov ebx, 0x80000000
+        mov dword [ebx], 0x12345678
+        db 0x26 ;ES
+        mov eax, [ebx]
+        db 0x64, 0x65 ;FS, GS
+        mov ecx, [ebx]
+        db 0x2E ;CS
+        mov dword [ebx + 4], 0xAABBCCDD
+        db 0x3E, 0x36 ;DS, SS
+        inc eax
+        hlt
+    ");
+    assert_eq!(vm.reg32(Reg32::EAX), 0x12345679);
+    assert_eq!(vm.reg32(Reg32::ECX), 0x12345678);
+    assert_eq!(vm.get_mem(0x80000004, ValueSize::Dword).unwrap().u32_exact().unwrap(), 0xAABBCCDD);
+    assert_eq!(vm.eip, CODE_MEM + 29);
+}
+
+#[test]
+fn test_lock(){
+    let vm = execute_vm_with_asm("
+        mov ebx, 0x80000000
+        mov dword [ebx], 5
+        mov eax, 5
+        mov ecx, 10
+        lock cmpxchg [ebx], ecx
+        lock add dword [ebx], 3
+        lock inc dword [ebx]
+        lock xadd [ebx], eax
+        lock bts dword [ebx], 8
+        lock xchg [ebx + 4], ecx
+        hlt
+    ");
+    assert_eq!(vm.get_mem(0x80000000, ValueSize::Dword).unwrap().u32_exact().unwrap(), 0x113);
+    assert_eq!(vm.get_mem(0x80000004, ValueSize::Dword).unwrap().u32_exact().unwrap(), 10);
+    assert_eq!(vm.reg32(Reg32::EAX), 14);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+}
+
+#[test]
+fn test_lock_invalid(){
+    let programs = [
+        "add eax, ebx",
+        "inc eax",
+        "xchg eax, ebx",
+        "mov [ebx], eax",
+        "cmp [ebx], eax",
+        "bt [ebx], eax",
+        "movsb"
+    ];
+    for code in programs.iter(){
+        let mut vm = create_vm_with_asm(&format!("
+            mov ebx, 0x80000000
+            db 0xF0
+            {}
+            hlt", code));
+        let fault = execute_vm_with_fault(&mut vm);
+        assert_eq!(fault.error, VMError::InvalidOpcode(0xF0));
+        assert_eq!(fault.eip, CODE_MEM + 5);
+    }
+}
+
+#[test]
+fn test_far_branches_invalid(){
+    let programs = [
+        ("db 0x9A, 0x00, 0x00, 0x01, 0x00, 0x08, 0x00 ;call far 0x08:0x10000", 0x9A),
+        ("db 0xEA, 0x00, 0x00, 0x01, 0x00, 0x08, 0x00 ;jmp far 0x08:0x10000", 0xEA),
+        ("db 0xFF, 0x1B ;call far [ebx]", 0xFF),
+        ("db 0xFF, 0x2B ;jmp far [ebx]", 0xFF),
+        ("db 0xCA, 0x04, 0x00 ;retf 4", 0xCA),
+        ("db 0xCB ;retf", 0xCB)
+    ];
+    for (code, opcode) in programs.iter(){
+        let mut vm = create_vm_with_asm(&format!("
+            mov ebx, 0x80000000
+            {}
+            hlt", code));
+        let fault = execute_vm_with_fault(&mut vm);
+        assert_eq!(fault.error, VMError::InvalidOpcode(*opcode));
+        assert_eq!(fault.eip, CODE_MEM + 5);
+    }
+}
+
 #[test]
 fn test_jmp(){
     //This is hard to follow, but order is _a,_b,_c,_d,_e
@@ -206,6 +457,108 @@ fn test_jcxz() {
     assert_eq!(vm.reg32(Reg32::EAX), 1);
 }
 
+#[test]
+fn test_loop() {
+    let vm = execute_vm_with_asm("
+        mov eax, 0
+        mov ecx, 5
+        _a:
+        add eax, 2
+        loop _a
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 10);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+}
+
+#[test]
+fn test_loop_zero_counter_wraps() {
+    let vm = execute_vm_with_asm("
+        mov ecx, 0
+        loop _a
+        hlt
+        _a:
+        mov eax, 1
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+    assert_eq!(vm.reg32(Reg32::ECX), 0xFFFFFFFF);
+}
+
+#[test]
+fn test_loop_16bit() {
+    //with an operand size override only CX is used as the counter
+    //the branch is not taken here, since a taken branch would truncate EIP to 16 bits
+    let vm = execute_vm_with_asm("
+        mov eax, 0
+        mov ecx, 0x12340001
+        _a:
+        inc eax
+        db 0x66 ;operand size override
+        loop _a
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+    assert_eq!(vm.reg32(Reg32::ECX), 0x12340000);
+}
+
+#[test]
+fn test_loope() {
+    //stops once ZF is cleared, even though ECX is not yet zero
+    let vm = execute_vm_with_asm("
+        mov esi, 0x80000000
+        mov dword [esi], 0
+        mov dword [esi + 4], 0
+        mov byte [esi + 2], 1
+        mov eax, 0
+        mov ecx, 8
+        _a:
+        inc eax
+        cmp byte [esi], 0
+        lea esi, [esi + 1] ;does not modify flags
+        loope _a
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 3);
+    assert_eq!(vm.reg32(Reg32::ECX), 5);
+    assert!(!vm.flags.zero);
+    //runs until ECX is zero when ZF stays set
+    let vm = execute_vm_with_asm("
+        mov eax, 0
+        mov ecx, 4
+        _a:
+        inc eax
+        cmp eax, eax
+        loope _a
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 4);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+    assert!(vm.flags.zero);
+}
+
+#[test]
+fn test_loopne() {
+    //stops once ZF is set, even though ECX is not yet zero
+    let vm = execute_vm_with_asm("
+        mov eax, 0
+        mov ecx, 10
+        _a:
+        inc eax
+        cmp eax, 4
+        loopne _a
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 4);
+    assert_eq!(vm.reg32(Reg32::ECX), 6);
+    assert!(vm.flags.zero);
+    //runs until ECX is zero when ZF stays clear
+    let vm = execute_vm_with_asm("
+        mov eax, 0
+        mov ecx, 3
+        _a:
+        inc eax
+        cmp eax, 100
+        loopne _a
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 3);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+}
+
 #[test]
 fn test_call_relw() {
     let vm = execute_vm_with_asm("
@@ -499,6 +852,77 @@ fn test_shr_16bit_carry_overflow(){
     assert_eq!(vm.flags, X86Flags{carry: true, overflow: true, parity: true, ..Default::default()});
 }
 
+#[test]
+fn test_shld_32bit(){
+    let vm = execute_vm_with_asm("
+        mov eax, 0x12345678
+        mov ebx, 0x9ABCDEF0
+        shld eax, ebx, 4
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 0x23456789);
+    assert_eq!(vm.reg32(Reg32::EBX), 0x9ABCDEF0);
+    assert_eq!(vm.flags, X86Flags{carry: true, ..Default::default()});
+}
+
+#[test]
+fn test_shld_32bit_overflow(){
+    let vm = execute_vm_with_asm("
+        mov eax, 0x40000000
+        mov ebx, 0x80000000
+        shld eax, ebx, 1
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 0x80000001);
+    assert_eq!(vm.flags, X86Flags{overflow: true, sign: true, ..Default::default()});
+}
+
+#[test]
+fn test_shld_zero_count_keeps_flags(){
+    let vm = execute_vm_with_asm("
+        mov eax, 0x12345678
+        mov ebx, 0xFFFFFFFF
+        mov cl, 32
+        cmp eax, eax
+        shld eax, ebx, cl
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 0x12345678);
+    assert_eq!(vm.flags, X86Flags{zero: true, parity: true, ..Default::default()});
+}
+
+#[test]
+fn test_shld_memory(){
+    let vm = execute_vm_with_asm("
+        mov dword [0x80000000], 0x80000001
+        mov ebx, 0
+        shld [0x80000000], ebx, 1
+        mov eax, [0x80000000]
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 2);
+    assert_eq!(vm.flags, X86Flags{carry: true, overflow: true, ..Default::default()});
+}
+
+#[test]
+fn test_shrd_32bit(){
+    let vm = execute_vm_with_asm("
+        mov eax, 0x12345678
+        mov ebx, 0xAB
+        shrd eax, ebx, 8
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 0xAB123456);
+    assert_eq!(vm.flags, X86Flags{sign: true, parity: true, ..Default::default()});
+}
+
+#[test]
+fn test_shrd_16bit_cl(){
+    let vm = execute_vm_with_asm("
+        mov eax, 0x55550001
+        mov bx, 0xFFFF
+        mov cl, 1
+        shrd ax, bx, cl
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 0x55558000);
+    assert_eq!(vm.flags, X86Flags{carry: true, overflow: true, sign: true, parity: true, ..Default::default()});
+}
+
 #[test]
 fn test_regular_mul8bit_mul_0() {
     let vm = execute_vm_with_asm("
@@ -1218,6 +1642,125 @@ fn test_lea() {
     assert_eq!(vm.reg32(Reg32::EDX), (5 * 2 + 100000) & 0x0000FFFF);
 }
 
+#[test]
+fn test_lea_address_override() {
+    let vm = execute_vm_with_asm("
+        mov ebx, 0x1234FFF0
+        mov esi, 0x20
+        mov edi, 0xFFFF0010
+        mov ebp, 0x10000
+        mov ecx, 0xFFFFFFFF
+        lea eax, [bx + si + 4]
+        lea cx, [bp + di - 2]
+        db 0x67, 0x8D, 0x16, 0x00, 0x80 ;lea edx, [0x8000] using 16 bit addressing
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 0x14); //wraps around at 64Kb
+    assert_eq!(vm.reg32(Reg32::ECX), 0xFFFF000E);
+    assert_eq!(vm.reg32(Reg32::EDX), 0x8000);
+}
+
+#[test]
+fn test_address_override_invalid() {
+    let mut vm = create_vm_with_asm("
+        mov eax, 0x80000000
+        mov ebx, [bx]
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::InvalidOpcode(0x67));
+    assert_eq!(fault.eip, CODE_MEM + 5);
+    vm = create_vm_with_asm("
+        mov esi, 0x80000000
+        mov edi, 0x80000010
+        mov ecx, 1
+        db 0x67
+        rep movsb
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0x67));
+}
+
+#[test]
+fn test_redundant_prefixes() {
+    let vm = execute_vm_with_asm("
+        mov ecx, 5
+        mov edx, 0xFFFFFFFF
+        %rep 14
+        db 0x3E
+        %endrep
+        mov eax, ecx ;16 bytes total
+        %rep 12
+        db 0x66
+        %endrep
+        mov dx, 0x1234 ;16 bytes total
+        hlt");
+    assert_eq!(vm.reg32(Reg32::EAX), 5);
+    assert_eq!(vm.reg32(Reg32::EDX), 0xFFFF1234);
+}
+
+#[test]
+fn test_opcode_too_long() {
+    let mut vm = create_vm_with_asm("
+        %rep 15
+        db 0x3E
+        %endrep
+        mov eax, ecx ;17 bytes total
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcodeEncoding);
+    //only prefixes
+    let mut vm = create_vm_with_asm("
+        %rep 16
+        db 0x66
+        %endrep
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcodeEncoding);
+    //the opcode arguments cross the 16 byte limit
+    let mut vm = create_vm_with_asm("
+        %rep 7
+        db 0x3E
+        %endrep
+        mov dword [0x80000000], 1 ;17 bytes total
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcodeEncoding);
+    //rep opcodes
+    let mut vm = create_vm_with_asm("
+        mov esi, 0x80000000
+        mov edi, 0x80000010
+        mov ecx, 1
+        %rep 15
+        db 0xF3
+        %endrep
+        movsb ;16 bytes total
+        %rep 16
+        db 0xF3
+        %endrep
+        movsb ;17 bytes total
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcodeEncoding);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+}
+
+#[test]
+fn test_conflicting_rep_prefixes() {
+    //the last of 0xF2 and 0xF3 is used
+    let mut vm = create_vm_with_asm("
+        mov esi, 0x80000000
+        mov edi, 0x80000010
+        mov ecx, 4
+        db 0xF2
+        repe cmpsb
+        mov ebx, ecx
+        mov esi, 0x80000000
+        mov edi, 0x80000010
+        mov ecx, 4
+        db 0xF3
+        repne cmpsb
+        hlt");
+    vm.memory.set_u32(DATA_MEM, 0x02020101).unwrap();
+    vm.memory.set_u32(DATA_MEM + 0x10, 0x01010101).unwrap();
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::EBX), 1);
+    assert_eq!(vm.reg32(Reg32::ECX), 3);
+}
+
 #[test]
 fn test_movzx() {
     let vm = execute_vm_with_asm("
@@ -1592,19 +2135,76 @@ fn test_enter_leave() {
     assert_eq!(vm.reg32(Reg32::EAX), 0x8000000A);
     assert_eq!(vm.reg32(Reg32::EBX), 0x8000664b);
     assert_eq!(vm.reg32(Reg32::ECX), 0x8000664C);
-    /**
-     * Todo: add these statements after enter 1, 0 and figure out why
-     * the while loop portion is erroring
-     *  
-     * also add in these assertions
-     * assert_eq!(vm.reg32(Reg32::EDI), 0x8000664b);
-     * assert_eq!(vm.reg32(Reg32::ESI), 0x8000664C);    
-     */
     assert_eq!(vm.reg32(Reg32::ESP), 0x80006650);
     assert_eq!(vm.reg32(Reg32::EBP), 0x8000000A);
     assert_eq!(vm.flags, X86Flags{..Default::default()});
 }
 
+#[test]
+fn test_enter_nesting() {
+    let vm = execute_vm_with_asm("
+        mov esp, 0x80001000
+        mov ebp, 0x80002000
+        mov dword [0x80001FFC], 0x11111111
+        mov dword [0x80001FF8], 0x22222222
+        enter 8, 3
+        hlt");
+    let mem = |a| vm.get_mem(a, ValueSize::Dword).unwrap().u32_exact().unwrap();
+    assert_eq!(mem(0x80000FFC), 0x80002000); //old ebp
+    assert_eq!(mem(0x80000FF8), 0x11111111); //copied from [ebp - 4]
+    assert_eq!(mem(0x80000FF4), 0x22222222); //copied from [ebp - 8]
+    assert_eq!(mem(0x80000FF0), 0x80000FFC); //new frame pointer
+    assert_eq!(vm.reg32(Reg32::EBP), 0x80000FFC);
+    assert_eq!(vm.reg32(Reg32::ESP), 0x80000FF0 - 8);
+    assert_eq!(vm.eip, CODE_MEM + 34);
+}
+
+#[test]
+fn test_enter_nested_display() {
+    let vm = execute_vm_with_asm("
+        mov esp, 0x80001000
+        mov ebp, 0
+        enter 0, 1
+        mov esi, ebp
+        enter 4, 2
+        mov edi, ebp
+        enter 0, 3
+        hlt");
+    let mem = |a| vm.get_mem(a, ValueSize::Dword).unwrap().u32_exact().unwrap();
+    //level 1
+    assert_eq!(vm.reg32(Reg32::ESI), 0x80000FFC);
+    assert_eq!(mem(0x80000FF8), 0x80000FFC);
+    //level 2
+    assert_eq!(vm.reg32(Reg32::EDI), 0x80000FF4);
+    assert_eq!(mem(0x80000FF4), 0x80000FFC); //old ebp
+    assert_eq!(mem(0x80000FF0), 0x80000FFC); //display: level 1
+    assert_eq!(mem(0x80000FEC), 0x80000FF4); //display: level 2
+    //level 3, after the 4 bytes of locals from level 2
+    assert_eq!(vm.reg32(Reg32::EBP), 0x80000FE4);
+    assert_eq!(mem(0x80000FE4), 0x80000FF4); //old ebp
+    assert_eq!(mem(0x80000FE0), 0x80000FFC); //display: level 1
+    assert_eq!(mem(0x80000FDC), 0x80000FF4); //display: level 2
+    assert_eq!(mem(0x80000FD8), 0x80000FE4); //display: level 3
+    assert_eq!(vm.reg32(Reg32::ESP), 0x80000FD8);
+}
+
+#[test]
+fn test_enter_nesting_16bit() {
+    let vm = execute_vm_with_asm("
+        mov esp, 0x80001000
+        mov ebp, 0x80002000
+        mov word [0x80001FFE], 0x1111
+        db 0x66, 0xC8, 0x04, 0x00, 0x02 ;enter 4, 2 (16 bit)
+        hlt");
+    let mem = |a| vm.get_mem(a, ValueSize::Word).unwrap().u16_exact().unwrap();
+    assert_eq!(mem(0x80000FFE), 0x2000); //old bp
+    assert_eq!(mem(0x80000FFC), 0x1111); //copied from [ebp - 2]
+    assert_eq!(mem(0x80000FFA), 0x0FFE); //new frame pointer
+    //only bp is modified, the top of ebp is kept
+    assert_eq!(vm.reg32(Reg32::EBP), 0x80000FFE);
+    assert_eq!(vm.reg32(Reg32::ESP), 0x80000FFA - 4);
+}
+
 #[test]
 fn test_bsf_bsr_1() {
     let vm = execute_vm_with_asm("
@@ -0,0 +1,168 @@
+#![cfg(feature = "sse2")]
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use common::*;
+
+#[test]
+fn test_sse2_moves(){
+    let mut vm = create_vm_with_asm("
+        mov esi, 0x80000000
+        mov edi, 0x80000100
+        movdqu xmm0, [esi + 1]
+        movdqu [edi + 1], xmm0
+        movdqa xmm1, [esi + 16]
+        movaps xmm2, xmm1
+        movaps [edi + 16], xmm2
+        movups xmm3, [esi + 32]
+        movups [edi + 32], xmm3
+        movdqa xmm7, xmm3
+        hlt");
+    for i in 0..48{
+        vm.memory.set_u8(DATA_MEM + i, i as u8 + 1).unwrap();
+    }
+    execute_vm_with_diagnostics(&mut vm);
+    for i in 1..48{
+        assert_eq!(vm.memory.get_u8(DATA_MEM + 0x100 + i).unwrap(), i as u8 + 1);
+    }
+    assert_eq!(vm.memory.get_u8(DATA_MEM + 0x100).unwrap(), 0);
+    assert_eq!(vm.xmm[0], vm.memory.get_u128(DATA_MEM + 1).unwrap());
+    assert_eq!(vm.xmm[7], vm.memory.get_u128(DATA_MEM + 32).unwrap());
+    assert_eq!(vm.xmm[7] as u8, 33);
+}
+
+#[test]
+fn test_sse2_logic(){
+    let mut vm = create_vm_with_asm("
+        mov esi, 0x80000000
+        movdqu xmm0, [esi]
+        movdqu xmm1, [esi + 16]
+        movdqa xmm2, xmm0
+        pand xmm2, xmm1
+        movdqa xmm3, xmm0
+        por xmm3, xmm1
+        movdqa xmm4, xmm0
+        pxor xmm4, [esi + 16]
+        movdqa xmm5, xmm0
+        pandn xmm5, xmm1
+        xorps xmm6, xmm6
+        movaps xmm7, xmm0
+        andnps xmm7, xmm1
+        orps xmm7, xmm6
+        xorpd xmm0, xmm0
+        hlt");
+    let a = 0xFF00FF00_12345678_00000000_FFFFFFFFu128;
+    let b = 0x0FF00FF0_FFFF0000_AAAAAAAA_55555555u128;
+    vm.memory.set_u128(DATA_MEM, a).unwrap();
+    vm.memory.set_u128(DATA_MEM + 16, b).unwrap();
+    vm.xmm[6] = 0x1234;
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.xmm[0], 0);
+    assert_eq!(vm.xmm[1], b);
+    assert_eq!(vm.xmm[2], a & b);
+    assert_eq!(vm.xmm[3], a | b);
+    assert_eq!(vm.xmm[4], a ^ b);
+    assert_eq!(vm.xmm[5], !a & b);
+    assert_eq!(vm.xmm[6], 0);
+    assert_eq!(vm.xmm[7], !a & b);
+}
+
+#[test]
+fn test_sse2_movd_movq(){
+    let mut vm = create_vm_with_asm("
+        mov esi, 0x80000000
+        mov eax, 0x11223344
+        movd xmm0, eax
+        movd xmm1, [esi]
+        movd ecx, xmm1
+        movd [esi + 16], xmm0
+        movq xmm2, [esi + 8]
+        movq [esi + 24], xmm2
+        movq xmm3, xmm2
+        hlt");
+    vm.memory.set_u32(DATA_MEM, 0xAABBCCDD).unwrap();
+    vm.memory.set_u64(DATA_MEM + 8, 0x0102030405060708).unwrap();
+    vm.xmm[0] = !0;
+    vm.xmm[2] = !0;
+    vm.xmm[3] = !0;
+    execute_vm_with_diagnostics(&mut vm);
+    //all loads into xmm registers clear the upper bits
+    assert_eq!(vm.xmm[0], 0x11223344);
+    assert_eq!(vm.xmm[1], 0xAABBCCDD);
+    assert_eq!(vm.reg32(Reg32::ECX), 0xAABBCCDD);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 16).unwrap(), 0x11223344);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 20).unwrap(), 0);
+    assert_eq!(vm.xmm[2], 0x0102030405060708);
+    assert_eq!(vm.memory.get_u64(DATA_MEM + 24).unwrap(), 0x0102030405060708);
+    assert_eq!(vm.xmm[3], 0x0102030405060708);
+}
+
+#[test]
+fn test_sse2_pshufd(){
+    let mut vm = create_vm_with_asm("
+        mov esi, 0x80000000
+        movdqu xmm0, [esi]
+        pshufd xmm1, xmm0, 0x1B ;reverse
+        pshufd xmm2, [esi], 0x00 ;broadcast lowest
+        pshufd xmm0, xmm0, 0xE4 ;identity
+        hlt");
+    let value = 0x44444444_33333333_22222222_11111111u128;
+    vm.memory.set_u128(DATA_MEM, value).unwrap();
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.xmm[0], value);
+    assert_eq!(vm.xmm[1], 0x11111111_22222222_33333333_44444444u128);
+    assert_eq!(vm.xmm[2], 0x11111111_11111111_11111111_11111111u128);
+}
+
+#[test]
+fn test_sse2_prefixed_fallback(){
+    //0x66 prefixed opcodes without their own SSE2 definition still work as before
+    let mut vm = create_vm_with_asm("
+        mov ax, 7
+        mov cx, 6
+        imul ax, cx
+        movzx bx, cl
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg16(Reg16::AX), 42);
+    assert_eq!(vm.reg16(Reg16::BX), 6);
+}
+
+#[test]
+fn test_sse2_unsupported(){
+    //MMX form of movq
+    let mut vm = create_vm_with_asm("
+        movq mm0, [0x80000000]
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0x0F));
+    //movsd has a mandatory 0xF2 prefix
+    let mut vm = create_vm_with_asm("
+        movsd xmm0, [0x80000000]
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0xF2));
+    //movss is not part of the integer subset
+    let mut vm = create_vm_with_asm("
+        movss xmm0, [0x80000000]
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0xF3));
+    //paddd
+    let mut vm = create_vm_with_asm("
+        paddd xmm0, xmm1
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::InvalidOpcode(0x66));
+    assert_eq!(fault.eip, CODE_MEM);
+}
+
+#[test]
+fn test_sse2_gas(){
+    let mut vm = create_vm_with_asm("
+        pxor xmm0, xmm0
+        movdqu [0x80000000], xmm0
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    let c = &vm.charger;
+    let expected = c.cost(GasCost::Low) * 2 + c.cost(GasCost::ModRMSurcharge) * 2 + c.cost(GasCost::MemoryAccess);
+    assert_eq!(INITIAL_GAS - vm.gas_remaining, expected);
+}
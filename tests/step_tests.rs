@@ -0,0 +1,104 @@
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use common::*;
+
+const LOOP_PROGRAM: &str = "
+        mov esp, 0x80000100
+        mov ecx, 5
+        xor eax, eax
+    top:
+        call add_ecx
+        loop top
+        jmp done
+    add_ecx:
+        add eax, ecx
+        ret
+    done:
+        mov [0x80000000], eax
+        hlt";
+
+#[test]
+fn test_step(){
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        jmp skip
+        mov eax, 2
+    skip:
+        add eax, eax
+        hlt");
+    //mov eax, 1
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.eip, CODE_MEM + 5);
+    assert_eq!(vm.reg32(Reg32::EAX), 1);
+    assert_eq!(vm.gas_remaining, INITIAL_GAS - vm.charger.cost(GasCost::VeryLow));
+    //jmp skip
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.eip, CODE_MEM + 12);
+    //add eax, eax
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.eip, CODE_MEM + 14);
+    assert_eq!(vm.reg32(Reg32::EAX), 2);
+    //hlt does not advance EIP
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(vm.eip, CODE_MEM + 14);
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Halted);
+}
+
+#[test]
+fn test_step_matches_execute(){
+    let expected = execute_vm_with_asm(LOOP_PROGRAM);
+    assert_eq!(expected.memory.get_u32(DATA_MEM).unwrap(), 15);
+
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm(LOOP_PROGRAM);
+    let mut count = 0;
+    while vm.step(&mut hv).unwrap() != Termination::Halted{
+        count += 1;
+    }
+    //3 setup instructions, 5 iterations of 4 instructions, then jmp, mov
+    assert_eq!(count, 3 + 5 * 4 + 2);
+    assert_same_state(&vm, &expected);
+}
+
+#[test]
+fn test_run_for(){
+    let expected = execute_vm_with_asm(LOOP_PROGRAM);
+    let mut hv = TestHypervisor::default();
+    //stopping at every possible instruction boundary and then resuming gives the same result
+    for n in 0..26{
+        let mut vm = create_vm_with_asm(LOOP_PROGRAM);
+        assert_eq!(vm.run_for(n, &mut hv).unwrap(), Termination::Stepped);
+        assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+        assert_same_state(&vm, &expected);
+    }
+    let mut vm = create_vm_with_asm(LOOP_PROGRAM);
+    assert_eq!(vm.run_for(25, &mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.run_for(1000, &mut hv).unwrap(), Termination::Halted);
+    assert_same_state(&vm, &expected);
+}
+
+#[test]
+fn test_step_errors(){
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        mov ecx, 2
+        hlt");
+    vm.gas_remaining = vm.charger.cost(GasCost::VeryLow);
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::Stepped);
+    assert_eq!(vm.step(&mut hv).unwrap(), Termination::OutOfGas);
+    //the instruction which ran out of gas is not executed
+    assert_eq!(vm.eip, CODE_MEM + 5);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        ud2
+        hlt");
+    let fault = vm.run_for(10, &mut hv).unwrap_err();
+    assert_eq!(fault.error, VMError::Trap(CODE_MEM + 5));
+    assert_eq!(fault.eip, CODE_MEM + 5);
+}
@@ -0,0 +1,168 @@
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use qx86::threaded::*;
+use common::*;
+
+//covers every operand combination of the opcodes which are translated into specialized closures
+const TRANSLATION_PROGRAM: &str = "
+        mov esp, 0x80000100
+        mov esi, 0x80000200
+        mov ecx, 20
+        mov dword [esi], 7
+    top:
+        mov eax, ecx
+        mov ebx, [esi]
+        mov [esi + ecx * 4], eax
+        mov dword [esi + 0x100], 0x12345678
+        add eax, ebx
+        add eax, 5
+        add eax, 0x10000
+        add eax, [esi + 4]
+        add [esi], eax
+        add dword [esi + 8], 3
+        sub ebx, ecx
+        sub ebx, -1
+        sub [esi + 12], ebx
+        xor edx, eax
+        xor edx, 0x5555
+        and edx, [esi]
+        or edx, 0x100
+        or [esi + 16], edx
+        and dword [esi + 16], 0xFFFF
+        inc edi
+        inc dword [esi + 20]
+        dec dword [esi + 24]
+        lea ebp, [eax + ebx * 2 + 7]
+        lea ebp, [ebp + 0x100]
+        push ebp
+        push dword [esi]
+        push 0x11223344
+        pop eax
+        pop ebx
+        pop edx
+        cmp eax, 0x11223344
+        jne fail
+        cmp ebx, [esi]
+        jne fail
+        cmp [esi + 4], ecx
+        jmp skip
+    fail:
+        hlt
+    skip:
+        dec ecx
+        jnz top
+        hlt";
+
+fn create_translated_vm(bytes: &[u8], translation: Translation) -> VM{
+    let mut vm = create_vm_with_bytes(bytes);
+    vm.translation = translation;
+    vm
+}
+
+#[test]
+fn test_translation_matches_interpreter(){
+    let bytes = asm(TRANSLATION_PROGRAM);
+    let mut hv = TestHypervisor::default();
+    let mut expected = create_translated_vm(&bytes, Translation::Disabled);
+    assert_eq!(expected.execute(&mut hv).unwrap(), Termination::Halted);
+    //sanity check that the loop ran to completion rather than stopping at fail
+    assert_eq!(expected.reg32(Reg32::ECX), 0);
+    for translation in [Translation::HotBlocks, Translation::AllBlocks].iter(){
+        let mut vm = create_translated_vm(&bytes, *translation);
+        assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+        assert_same_state(&vm, &expected);
+    }
+}
+
+#[test]
+fn test_translation_at_every_gas_boundary(){
+    let bytes = asm(TRANSLATION_PROGRAM);
+    //translated blocks are only used when there is enough gas for the entire block, otherwise the interpreter is used
+    assert_resumes_at_every_gas_boundary(&|| create_translated_vm(&bytes, Translation::AllBlocks), &|| create_translated_vm(&bytes, Translation::Disabled));
+}
+
+#[test]
+fn test_translated_error_in_block(){
+    //the error happens partway through a block, so the gas of the rest of the block must be refunded
+    let program = "
+        mov eax, 1
+        mov ebx, 2
+        mov [0x10000], eax
+        mov ecx, 3
+        hlt";
+    let mut expected = create_vm_with_asm(program);
+    expected.translation = Translation::Disabled;
+    let mut vm = create_vm_with_asm(program);
+    vm.translation = Translation::AllBlocks;
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::WroteReadOnlyMemory(0x10000));
+    assert_eq!(fault.eip, CODE_MEM + 10);
+    assert_eq!(execute_vm_with_fault(&mut expected), fault);
+    assert_same_state(&vm, &expected);
+}
+
+/// Records the gas remaining at each interrupt
+#[derive(Default)]
+struct GasHypervisor{
+    gas: Vec<u64>
+}
+impl Hypervisor for GasHypervisor{
+    fn interrupt(&mut self, vm: &mut VM, _num: u8) -> Result<(), VMError>{
+        self.gas.push(vm.gas_remaining);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_translated_gas_observers(){
+    //rdtsc, int, and rep all observe the remaining gas, and so must see the same value as when interpreted
+    let program = "
+        mov ecx, 10
+        mov esi, 0x80000000
+        mov edi, 0x80000100
+    top:
+        mov eax, ecx
+        int 0x10
+        add ebx, eax
+        rdtsc
+        mov [edi + ecx * 4], eax
+        push ecx
+        mov ecx, 3
+        rep movsb
+        pop ecx
+        dec ecx
+        jnz top
+        hlt";
+    let mut expected_hv = GasHypervisor::default();
+    let mut expected = create_vm_with_asm(program);
+    expected.translation = Translation::Disabled;
+    expected.regs[Reg32::ESP as usize] = 0x80001000;
+    assert_eq!(expected.execute(&mut expected_hv).unwrap(), Termination::Halted);
+    let mut hv = GasHypervisor::default();
+    let mut vm = create_vm_with_asm(program);
+    vm.translation = Translation::AllBlocks;
+    vm.regs[Reg32::ESP as usize] = 0x80001000;
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(hv.gas, expected_hv.gas);
+    assert_eq!(hv.gas.len(), 10);
+    assert_same_state(&vm, &expected);
+}
+
+#[test]
+fn test_translated_code_modified_externally(){
+    let mut hv = TestHypervisor::default();
+    let mut vm = create_vm_with_asm("
+        mov eax, 1
+        add eax, 2
+        hlt");
+    vm.translation = Translation::AllBlocks;
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(vm.reg32(Reg32::EAX), 3);
+    //changing read only memory from outside of the VM discards the translated blocks, and with the jit feature the compiled blocks too
+    vm.copy_into_memory(CODE_MEM + 1, &[5]).unwrap();
+    vm.eip = CODE_MEM;
+    assert_eq!(vm.execute(&mut hv).unwrap(), Termination::Halted);
+    assert_eq!(vm.reg32(Reg32::EAX), 7);
+}
@@ -0,0 +1,5 @@
+//! Runs every test in simple_tests.rs again with opcode fusion disabled, see `create_vm`
+extern crate qx86;
+
+#[path = "simple_tests.rs"]
+mod simple_tests;
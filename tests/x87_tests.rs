@@ -0,0 +1,249 @@
+#![cfg(feature = "x87")]
+extern crate qx86;
+mod common;
+
+use qx86::vm::*;
+use qx86::x87::*;
+use common::*;
+
+fn create_vm_with_doubles(input: &str, values: &[f64]) -> VM{
+    let mut vm = create_vm_with_asm(input);
+    for (i, v) in values.iter().enumerate(){
+        vm.memory.set_u64(DATA_MEM + (i as u32 * 8), v.to_bits()).unwrap();
+    }
+    vm
+}
+
+fn get_double(vm: &VM, index: u32) -> f64{
+    f64::from_bits(vm.memory.get_u64(DATA_MEM + index * 8).unwrap())
+}
+
+#[test]
+fn test_x87_memory_arithmetic(){
+    let mut vm = create_vm_with_doubles("
+        mov ebx, 0x80000000
+        fld qword [ebx] ;1.5
+        fadd qword [ebx + 8] ;2.25
+        fmul qword [ebx + 16] ;3.0
+        fdiv qword [ebx + 24] ;0.5
+        fsub qword [ebx] ;1.5
+        fstp qword [ebx + 32]
+        fld qword [ebx + 8]
+        fsubr qword [ebx] ;1.5 - 2.25
+        fdivr qword [ebx + 16] ;3.0 / -0.75
+        fstp qword [ebx + 40]
+        hlt", &[1.5, 2.25, 3.0, 0.5]);
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(get_double(&vm, 4), 21.0);
+    assert_eq!(get_double(&vm, 5), -4.0);
+    assert_eq!(vm.fpu.valid, 0);
+    assert_eq!(vm.fpu.top, 0);
+    assert_eq!(vm.fpu.status_word(), 0);
+}
+
+#[test]
+fn test_x87_single_precision(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x80000000
+        mov dword [ebx], 0x3dcccccd ;0.1f
+        mov dword [ebx + 4], 0x40400000 ;3.0f
+        fld dword [ebx]
+        fmul dword [ebx + 4]
+        fst dword [ebx + 8]
+        fstp qword [ebx + 16]
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    let tenth = 0.1f32 as f64;
+    assert_eq!(vm.memory.get_u64(DATA_MEM + 16).unwrap(), (tenth * 3.0).to_bits());
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 8).unwrap(), ((tenth * 3.0) as f32).to_bits());
+    assert_eq!(vm.fpu.status & 0x3F, 0x20); //precision exception from rounding to f32
+}
+
+#[test]
+fn test_x87_register_forms(){
+    let mut vm = create_vm_with_doubles("
+        mov ebx, 0x80000000
+        fld qword [ebx] ;ST: 10
+        fld qword [ebx + 8] ;ST: 4, 10
+        fld st1 ;ST: 10, 4, 10
+        fsub st0, st1 ;ST: 6, 4, 10
+        fxch st2 ;ST: 10, 4, 6
+        fsub st2, st0 ;ST: 10, 4, -4
+        fdivp st1 ;ST: 0.4, -4
+        fmul st0, st0 ;ST: 0.16, -4
+        fld1 ;ST: 1, 0.16, -4
+        faddp st2 ;ST: 0.16, -3
+        fsubrp st1 ;ST: 3.16
+        fchs
+        fst qword [ebx + 16]
+        fabs
+        fldz
+        fdivrp st1 ;ST: 0
+        fstp qword [ebx + 24]
+        hlt", &[10.0, 4.0]);
+    execute_vm_with_diagnostics(&mut vm);
+    let product = 0.4f64 * 0.4f64;
+    assert_eq!(get_double(&vm, 2), -(product - -3.0));
+    assert_eq!(get_double(&vm, 3), 0.0);
+    assert_eq!(vm.fpu.valid, 0);
+}
+
+#[test]
+fn test_x87_integers(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x80000000
+        mov dword [ebx], -7
+        mov word [ebx + 4], 3
+        mov dword [ebx + 8], 0
+        mov dword [ebx + 12], 0x80000000
+        fild dword [ebx]
+        fild word [ebx + 4]
+        fdivp st1 ;-2.333
+        fist dword [ebx + 16]
+        fistp word [ebx + 20]
+        fild qword [ebx + 8] ;2^63 as a signed value is -2^63
+        fistp qword [ebx + 24]
+        fild qword [ebx + 8]
+        fchs
+        fist dword [ebx + 32] ;too large for 32 bits
+        fistp qword [ebx + 40] ;too large for 64 bits
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 16).unwrap(), -2i32 as u32);
+    assert_eq!(vm.memory.get_u16(DATA_MEM + 20).unwrap(), -2i16 as u16);
+    assert_eq!(vm.memory.get_u64(DATA_MEM + 24).unwrap(), 0x8000000000000000);
+    //integer indefinite
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 32).unwrap(), 0x80000000);
+    assert_eq!(vm.memory.get_u64(DATA_MEM + 40).unwrap(), 0x8000000000000000);
+    assert_eq!(vm.fpu.status & 0x3F, 0x21);
+}
+
+#[test]
+fn test_x87_rounding_control(){
+    let mut vm = create_vm_with_doubles("
+        mov ebx, 0x80000000
+        fnstcw [ebx + 16]
+        fld qword [ebx] ;2.5
+        fist dword [ebx + 20] ;nearest even: 2
+        mov word [ebx + 24], 0x077F ;round down
+        fldcw [ebx + 24]
+        fld qword [ebx + 8] ;-2.5
+        fist dword [ebx + 28] ;-3
+        mov word [ebx + 24], 0x0B7F ;round up
+        fldcw [ebx + 24]
+        fist dword [ebx + 32] ;-2
+        fxch st1
+        fist dword [ebx + 36] ;3
+        mov word [ebx + 24], 0x0F7F ;round toward zero
+        fldcw [ebx + 24]
+        fist dword [ebx + 40] ;2
+        fninit
+        fnstcw [ebx + 44]
+        hlt", &[2.5, -2.5]);
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.memory.get_u16(DATA_MEM + 16).unwrap(), DEFAULT_CONTROL_WORD);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 20).unwrap(), 2);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 28).unwrap(), -3i32 as u32);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 32).unwrap(), -2i32 as u32);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 36).unwrap(), 3);
+    assert_eq!(vm.memory.get_u32(DATA_MEM + 40).unwrap(), 2);
+    assert_eq!(vm.memory.get_u16(DATA_MEM + 44).unwrap(), DEFAULT_CONTROL_WORD);
+    assert_eq!(vm.fpu, FpuState::default());
+}
+
+#[test]
+fn test_x87_compare(){
+    let mut vm = create_vm_with_doubles("
+        mov ebx, 0x80000000
+        mov ecx, 0
+        fld qword [ebx] ;1.0
+        fld qword [ebx + 8] ;2.0
+        fcomi st0, st1
+        ja greater
+        mov ecx, 1
+    greater:
+        fucomip st0, st1 ;2.0 > 1.0
+        jb below
+        setz dl
+        fld qword [ebx]
+        fucomip st0, st1
+        sete dh
+        fld qword [ebx + 16] ;NaN
+        fucomip st0, st1
+        setp al
+        fld qword [ebx + 16]
+        fcomip st0, st1
+        setc ah
+        hlt
+    below:
+        mov ecx, 2
+        hlt", &[1.0, 2.0, f64::NAN]);
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::ECX), 0);
+    assert_eq!(vm.reg8(Reg8::DL), 0);
+    assert_eq!(vm.reg8(Reg8::DH), 1);
+    assert_eq!(vm.reg8(Reg8::AL), 1);
+    assert_eq!(vm.reg8(Reg8::AH), 1);
+    //only fcomi raises an invalid exception for a quiet NaN
+    assert_eq!(vm.fpu.status & 0x3F, 0x01);
+    assert_eq!(vm.fpu.valid.count_ones(), 1);
+}
+
+#[test]
+fn test_x87_stack_faults(){
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x80000000
+        fstp qword [ebx]
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.memory.get_u64(DATA_MEM).unwrap(), 0xFFF8000000000000);
+    assert_eq!(vm.fpu.status_word() & 0x3F, 0x01);
+    assert_eq!(vm.fpu.status_word() & STATUS_STACK_FAULT, STATUS_STACK_FAULT);
+
+    let mut vm = create_vm_with_asm("
+        %rep 9
+        fld1
+        %endrep
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.fpu.valid, 0xFF);
+    assert_eq!(vm.fpu.top, 7);
+    assert_eq!(vm.fpu.regs[7], 0xFFF8000000000000);
+    assert_eq!(vm.fpu.status_word() & STATUS_C1, STATUS_C1);
+}
+
+#[test]
+fn test_x87_unsupported(){
+    //fsin
+    let mut vm = create_vm_with_asm("
+        fld1
+        fsin
+        hlt");
+    let fault = execute_vm_with_fault(&mut vm);
+    assert_eq!(fault.error, VMError::InvalidOpcode(0xD9));
+    assert_eq!(fault.eip, CODE_MEM + 2);
+    //fiadd m16
+    let mut vm = create_vm_with_asm("
+        mov ebx, 0x80000000
+        fld1
+        fiadd word [ebx]
+        hlt");
+    assert_eq!(execute_vm_with_error(&mut vm), VMError::InvalidOpcode(0xDE));
+}
+
+#[test]
+fn test_x87_gas_and_cpuid(){
+    let mut vm = create_vm_with_asm("
+        fld1
+        fld1
+        fdivp st1
+        mov eax, 1
+        cpuid
+        hlt");
+    execute_vm_with_diagnostics(&mut vm);
+    assert_eq!(vm.reg32(Reg32::EDX) & CPUID_EDX_FPU, CPUID_EDX_FPU);
+    let c = &vm.charger;
+    let expected = c.cost(GasCost::FloatLow) * 2 + c.cost(GasCost::FloatHigh) + c.cost(GasCost::ModRMSurcharge) * 3
+        + c.cost(GasCost::VeryLow) + c.cost(GasCost::Low);
+    assert_eq!(INITIAL_GAS - vm.gas_remaining, expected);
+}